@@ -0,0 +1,121 @@
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::Path;
+use thiserror::Error;
+
+use crate::server::turn_server::{ConfigError, TurnServerConfig};
+use crate::turn::auth::UserDatabase;
+
+#[derive(Error, Debug)]
+pub enum ConfigFileError {
+    #[error("Failed to read config file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid config file: {0}")]
+    Parse(#[from] toml::de::Error),
+
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+}
+
+// On-disk server configuration, for deployments that outgrow the
+// environment variables main.rs reads
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerConfigFile {
+    pub listen_address: SocketAddr,
+    pub realm: String,
+    pub relay_address_start: SocketAddr,
+    pub relay_address_count: u16,
+    #[serde(default)]
+    pub users: Vec<UserEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserEntry {
+    pub username: String,
+    pub password: String,
+}
+
+impl ServerConfigFile {
+    pub fn parse(contents: &str) -> Result<Self, ConfigFileError> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigFileError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn server_config(&self) -> Result<TurnServerConfig, ConfigError> {
+        TurnServerConfig::builder()
+            .listen_address(self.listen_address)
+            .realm(self.realm.clone())
+            .relay_range(self.relay_address_start, self.relay_address_count)
+            .build()
+    }
+
+    pub fn user_database(&self) -> UserDatabase {
+        let mut user_database = UserDatabase::new();
+        for user in &self.users {
+            user_database.add_user(user.username.clone(), user.password.clone());
+        }
+        user_database
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"
+        listen_address = "127.0.0.1:3478"
+        realm = "example.org"
+        relay_address_start = "127.0.0.1:50000"
+        relay_address_count = 10
+
+        [[users]]
+        username = "alice"
+        password = "password123"
+
+        [[users]]
+        username = "bob"
+        password = "hunter2"
+    "#;
+
+    #[test]
+    fn test_parse_config_file() {
+        let config_file = ServerConfigFile::parse(SAMPLE).unwrap();
+
+        let config = config_file.server_config().unwrap();
+        assert_eq!(config.listen_address, "127.0.0.1:3478".parse().unwrap());
+        assert_eq!(config.realm, "example.org");
+        assert_eq!(config.relay_address_start, "127.0.0.1:50000".parse().unwrap());
+        assert_eq!(config.relay_address_count, 10);
+
+        let user_database = config_file.user_database();
+        assert!(user_database.authenticate("alice", "password123"));
+        assert!(user_database.authenticate("bob", "hunter2"));
+        assert!(!user_database.authenticate("alice", "hunter2"));
+        assert!(!user_database.authenticate("charlie", "password123"));
+    }
+
+    #[test]
+    fn test_parse_config_file_errors() {
+        // Missing realm
+        let result = ServerConfigFile::parse(r#"
+            listen_address = "127.0.0.1:3478"
+            relay_address_start = "127.0.0.1:50000"
+            relay_address_count = 10
+        "#);
+        assert!(matches!(result, Err(ConfigFileError::Parse(_))));
+
+        // Relay range past port 65535
+        let config_file = ServerConfigFile::parse(r#"
+            listen_address = "127.0.0.1:3478"
+            realm = "example.org"
+            relay_address_start = "127.0.0.1:65530"
+            relay_address_count = 10
+        "#).unwrap();
+        assert!(config_file.users.is_empty());
+        assert!(matches!(config_file.server_config(), Err(ConfigError::RelayRangeOverflow { .. })));
+    }
+}
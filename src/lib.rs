@@ -1,3 +1,4 @@
 pub mod stun;
 pub mod turn;
-pub mod server;
\ No newline at end of file
+pub mod server;
+pub mod config;
\ No newline at end of file
@@ -5,27 +5,56 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize logging
     tracing_subscriber::fmt::init();
 
+    // A config file replaces the environment variables and test users below
+    if let Ok(path) = std::env::var("TURN_CONFIG") {
+        let server = TurnServer::from_config_file(&path).await?;
+        println!("TURN server starting with configuration from {path}");
+        return run(server).await;
+    }
+
     // Create server configuration
     let listen_addr = std::env::var("TURN_LISTEN_ADDR")
         .unwrap_or_else(|_| "0.0.0.0:3478".to_string());
     let relay_start = std::env::var("TURN_RELAY_START")
         .unwrap_or_else(|_| "0.0.0.0:49152".to_string());
+    let tcp_listen_addr = std::env::var("TURN_TCP_LISTEN_ADDR").ok();
+    let relay6_start = std::env::var("TURN_RELAY6_START").ok();
     
-    let config = TurnServerConfig {
-        listen_address: listen_addr.parse()?,
-        realm: "example.com".to_string(),
-        relay_address_start: relay_start.parse()?,
-        relay_address_count: 100,
-    };
+    let mut builder = TurnServerConfig::builder()
+        .listen_address(listen_addr.parse()?)
+        .realm("example.com")
+        .relay_range(relay_start.parse()?, 100)
+        .software(concat!("toy-turn ", env!("CARGO_PKG_VERSION")));
+    if let Some(tcp_listen_addr) = tcp_listen_addr {
+        builder = builder.tcp_listen_address(tcp_listen_addr.parse()?);
+    }
+    if let Some(relay6_start) = relay6_start {
+        builder = builder.ipv6_relay_address_start(relay6_start.parse()?);
+    }
+    let config = builder.build()?;
 
     // Create and configure server
+    #[cfg(all(unix, feature = "systemd"))]
+    let mut server = TurnServer::with_socket_activation(config).await?;
+    #[cfg(not(all(unix, feature = "systemd")))]
     let mut server = TurnServer::new(config).await?;
     
     // Add some test users
     server.add_user("testuser".to_string(), "testpass".to_string());
     server.add_user("alice".to_string(), "password123".to_string());
     
+    #[cfg(feature = "metrics")]
+    if let Ok(metrics_addr) = std::env::var("TURN_METRICS_ADDR") {
+        let listener = tokio::net::TcpListener::bind(&metrics_addr).await?;
+        server.spawn_metrics_endpoint(listener);
+        println!("Serving metrics on {metrics_addr}");
+    }
+    
     println!("TURN server starting on {listen_addr}");
+    run(server).await
+}
+
+async fn run(server: TurnServer) -> Result<(), Box<dyn std::error::Error>> {
     println!("Press Ctrl+C to stop the server");
     
     // Run the server
@@ -37,6 +66,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         _ = tokio::signal::ctrl_c() => {
             println!("\nShutting down TURN server...");
+            server.shutdown();
         }
     }
     
@@ -0,0 +1,93 @@
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+// Receive buffers kept around once their datagram has been handled, so the
+// receive path doesn't allocate per packet. Buffers over the limit are freed.
+#[derive(Debug)]
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    max_pooled: usize,
+}
+
+impl BufferPool {
+    pub fn new(max_pooled: usize) -> Arc<Self> {
+        Arc::new(BufferPool {
+            free: Mutex::new(Vec::new()),
+            max_pooled,
+        })
+    }
+
+    // A pooled buffer holding a copy of data, allocated only if the pool
+    // is empty or its buffer is too small
+    pub fn copy_from(self: &Arc<Self>, data: &[u8]) -> PooledBuffer {
+        let mut buffer = self.free.lock().unwrap().pop().unwrap_or_default();
+        buffer.extend_from_slice(data);
+
+        PooledBuffer {
+            buffer,
+            pool: self.clone(),
+        }
+    }
+
+    pub fn pooled(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+}
+
+// Goes back to its pool when dropped
+#[derive(Debug)]
+pub struct PooledBuffer {
+    buffer: Vec<u8>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut free = self.pool.free.lock().unwrap();
+        if free.len() < self.pool.max_pooled {
+            let mut buffer = std::mem::take(&mut self.buffer);
+            buffer.clear();
+            free.push(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffers_recycled() {
+        let pool = BufferPool::new(2);
+
+        let first = pool.copy_from(b"first datagram");
+        assert_eq!(&*first, b"first datagram");
+        let address = first.buffer.as_ptr();
+        drop(first);
+        assert_eq!(pool.pooled(), 1);
+
+        // Same allocation, reused for a shorter datagram
+        let second = pool.copy_from(b"second");
+        assert_eq!(&*second, b"second");
+        assert_eq!(second.buffer.as_ptr(), address);
+        assert_eq!(pool.pooled(), 0);
+    }
+
+    #[test]
+    fn test_pool_bounded() {
+        let pool = BufferPool::new(2);
+
+        let buffers: Vec<_> = (0..3).map(|_| pool.copy_from(b"data")).collect();
+        drop(buffers);
+
+        assert_eq!(pool.pooled(), 2);
+    }
+}
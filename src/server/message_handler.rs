@@ -1,152 +1,380 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::net::UdpSocket;
-use tokio::sync::RwLock;
-use tracing::{debug, warn};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::{RwLock, Semaphore};
+use tracing::{debug, info_span, warn, Instrument};
 
 use crate::stun::{
-    message::{Message, MessageClass},
-    attributes::{RawAttribute, AttributeType},
+    message::{IntoStunMessage, Message, MessageClass, MessageMethod, ParseOptions},
+    attributes::{unknown_comprehension_required, AttributeType, RawAttribute},
+    auth::{verify_long_term_integrity, Credentials},
+    binding::BindingResponse,
+    error_response::ErrorResponse,
+    error::StunError,
 };
+use crate::server::rate_limit::RateLimiter;
+use crate::server::relay::spawn_peer_relay;
+use crate::server::transport::ClientConnection;
+use crate::server::stats::ServerStats;
+use crate::server::socket_options::{set_dont_fragment, DONT_FRAGMENT_SUPPORTED};
 use crate::turn::{
-    allocation::AllocationManager,
+    allocation::{Allocation, AllocationManager, FiveTuple, RelayPortRequest, Transport, RelayRequest, DEFAULT_ALLOCATION_LIFETIME},
     auth::{NonceManager, UserDatabase},
-    allocate::{AllocateRequest, AllocateResponse},
+    allocate::{AllocateRequest, AllocateResponse, TRANSPORT_UDP},
     refresh::{RefreshRequest, RefreshResponse},
     permission::{CreatePermissionRequest, CreatePermissionResponse},
     data::SendIndication,
+    error::TurnError,
     channel::{ChannelBindRequest, ChannelBindResponse, ChannelData},
+    relay_address::AddressFamily,
 };
 
+// Shared server state handed to every message handler
+#[derive(Clone)]
+pub struct HandlerContext {
+    pub connection: ClientConnection,
+    pub allocation_manager: Arc<AllocationManager>,
+    pub nonce_manager: Arc<RwLock<NonceManager>>,
+    pub user_database: Arc<UserDatabase>,
+    pub realm: String,
+    pub max_send_data_bytes: Option<usize>,
+    pub advertised_bandwidth_kbps: Option<u32>,
+    pub software: Option<String>,
+    pub stats: Arc<ServerStats>,
+    pub turn_enabled: bool,
+    pub alternate_server: Option<SocketAddr>,
+    pub reject_link_local_clients: bool,
+    pub allocate_rate_limiter: Option<Arc<RateLimiter>>,
+    // Shared by every listener; each relay task holds a permit
+    pub relay_task_budget: Option<Arc<Semaphore>>,
+    // Relay tasks are spawned here when set, else on the current runtime
+    pub relay_runtime: Option<Handle>,
+}
+
 pub async fn handle_message(
     data: Vec<u8>,
     src_addr: SocketAddr,
-    socket: Arc<UdpSocket>,
-    allocation_manager: Arc<AllocationManager>,
-    nonce_manager: Arc<RwLock<NonceManager>>,
-    user_database: Arc<UserDatabase>,
-    realm: String,
+    context: HandlerContext,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    handle_datagram(&data, src_addr, &context).await
+}
+
+// Borrows the data so the caller can reuse its buffer afterwards
+pub async fn handle_datagram(
+    data: &[u8],
+    src_addr: SocketAddr,
+    context: &HandlerContext,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // A UDP datagram holds exactly one message, so bytes past the declared
+    // length mean it isn't a well-formed STUN message
+    let options = ParseOptions {
+        reject_trailing_bytes: context.connection.transport() == Transport::Udp,
+        ..Default::default()
+    };
+
     // Try to parse as STUN message
-    if let Ok(message) = Message::parse(&data) {
-        debug!("Received STUN message from {}: {:?}", src_addr, message.message_type);
-        
-        match message.message_type.class() {
-            MessageClass::Request => {
-                handle_request(
-                    message,
-                    src_addr,
-                    socket,
-                    allocation_manager,
-                    nonce_manager,
-                    user_database,
-                    realm,
-                ).await?;
-            }
-            MessageClass::Indication => {
-                handle_indication(
-                    message,
-                    src_addr,
-                    allocation_manager,
-                ).await?;
-            }
-            _ => {
-                warn!("Received unexpected message class from {}", src_addr);
-            }
-        }
+    if let Ok(message) = Message::parse_with_options(data, options) {
+        // Everything logged while handling the message carries these
+        let span = info_span!(
+            "stun",
+            src = %src_addr,
+            transaction_id = %transaction_id_hex(&message.transaction_id),
+            method = ?message.message_type.method(),
+        );
+        handle_stun_message(message, src_addr, context).instrument(span).await?;
     } else if data.len() >= 4 {
         // Try to parse as ChannelData
         let channel_number = u16::from_be_bytes([data[0], data[1]]);
-        if (0x4000..=0x7FFF).contains(&channel_number) {
-            if let Ok(channel_data) = ChannelData::parse(&data) {
-                handle_channel_data(channel_data, src_addr, allocation_manager).await?;
-            }
+        if (0x4000..=0x7FFF).contains(&channel_number)
+            && let Ok(channel_data) = ChannelData::parse_datagram(data)
+        {
+            let five_tuple = FiveTuple::new(src_addr, context.connection.transport());
+            handle_channel_data(channel_data, five_tuple, context).await?;
         }
     }
     
     Ok(())
 }
 
+async fn handle_stun_message(
+    message: Message,
+    src_addr: SocketAddr,
+    context: &HandlerContext,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Received STUN message from {} over {:?}: {:?}", src_addr, context.connection.transport(), message.message_type);
+    
+    match message.message_type.class() {
+        MessageClass::Request => handle_request(message, src_addr, context).await,
+        MessageClass::Indication => handle_indication(message, src_addr, context).await,
+        _ => {
+            warn!("Received unexpected message class from {}", src_addr);
+            Ok(())
+        }
+    }
+}
+
+fn transaction_id_hex(transaction_id: &[u8; 12]) -> String {
+    transaction_id.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
 async fn handle_request(
     message: Message,
     src_addr: SocketAddr,
-    socket: Arc<UdpSocket>,
-    allocation_manager: Arc<AllocationManager>,
-    nonce_manager: Arc<RwLock<NonceManager>>,
-    _user_database: Arc<UserDatabase>,
-    realm: String,
+    context: &HandlerContext,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    use crate::stun::message::MessageMethod;
+    let HandlerContext {
+        connection,
+        allocation_manager,
+        realm,
+        ..
+    } = context;
+    let five_tuple = FiveTuple::new(src_addr, connection.transport());
+    
+    // STUN-only mode turns away every TURN method before authentication
+    if !context.turn_enabled && message.message_type.method() != MessageMethod::Binding {
+        let e = TurnError::Forbidden;
+        send_response(ErrorResponse::new(&message, e.error_code(), e.to_string()), context, src_addr).await?;
+        return Ok(());
+    }
+    
+    let unknown_attributes = unknown_comprehension_required(&message)?;
+    if !unknown_attributes.is_empty() {
+        debug!("Rejecting request from {} with unknown attributes {:04x?}", src_addr, unknown_attributes);
+        send_response(ErrorResponse::unknown_attributes(&message, unknown_attributes), context, src_addr).await?;
+        return Ok(());
+    }
     
     match message.message_type.method() {
+        MessageMethod::Binding => {
+            // Unauthenticated, as for any public STUN server
+            let response = BindingResponse::success(message.transaction_id, src_addr);
+            send_response(response, context, src_addr).await?;
+        }
         MessageMethod::Allocate => {
-            let request = AllocateRequest::from_message(&message)?;
+            let request = match AllocateRequest::from_message(&message) {
+                Ok(request) => request,
+                Err(e) => {
+                    let response = AllocateResponse::error(message.transaction_id, e.error_code(), e.to_string(), None, None);
+                    send_response(response, context, src_addr).await?;
+                    return Ok(());
+                }
+            };
+            
+            // Before authentication, so such clients aren't issued nonces
+            if context.reject_link_local_clients && !routable_client_address(src_addr.ip()) {
+                debug!("Rejecting Allocate from non-routable client address {}", src_addr);
+                let e = TurnError::BadRequest;
+                let response = AllocateResponse::error(request.transaction_id, e.error_code(), e.to_string(), None, None);
+                send_response(response, context, src_addr).await?;
+                return Ok(());
+            }
+            
+            // Also before authentication, to slow down credential guessing
+            if let Some(rate_limiter) = &context.allocate_rate_limiter
+                && !rate_limiter.allow(src_addr.ip())
+            {
+                context.stats.rate_limited_allocates.fetch_add(1, Ordering::Relaxed);
+                debug!("Allocate from {} over the rate limit", src_addr);
+                let e = TurnError::AllocationQuotaReached;
+                let response = AllocateResponse::error(request.transaction_id, e.error_code(), e.to_string(), None, None);
+                send_response(response, context, src_addr).await?;
+                return Ok(());
+            }
             
             // Check authentication
-            if request.username.is_none() || request.nonce.is_none() {
-                // Send 401 Unauthorized with new nonce
-                let nonce = nonce_manager.write().await.generate_nonce();
+            if let Err(e) = authenticate_request(&message, request.username.as_deref(), request.nonce.as_deref(), context).await {
+                // Send 401 Unauthorized or 438 Stale Nonce with new nonce
+                let Some((realm, nonce)) = challenge(context, src_addr).await else {
+                    return Ok(());
+                };
                 let response = AllocateResponse::error(
                     request.transaction_id,
-                    401,
-                    "Unauthorized".to_string(),
-                    Some(realm),
-                    Some(nonce.into_bytes()),
+                    e.error_code(),
+                    e.to_string(),
+                    realm,
+                    nonce,
                 );
                 
-                send_error_response(response.transaction_id, 401, "Unauthorized", &socket, src_addr).await?;
+                send_response(response, context, src_addr).await?;
                 return Ok(());
             }
             
+            // Redirected only once authenticated, so the 300 can't be used
+            // to probe for the alternate without credentials
+            if let Some(alternate_server) = context.alternate_server {
+                let response = AllocateResponse::try_alternate(request.transaction_id, alternate_server);
+                send_response(response, context, src_addr).await?;
+                return Ok(());
+            }
+            
+            let lifetime = request.lifetime
+                .map(|secs| Duration::from_secs(secs as u64))
+                .unwrap_or(DEFAULT_ALLOCATION_LIFETIME);
+            
+            // Indications get no response, so a client that needs DF learns
+            // here whether the server can honor it
+            if request.dont_fragment && !DONT_FRAGMENT_SUPPORTED {
+                let e = TurnError::Forbidden;
+                let response = AllocateResponse::error(request.transaction_id, e.error_code(), e.to_string(), None, None);
+                send_response(response, context, src_addr).await?;
+                return Ok(());
+            }
+            
+            let relay = match relay_request(&request) {
+                Ok(relay) => relay,
+                Err(e) => {
+                    // A valid family with no relay addresses is 440 as well
+                    if let Some(family) = request.requested_address_family.filter(|family| !matches!(family, 0x01 | 0x02)) {
+                        count_unsupported_family(family, src_addr, context);
+                    }
+                    let response = AllocateResponse::error(request.transaction_id, e.error_code(), e.to_string(), None, None);
+                    send_response(response, context, src_addr).await?;
+                    return Ok(());
+                }
+            };
+            
+            // Taken before the relayed address, so a refused Allocate holds
+            // neither; the relay tasks give the permits back when they stop
+            let relay_tasks = match &context.relay_task_budget {
+                Some(budget) => match budget.clone().try_acquire_many_owned(allocation_manager.relay_reader_sockets() as u32) {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        debug!("Relay task budget exhausted; refusing Allocate from {}", src_addr);
+                        let e = TurnError::InsufficientCapacity;
+                        let response = AllocateResponse::error(request.transaction_id, e.error_code(), e.to_string(), None, None);
+                        send_response(response, context, src_addr).await?;
+                        return Ok(());
+                    }
+                },
+                None => None,
+            };
+            
             // Create allocation
-            let allocation = allocation_manager.create_allocation(
+            let allocation = match allocation_manager.create_allocation_with(
                 request.username.unwrap_or_default(),
-                src_addr,
-            ).await?;
+                realm.clone(),
+                five_tuple,
+                lifetime,
+                relay,
+            ).await {
+                Ok(allocation) => allocation,
+                Err(e) => {
+                    let response = AllocateResponse::error(request.transaction_id, e.error_code(), e.to_string(), None, None);
+                    send_response(response, context, src_addr).await?;
+                    return Ok(());
+                }
+            };
             
-            let response = AllocateResponse::success(
+            spawn_peer_relay(&allocation, connection.clone(), allocation_manager.clone(), context.stats.clone(), relay_tasks, context.relay_runtime.as_ref());
+            
+            // Report the lifetime actually granted, which may have been clamped
+            let mut response = AllocateResponse::success(
                 request.transaction_id,
                 allocation.relayed_address,
                 src_addr,
-                600, // 10 minutes
+                allocation.lifetime.as_secs() as u32,
             );
+            response.reservation_token = allocation.reservation_token;
+            response.bandwidth = context.advertised_bandwidth_kbps;
             
-            send_success_response(response, &socket, src_addr).await?;
+            send_response(response, context, src_addr).await?;
         }
         MessageMethod::Refresh => {
-            let request = RefreshRequest::from_message(&message)?;
+            let request = match RefreshRequest::from_message(&message) {
+                Ok(request) => request,
+                Err(e) => {
+                    reject_malformed(&message, e, src_addr, context).await?;
+                    return Ok(());
+                }
+            };
             
-            if request.is_delete_request() {
-                allocation_manager.remove_allocation(&src_addr);
-            } else {
-                let lifetime = request.lifetime.unwrap_or(600);
-                allocation_manager.refresh_allocation(&src_addr, std::time::Duration::from_secs(lifetime as u64))?;
+            if let Err(e) = authenticate_request(&message, request.username.as_deref(), request.nonce.as_deref(), context).await {
+                let Some((realm, nonce)) = challenge(context, src_addr).await else {
+                    return Ok(());
+                };
+                let response = RefreshResponse::error(request.transaction_id, e.error_code(), e.to_string(), realm, nonce);
+                send_response(response, context, src_addr).await?;
+                return Ok(());
             }
             
-            let response = RefreshResponse::success(request.transaction_id, request.lifetime.unwrap_or(0));
-            send_success_response(response, &socket, src_addr).await?;
+            let result = if request.is_delete_request() {
+                allocation_manager.remove_allocation(&five_tuple)
+                    .map(|_| Duration::ZERO)
+                    .ok_or(TurnError::AllocationMismatch)
+            } else {
+                let lifetime = request.lifetime
+                    .map(|secs| Duration::from_secs(secs as u64))
+                    .unwrap_or(DEFAULT_ALLOCATION_LIFETIME);
+                allocation_manager.refresh_allocation(&five_tuple, lifetime)
+            };
+            
+            // A Refresh can overtake its Allocate on a reordering path. The
+            // client sees a 437 and is expected to retransmit the Refresh once
+            // its Allocate succeeds, rather than treating it as fatal.
+            let granted = match result {
+                Ok(granted) => granted,
+                Err(e) => {
+                    let response = RefreshResponse::error(request.transaction_id, e.error_code(), e.to_string(), None, None);
+                    send_response(response, context, src_addr).await?;
+                    return Ok(());
+                }
+            };
+            
+            let response = RefreshResponse::success(request.transaction_id, granted.as_secs() as u32);
+            send_response(response, context, src_addr).await?;
         }
         MessageMethod::CreatePermission => {
-            let request = CreatePermissionRequest::from_message(&message)?;
-            
-            if let Some(mut allocation) = allocation_manager.get_allocation(&src_addr) {
-                for peer_addr in request.peer_addresses {
-                    allocation.add_permission(peer_addr);
+            let request = match CreatePermissionRequest::from_message(&message) {
+                Ok(request) => request,
+                Err(e) => {
+                    reject_malformed(&message, e, src_addr, context).await?;
+                    return Ok(());
                 }
+            };
+            
+            if let Err(e) = authenticate_request(&message, request.username.as_deref(), request.nonce.as_deref(), context).await {
+                let Some((realm, nonce)) = challenge(context, src_addr).await else {
+                    return Ok(());
+                };
+                let response = CreatePermissionResponse::error(request.transaction_id, e.error_code(), e.to_string(), realm, nonce);
+                send_response(response, context, src_addr).await?;
+                return Ok(());
+            }
+            
+            for peer_addr in request.peer_addresses {
+                allocation_manager.add_permission(&five_tuple, peer_addr);
             }
             
             let response = CreatePermissionResponse::success(request.transaction_id);
-            send_success_response(response, &socket, src_addr).await?;
+            send_response(response, context, src_addr).await?;
         }
         MessageMethod::ChannelBind => {
-            let request = ChannelBindRequest::from_message(&message)?;
+            let request = match ChannelBindRequest::from_message(&message) {
+                Ok(request) => request,
+                Err(e) => {
+                    reject_malformed(&message, e, src_addr, context).await?;
+                    return Ok(());
+                }
+            };
             
-            if let Some(mut allocation) = allocation_manager.get_allocation(&src_addr) {
-                allocation.add_channel_binding(request.channel_number, request.peer_address)?;
+            // ChannelBind must always be integrity-protected, unlike the
+            // ChannelData frames it enables, which carry no STUN header
+            if let Err(e) = authenticate_request(&message, request.username.as_deref(), request.nonce.as_deref(), context).await {
+                let Some((realm, nonce)) = challenge(context, src_addr).await else {
+                    return Ok(());
+                };
+                let response = ChannelBindResponse::error(request.transaction_id, e.error_code(), e.to_string(), realm, nonce);
+                send_response(response, context, src_addr).await?;
+                return Ok(());
             }
             
-            let response = ChannelBindResponse::success(request.transaction_id);
-            send_success_response(response, &socket, src_addr).await?;
+            let response = match allocation_manager.add_channel_binding(&five_tuple, request.channel_number, request.peer_address) {
+                Ok(()) => ChannelBindResponse::success(request.transaction_id),
+                Err(e) => ChannelBindResponse::error(request.transaction_id, e.error_code(), e.to_string(), None, None),
+            };
+            send_response(response, context, src_addr).await?;
         }
         _ => {
             warn!("Unhandled request method: {:?}", message.message_type.method());
@@ -156,23 +384,172 @@ async fn handle_request(
     Ok(())
 }
 
+// Error response for a request that failed to parse
+async fn reject_malformed(
+    message: &Message,
+    e: TurnError,
+    src_addr: SocketAddr,
+    context: &HandlerContext,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let TurnError::UnsupportedAddressFamily(family) = e {
+        count_unsupported_family(family, src_addr, context);
+    }
+    send_response(ErrorResponse::new(message, e.error_code(), e.to_string()), context, src_addr).await
+}
+
+// The error response alone doesn't say which family byte was wrong
+fn count_unsupported_family(family: u8, src_addr: SocketAddr, context: &HandlerContext) {
+    context.stats.unsupported_families.fetch_add(1, Ordering::Relaxed);
+    debug!("Unsupported address family {:#04x} from {}", family, src_addr);
+}
+
+// Link-local (fe80::/10), multicast and unspecified sources are almost
+// always misconfigured clients. IPv4-mapped addresses are checked as IPv4.
+fn routable_client_address(ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    let link_local = matches!(ip, IpAddr::V6(ip) if ip.is_unicast_link_local());
+    !(link_local || ip.is_multicast() || ip.is_unspecified())
+}
+
+// REQUESTED-TRANSPORT is mandatory and only UDP relays exist. EVEN-PORT and
+// RESERVATION-TOKEN are mutually exclusive (RFC 5766 section 6.2), and a
+// token already fixes the family (RFC 6156 section 4.2).
+fn relay_request(request: &AllocateRequest) -> Result<RelayRequest, TurnError> {
+    match request.requested_transport {
+        Some(TRANSPORT_UDP) => {}
+        Some(_) => return Err(TurnError::UnsupportedTransportProtocol),
+        None => return Err(TurnError::BadRequest),
+    }
+    
+    let family = match request.requested_address_family {
+        None | Some(0x01) => AddressFamily::IPv4,
+        Some(0x02) => AddressFamily::IPv6,
+        Some(_) => return Err(TurnError::AddressFamilyNotSupported),
+    };
+    
+    let port = match (request.even_port, request.reservation_token) {
+        (false, None) => RelayPortRequest::Any,
+        (true, None) => RelayPortRequest::Even { reserve_next: request.reserve_next_port },
+        (false, Some(_)) if request.requested_address_family.is_some() => return Err(TurnError::BadRequest),
+        (false, Some(token)) => RelayPortRequest::Reserved(token),
+        (true, Some(_)) => return Err(TurnError::BadRequest),
+    };
+    
+    Ok(RelayRequest { family, port })
+}
+
+// Long-term credential check (RFC 5389 section 10.2.2). Missing credentials
+// or a bad MESSAGE-INTEGRITY are Unauthorized; a NONCE we did not issue or
+// that has expired is StaleNonce so the client retries with the new one.
+async fn authenticate_request(
+    message: &Message,
+    username: Option<&str>,
+    nonce: Option<&[u8]>,
+    context: &HandlerContext,
+) -> Result<(), TurnError> {
+    let (Some(_), Some(nonce)) = (username, nonce) else {
+        return Err(TurnError::Unauthorized);
+    };
+    let nonce_valid = match std::str::from_utf8(nonce) {
+        Ok(nonce) => context.nonce_manager.write().await.validate_nonce(nonce),
+        Err(_) => Err(TurnError::StaleNonce),
+    };
+    if let Err(e) = nonce_valid {
+        context.stats.stale_nonces.fetch_add(1, Ordering::Relaxed);
+        return Err(e);
+    }
+    
+    match verify_request_integrity(message, username, &context.user_database, &context.realm) {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            context.stats.auth_failures.fetch_add(1, Ordering::Relaxed);
+            Err(TurnError::Unauthorized)
+        }
+        Err(e) => {
+            context.stats.auth_failures.fetch_add(1, Ordering::Relaxed);
+            Err(e.into())
+        }
+    }
+}
+
+// REALM and a fresh NONCE for a 401 or 438 challenge, or None when the
+// server-wide nonce rate is exceeded and the request should be dropped
+async fn challenge(context: &HandlerContext, src_addr: SocketAddr) -> Option<(Option<String>, Option<Vec<u8>>)> {
+    let Some(nonce) = context.nonce_manager.write().await.try_generate_nonce() else {
+        debug!("Nonce rate limit reached, dropping request from {}", src_addr);
+        return None;
+    };
+    Some((Some(context.realm.clone()), Some(nonce.into_bytes())))
+}
+
+fn verify_request_integrity(
+    message: &Message,
+    username: Option<&str>,
+    user_database: &UserDatabase,
+    realm: &str,
+) -> Result<bool, StunError> {
+    let Some(username) = username else {
+        return Ok(false);
+    };
+    let Some(password) = user_database.get_password(username) else {
+        return Ok(false);
+    };
+    
+    let credentials = Credentials::new(username.to_string(), password.clone(), realm.to_string());
+    verify_long_term_integrity(message, &credentials)
+}
+
 async fn handle_indication(
     message: Message,
     src_addr: SocketAddr,
-    allocation_manager: Arc<AllocationManager>,
+    context: &HandlerContext,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    use crate::stun::message::MessageMethod;
+    let five_tuple = FiveTuple::new(src_addr, context.connection.transport());
+    
+    // Indications can't be answered with a 420, so they are dropped
+    if !unknown_comprehension_required(&message)?.is_empty() {
+        return Ok(());
+    }
     
     match message.message_type.method() {
         MessageMethod::Send => {
-            let indication = SendIndication::from_message(&message)?;
-            
-            if let Some(allocation) = allocation_manager.get_allocation(&src_addr) {
-                if allocation.has_permission(&indication.peer_address) {
-                    // Send data to peer
-                    allocation.relay_socket.send_to(&indication.data, indication.peer_address).await?;
+            let indication = match SendIndication::from_message(&message) {
+                Ok(indication) => indication,
+                Err(TurnError::UnsupportedAddressFamily(family)) => {
+                    count_unsupported_family(family, src_addr, context);
+                    return Ok(());
                 }
+                Err(e) => return Err(e.into()),
+            };
+            
+            // Indications get no response, so oversized data is only counted
+            if let Some(max_send_data_bytes) = context.max_send_data_bytes
+                && indication.data.len() > max_send_data_bytes
+            {
+                context.stats.oversized_send_indications.fetch_add(1, Ordering::Relaxed);
+                debug!("Dropping Send indication from {} with {} bytes of data", src_addr, indication.data.len());
+                return Ok(());
             }
+            
+            let Some(allocation) = context.allocation_manager.get_allocation(&five_tuple) else {
+                return Ok(());
+            };
+            
+            // Dropped silently as RFC 5766 requires, but counted
+            if !allocation.has_permission(&indication.peer_address) {
+                context.stats.permissions_denied.fetch_add(1, Ordering::Relaxed);
+                debug!("Dropping Send indication from {} to {} without permission", src_addr, indication.peer_address);
+                return Ok(());
+            }
+            
+            // Sending to a peer keeps its permission alive. RFC 5766 section 8
+            // only refreshes through CreatePermission and ChannelBind; this is
+            // a deliberate deviation, and safe because only the allocation's
+            // own client can send. Peer traffic never refreshes a permission.
+            context.allocation_manager.add_permission(&five_tuple, indication.peer_address);
+            
+            // Send data to peer
+            relay_to_peer(&allocation, &indication.data, indication.peer_address, indication.dont_fragment, &context.stats).await;
         }
         _ => {
             warn!("Unhandled indication method: {:?}", message.message_type.method());
@@ -184,53 +561,1186 @@ async fn handle_indication(
 
 async fn handle_channel_data(
     channel_data: ChannelData,
-    src_addr: SocketAddr,
-    allocation_manager: Arc<AllocationManager>,
+    five_tuple: FiveTuple,
+    context: &HandlerContext,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    if let Some(allocation) = allocation_manager.get_allocation(&src_addr) {
-        if let Some(peer_addr) = allocation.get_peer_by_channel(channel_data.channel_number) {
-            // Send data to peer
-            allocation.relay_socket.send_to(&channel_data.data, peer_addr).await?;
-        }
+    if let Some(allocation) = context.allocation_manager.get_allocation(&five_tuple)
+        && let Some(&peer_addr) = allocation.get_peer_by_channel(channel_data.channel_number)
+    {
+        // Send data to peer
+        relay_to_peer(&allocation, &channel_data.data, peer_addr, false, &context.stats).await;
     }
     
     Ok(())
 }
 
-async fn send_success_response<T>(
-    _response: T,
-    socket: &UdpSocket,
-    dst_addr: SocketAddr,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // TODO: Properly serialize response based on type
-    // For now, send a minimal success response
-    let response_data = vec![0u8; 20]; // Placeholder
-    socket.send_to(&response_data, dst_addr).await?;
-    Ok(())
+// Send failures are recorded on the allocation rather than failing the
+// packet, so an unreachable peer doesn't flood the error log
+async fn relay_to_peer(
+    allocation: &Allocation,
+    data: &[u8],
+    peer_addr: SocketAddr,
+    dont_fragment: bool,
+    stats: &ServerStats,
+) {
+    let mut dont_fragment_set = allocation.dont_fragment.lock().await;
+    
+    // A packet that asked for DF is dropped rather than sent without it
+    if *dont_fragment_set != dont_fragment {
+        if let Err(e) = set_dont_fragment(&allocation.relay_socket, dont_fragment) {
+            debug!("Setting DF for {} failed: {}", allocation.client_address, e);
+            allocation.relay_errors.record(e.kind(), peer_addr);
+            return;
+        }
+        *dont_fragment_set = dont_fragment;
+    }
+    
+    match allocation.relay_socket.send_to(data, peer_addr).await {
+        Ok(sent) => {
+            stats.bytes_relayed.fetch_add(sent as u64, Ordering::Relaxed);
+        }
+        Err(e) => {
+            debug!("Relay to {} for {} failed: {}", peer_addr, allocation.client_address, e);
+            allocation.relay_errors.record(e.kind(), peer_addr);
+        }
+    }
 }
 
-async fn send_error_response(
-    transaction_id: [u8; 12],
-    error_code: u16,
-    _error_text: &str,
-    socket: &UdpSocket,
+async fn send_response<T: IntoStunMessage>(
+    response: T,
+    context: &HandlerContext,
     dst_addr: SocketAddr,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    use crate::stun::message::{MessageType, MessageMethod};
-    
-    let mut response = Message::new(MessageType::new(
-        MessageMethod::Allocate,
-        MessageClass::ErrorResponse,
-    ));
-    response.transaction_id = transaction_id;
-    
-    // Add ERROR-CODE attribute
-    let error_data = vec![(error_code / 100) as u8, (error_code % 100) as u8, 0, 0];
-    let error_attr = RawAttribute::new(AttributeType::ErrorCode as u16, error_data);
-    response.attributes = error_attr.serialize();
-    response.length = response.attributes.len() as u16;
-    
-    let response_data = response.serialize();
-    socket.send_to(&response_data, dst_addr).await?;
+    let mut message = response.to_message();
+    
+    if let Some(software) = &context.software {
+        message.push_attribute(RawAttribute::new(AttributeType::Software as u16, software.as_bytes().to_vec()));
+    }
+    
+    let response_data = message.serialize();
+    context.connection.send_to(&response_data, dst_addr).await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UdpSocket;
+    use std::time::Duration;
+    use crate::stun::attributes::decode_error_code;
+    use crate::stun::xor_addr::{decode_xor_address, encode_xor_address};
+    use crate::stun::message::MessageType;
+    use crate::stun::auth::calculate_message_integrity;
+    use crate::turn::admission::{AdmissionFuture, AdmissionPolicy};
+
+    const REALM: &str = "test.realm";
+
+    struct TestServer {
+        client: UdpSocket,
+        context: HandlerContext,
+    }
+
+    impl TestServer {
+        async fn new(user_database: UserDatabase) -> Self {
+            TestServer {
+                client: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+                context: HandlerContext {
+                    connection: ClientConnection::Udp(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap())),
+                    allocation_manager: Arc::new(AllocationManager::new(vec!["127.0.0.1:0".parse().unwrap()])),
+                    nonce_manager: Arc::new(RwLock::new(NonceManager::new(Duration::from_secs(300)))),
+                    user_database: Arc::new(user_database),
+                    realm: REALM.to_string(),
+                    max_send_data_bytes: None,
+                    advertised_bandwidth_kbps: None,
+                    software: None,
+                    stats: Arc::new(ServerStats::default()),
+                    turn_enabled: true,
+                    alternate_server: None,
+                    reject_link_local_clients: false,
+                    allocate_rate_limiter: None,
+                    relay_task_budget: None,
+                    relay_runtime: None,
+                },
+            }
+        }
+
+        async fn exchange(&self, data: Vec<u8>) -> Option<Message> {
+            handle_message(data, self.client.local_addr().unwrap(), self.context.clone()).await.unwrap();
+
+            let mut buf = vec![0u8; 1500];
+            let len = tokio::time::timeout(Duration::from_millis(200), self.client.recv(&mut buf))
+                .await
+                .ok()?
+                .unwrap();
+            Message::parse(&buf[..len]).ok()
+        }
+
+        async fn nonce(&self) -> String {
+            self.context.nonce_manager.write().await.generate_nonce()
+        }
+
+        async fn sign(&self, message: Message) -> Message {
+            with_credentials(message, &self.nonce().await, Some(&alice_key("password123")))
+        }
+    }
+
+    fn alice_database() -> UserDatabase {
+        let mut user_database = UserDatabase::new();
+        user_database.add_user("alice".to_string(), "password123".to_string());
+        user_database
+    }
+
+    fn alice_key(password: &str) -> Vec<u8> {
+        Credentials::new("alice".to_string(), password.to_string(), REALM.to_string()).compute_key()
+    }
+
+    // Appends USERNAME and NONCE, then MESSAGE-INTEGRITY if a key is given
+    fn with_credentials(mut message: Message, nonce: &str, key: Option<&[u8]>) -> Message {
+        message.attributes.extend(RawAttribute::new(AttributeType::Username as u16, b"alice".to_vec()).serialize());
+        message.attributes.extend(RawAttribute::new(AttributeType::Nonce as u16, nonce.as_bytes().to_vec()).serialize());
+        message.length = message.attributes.len() as u16;
+
+        if let Some(key) = key {
+            let integrity = calculate_message_integrity(&message, key).unwrap();
+            message.attributes.extend(RawAttribute::new(AttributeType::MessageIntegrity as u16, integrity).serialize());
+            message.length = message.attributes.len() as u16;
+        }
+
+        message
+    }
+
+    fn allocate_message(lifetime: Option<u32>) -> Message {
+        let mut request = Message::new(MessageType::new(
+            MessageMethod::Allocate,
+            MessageClass::Request,
+        ));
+        let mut attrs = Vec::new();
+        attrs.extend(RawAttribute::new(AttributeType::RequestedTransport as u16, vec![17, 0, 0, 0]).serialize());
+        if let Some(lifetime) = lifetime {
+            attrs.extend(RawAttribute::new(AttributeType::Lifetime as u16, lifetime.to_be_bytes().to_vec()).serialize());
+        }
+        request.attributes = attrs;
+        request.length = request.attributes.len() as u16;
+        request
+    }
+
+    fn refresh_message(lifetime: u32) -> Message {
+        let mut request = Message::new(MessageType::new(
+            MessageMethod::Refresh,
+            MessageClass::Request,
+        ));
+        request.attributes = RawAttribute::new(AttributeType::Lifetime as u16, lifetime.to_be_bytes().to_vec()).serialize();
+        request.length = request.attributes.len() as u16;
+        request
+    }
+
+    fn find_attribute(message: &Message, attribute_type: AttributeType) -> Option<RawAttribute> {
+        message.attributes_iter()
+            .map_while(Result::ok)
+            .find(|attr| attr.attribute_type == attribute_type as u16)
+    }
+
+    fn lifetime(message: &Message) -> Option<u32> {
+        let attr = find_attribute(message, AttributeType::Lifetime)?;
+        Some(u32::from_be_bytes(attr.value[..4].try_into().ok()?))
+    }
+
+    fn channel_bind_message() -> Message {
+        let mut message = Message::new(MessageType::new(
+            MessageMethod::ChannelBind,
+            MessageClass::Request,
+        ));
+
+        let mut attrs = Vec::new();
+        attrs.extend(RawAttribute::new(AttributeType::ChannelNumber as u16, vec![0x40, 0x00, 0, 0]).serialize());
+        attrs.extend(RawAttribute::new(AttributeType::XorPeerAddress as u16, encode_xor_address("192.0.2.1:80".parse().unwrap(), &message.transaction_id)).serialize());
+        message.attributes = attrs;
+        message.length = message.attributes.len() as u16;
+        message
+    }
+
+    fn error_code(message: &Message) -> Option<u16> {
+        let attr = find_attribute(message, AttributeType::ErrorCode)?;
+        decode_error_code(&attr.value).ok().map(|(code, _)| code)
+    }
+
+    #[tokio::test]
+    async fn test_channel_bind_without_integrity_rejected() {
+        let server = TestServer::new(alice_database()).await;
+
+        let request = with_credentials(channel_bind_message(), &server.nonce().await, None);
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        assert_eq!(response.message_type.method(), MessageMethod::ChannelBind);
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(response.transaction_id, request.transaction_id);
+        assert_eq!(error_code(&response), Some(401));
+    }
+
+    #[tokio::test]
+    async fn test_channel_data_relayed_without_integrity() {
+        let server = TestServer::new(alice_database()).await;
+        let client_addr = server.client.local_addr().unwrap();
+        let request = server.sign(allocate_message(None)).await;
+        server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut request = Message::new(MessageType::new(MessageMethod::ChannelBind, MessageClass::Request));
+        request.attributes = RawAttribute::new(AttributeType::ChannelNumber as u16, vec![0x40, 0x01, 0, 0]).serialize();
+        request.attributes.extend(RawAttribute::new(AttributeType::XorPeerAddress as u16, encode_xor_address(peer.local_addr().unwrap(), &request.transaction_id)).serialize());
+        request.length = request.attributes.len() as u16;
+        let request = server.sign(request).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+
+        // ChannelData carries no credentials; the 5-tuple and binding are
+        // all that authorize it
+        let frame = ChannelData::new(0x4001, b"unsigned".to_vec()).unwrap().serialize();
+        handle_message(frame, client_addr, server.context.clone()).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(1), peer.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..len], b"unsigned");
+    }
+
+    #[tokio::test]
+    async fn test_channel_bind_with_wrong_key_rejected() {
+        let server = TestServer::new(alice_database()).await;
+
+        let wrong_key = alice_key("guess");
+        let request = with_credentials(channel_bind_message(), &server.nonce().await, Some(&wrong_key));
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(error_code(&response), Some(401));
+    }
+
+    #[tokio::test]
+    async fn test_success_response_round_trip() {
+        let server = TestServer::new(alice_database()).await;
+
+        let mut request = Message::new(MessageType::new(
+            MessageMethod::CreatePermission,
+            MessageClass::Request,
+        ));
+        request.attributes = RawAttribute::new(AttributeType::XorPeerAddress as u16, encode_xor_address("192.0.2.1:80".parse().unwrap(), &request.transaction_id)).serialize();
+        request.length = request.attributes.len() as u16;
+        let request = server.sign(request).await;
+
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        assert_eq!(response.message_type.method(), MessageMethod::CreatePermission);
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+        assert_eq!(response.transaction_id, request.transaction_id);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_success_response_round_trip() {
+        let server = TestServer::new(alice_database()).await;
+        let request = server.sign(allocate_message(None)).await;
+
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        assert_eq!(response.message_type.method(), MessageMethod::Allocate);
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+        assert_eq!(response.transaction_id, request.transaction_id);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_integrity_checked() {
+        let server = TestServer::new(alice_database()).await;
+
+        let wrong_key = alice_key("guess");
+        let request = with_credentials(allocate_message(None), &server.nonce().await, Some(&wrong_key));
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(error_code(&response), Some(401));
+        assert_eq!(server.context.stats.auth_failures.load(Ordering::Relaxed), 1);
+
+        let request = server.sign(allocate_message(None)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+        assert_eq!(server.context.stats.auth_failures.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_with_unknown_nonce_rejected() {
+        let server = TestServer::new(alice_database()).await;
+
+        // Correctly signed, but the nonce was never issued by this server
+        let key = alice_key("password123");
+        let request = with_credentials(allocate_message(None), "0123456789abcdef", Some(&key));
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(error_code(&response), Some(438));
+        assert_eq!(server.context.stats.stale_nonces.load(Ordering::Relaxed), 1);
+        assert_eq!(server.context.stats.auth_failures.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_expired_nonce_returns_438_with_fresh_nonce() {
+        let mut server = TestServer::new(alice_database()).await;
+        server.context.nonce_manager = Arc::new(RwLock::new(NonceManager::new(Duration::ZERO)));
+
+        let stale_nonce = server.nonce().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let key = alice_key("password123");
+        let request = with_credentials(allocate_message(None), &stale_nonce, Some(&key));
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(error_code(&response), Some(438));
+
+        let realm = find_attribute(&response, AttributeType::Realm).unwrap();
+        assert_eq!(realm.value, REALM.as_bytes());
+        let nonce = find_attribute(&response, AttributeType::Nonce).unwrap();
+        assert!(!nonce.value.is_empty());
+        assert_ne!(nonce.value, stale_nonce.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_missing_credentials_challenged_with_realm_and_nonce() {
+        let server = TestServer::new(alice_database()).await;
+
+        let response = server.exchange(allocate_message(None).serialize().to_vec()).await.unwrap();
+
+        assert_eq!(error_code(&response), Some(401));
+        assert!(find_attribute(&response, AttributeType::Realm).is_some());
+        assert!(find_attribute(&response, AttributeType::Nonce).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_challenges_dropped_past_nonce_rate_limit() {
+        let mut server = TestServer::new(alice_database()).await;
+        server.context.nonce_manager = Arc::new(RwLock::new(
+            NonceManager::new(Duration::from_secs(300)).with_rate_limit(5),
+        ));
+
+        // Sent back to back, well inside one rate window
+        let client_addr = server.client.local_addr().unwrap();
+        for _ in 0..20 {
+            let request = allocate_message(None);
+            handle_message(request.serialize().to_vec(), client_addr, server.context.clone()).await.unwrap();
+        }
+
+        let mut challenged = 0;
+        let mut buf = vec![0u8; 1500];
+        while let Ok(received) = tokio::time::timeout(Duration::from_millis(200), server.client.recv(&mut buf)).await {
+            let response = Message::parse(&buf[..received.unwrap()]).unwrap();
+            assert_eq!(error_code(&response), Some(401));
+            challenged += 1;
+        }
+
+        assert_eq!(challenged, 5);
+        assert_eq!(server.context.nonce_manager.read().await.outstanding(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_before_allocate_returns_437() {
+        let server = TestServer::new(alice_database()).await;
+        let request = server.sign(refresh_message(600)).await;
+
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        assert_eq!(response.message_type.method(), MessageMethod::Refresh);
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(response.transaction_id, request.transaction_id);
+        assert_eq!(error_code(&response), Some(437));
+    }
+
+    #[tokio::test]
+    async fn test_allocate_and_refresh_report_lifetime() {
+        let server = TestServer::new(alice_database()).await;
+
+        let request = server.sign(allocate_message(None)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+        assert_eq!(lifetime(&response), Some(600));
+
+        let request = server.sign(refresh_message(300)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.method(), MessageMethod::Refresh);
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+        assert_eq!(lifetime(&response), Some(300));
+    }
+
+    #[tokio::test]
+    async fn test_overlong_username_rejected_before_authentication() {
+        let server = TestServer::new(alice_database()).await;
+
+        for mut request in [allocate_message(None), refresh_message(600)] {
+            request.attributes.extend(RawAttribute::new(AttributeType::Username as u16, vec![b'a'; 600]).serialize());
+            request.length = request.attributes.len() as u16;
+
+            let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+            assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+            assert_eq!(error_code(&response), Some(400));
+            assert!(find_attribute(&response, AttributeType::Nonce).is_none());
+        }
+        assert_eq!(server.context.stats.auth_failures.load(Ordering::Relaxed), 0);
+
+        // An ordinary username still gets through to authentication
+        let request = server.sign(allocate_message(None)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_delete_reports_zero_lifetime() {
+        let server = TestServer::new(alice_database()).await;
+        let request = server.sign(allocate_message(None)).await;
+        server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        let request = server.sign(refresh_message(0)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.method(), MessageMethod::Refresh);
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+        assert_eq!(response.transaction_id, request.transaction_id);
+        assert_eq!(lifetime(&response), Some(0));
+        assert_eq!(server.context.allocation_manager.allocation_count(), 0);
+
+        // Nothing left to delete
+        let request = server.sign(refresh_message(0)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(error_code(&response), Some(437));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_lifetime_clamped_to_maximum() {
+        let server = TestServer::new(alice_database()).await;
+        let client_addr = server.client.local_addr().unwrap();
+        let request = server.sign(allocate_message(None)).await;
+        server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        let request = server.sign(refresh_message(7200)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+        assert_eq!(lifetime(&response), Some(3600));
+
+        let allocation = server.context.allocation_manager.get_allocation(&FiveTuple::udp(client_addr)).unwrap();
+        assert_eq!(allocation.lifetime, Duration::from_secs(3600));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_send_indication_dropped() {
+        let mut server = TestServer::new(UserDatabase::new()).await;
+        server.context.max_send_data_bytes = Some(16);
+        let peer_addr: SocketAddr = "192.0.2.1:80".parse().unwrap();
+
+        let oversized = SendIndication {
+            transaction_id: [1; 12],
+            peer_address: peer_addr,
+            data: vec![0xAB; 17],
+            dont_fragment: false,
+        };
+        assert!(server.exchange(oversized.to_message().serialize().to_vec()).await.is_none());
+        assert_eq!(server.context.stats.snapshot(&server.context.allocation_manager).oversized_send_indications, 1);
+
+        // Data at the limit is not counted
+        let within_limit = SendIndication {
+            transaction_id: [2; 12],
+            peer_address: peer_addr,
+            data: vec![0xAB; 16],
+            dont_fragment: false,
+        };
+        server.exchange(within_limit.to_message().serialize().to_vec()).await;
+        assert_eq!(server.context.stats.snapshot(&server.context.allocation_manager).oversized_send_indications, 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_send_indication_dont_fragment_reaches_relay_socket() {
+        use crate::server::socket_options::dont_fragment;
+
+        let server = TestServer::new(alice_database()).await;
+        let client_addr = server.client.local_addr().unwrap();
+        let request = server.sign(allocate_message(None)).await;
+        server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        server.context.allocation_manager.add_permission(&FiveTuple::udp(client_addr), peer_addr);
+        let relay_socket = server.context.allocation_manager.get_allocation(&FiveTuple::udp(client_addr)).unwrap().relay_socket;
+
+        let mut buf = vec![0u8; 1500];
+        for (i, dont_fragment_set) in [true, false].into_iter().enumerate() {
+            let indication = SendIndication {
+                transaction_id: [i as u8; 12],
+                peer_address: peer_addr,
+                data: b"payload".to_vec(),
+                dont_fragment: dont_fragment_set,
+            };
+            handle_message(indication.to_message().serialize().to_vec(), client_addr, server.context.clone()).await.unwrap();
+
+            let len = tokio::time::timeout(Duration::from_millis(200), peer.recv(&mut buf)).await.unwrap().unwrap();
+            assert_eq!(&buf[..len], b"payload");
+            assert_eq!(dont_fragment(&relay_socket).unwrap(), dont_fragment_set);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_concurrent_send_keeps_dont_fragment_of_packet_in_flight() {
+        use crate::server::socket_options::dont_fragment;
+
+        let server = TestServer::new(alice_database()).await;
+        let client_addr = server.client.local_addr().unwrap();
+        let request = server.sign(allocate_message(None)).await;
+        server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        server.context.allocation_manager.add_permission(&FiveTuple::udp(client_addr), peer_addr);
+        let allocation = server.context.allocation_manager.get_allocation(&FiveTuple::udp(client_addr)).unwrap();
+        let send = |transaction_id: u8, dont_fragment: bool| SendIndication {
+            transaction_id: [transaction_id; 12],
+            peer_address: peer_addr,
+            data: vec![transaction_id],
+            dont_fragment,
+        }.to_message().serialize().to_vec();
+
+        let mut buf = vec![0u8; 1500];
+        handle_message(send(1, true), client_addr, server.context.clone()).await.unwrap();
+        tokio::time::timeout(Duration::from_millis(200), peer.recv(&mut buf)).await.unwrap().unwrap();
+
+        // A DF packet between setting DF and being sent
+        let in_flight = allocation.dont_fragment.lock().await;
+        let (data, context) = (send(2, false), server.context.clone());
+        let without_df = tokio::spawn(async move {
+            handle_message(data, client_addr, context).await.unwrap();
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(dont_fragment(&allocation.relay_socket).unwrap());
+        assert!(tokio::time::timeout(Duration::from_millis(50), peer.recv(&mut buf)).await.is_err());
+
+        drop(in_flight);
+        without_df.await.unwrap();
+        let len = tokio::time::timeout(Duration::from_millis(200), peer.recv(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..len], [2]);
+        assert!(!dont_fragment(&allocation.relay_socket).unwrap());
+    }
+
+    // 127.0.0.2 is only routed to loopback out of the box on Linux
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_relayed_packets_sourced_from_relay_ip() {
+        let server = server_with_pool(TestServer::new(alice_database()).await, &["127.0.0.1:0", "127.0.0.2:0"]);
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        // One client per relay IP; the pool hands out 127.0.0.2 first
+        let mut buf = vec![0u8; 1500];
+        for expected_ip in ["127.0.0.2", "127.0.0.1"] {
+            let client = TestServer {
+                client: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+                context: server.context.clone(),
+            };
+            let client_addr = client.client.local_addr().unwrap();
+            let request = client.sign(allocate_message(None)).await;
+            let response = client.exchange(request.serialize().to_vec()).await.unwrap();
+            assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+
+            let allocation = server.context.allocation_manager.get_allocation(&FiveTuple::udp(client_addr)).unwrap();
+            assert_eq!(allocation.relayed_address.ip().to_string(), expected_ip);
+            server.context.allocation_manager.add_permission(&FiveTuple::udp(client_addr), peer_addr);
+
+            let indication = SendIndication {
+                transaction_id: [1; 12],
+                peer_address: peer_addr,
+                data: b"payload".to_vec(),
+                dont_fragment: false,
+            };
+            handle_message(indication.to_message().serialize().to_vec(), client_addr, server.context.clone()).await.unwrap();
+
+            let (len, from) = tokio::time::timeout(Duration::from_millis(200), peer.recv_from(&mut buf)).await.unwrap().unwrap();
+            assert_eq!(&buf[..len], b"payload");
+            assert_eq!(from.ip(), allocation.relayed_address.ip());
+            assert_eq!(from, allocation.relay_socket.local_addr().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stun_only_mode_serves_binding_only() {
+        let mut server = TestServer::new(alice_database()).await;
+        server.context.turn_enabled = false;
+
+        let binding = Message::new(MessageType::new(MessageMethod::Binding, MessageClass::Request));
+        let response = server.exchange(binding.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+
+        // Rejected even with valid credentials, and nothing is allocated
+        let request = server.sign(allocate_message(None)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.method(), MessageMethod::Allocate);
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(error_code(&response), Some(403));
+        assert_eq!(server.context.allocation_manager.allocation_count(), 0);
+
+        let request = server.sign(channel_bind_message()).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.method(), MessageMethod::ChannelBind);
+        assert_eq!(error_code(&response), Some(403));
+    }
+
+    #[tokio::test]
+    async fn test_second_allocate_returns_437() {
+        use crate::turn::relay_address::RelayAddressPool;
+
+        let mut server = TestServer::new(alice_database()).await;
+        let pool = Arc::new(RelayAddressPool::new(vec!["127.0.0.1:0".parse().unwrap(); 3]));
+        server.context.allocation_manager = Arc::new(AllocationManager::with_provider(pool.clone()));
+
+        let request = server.sign(allocate_message(None)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+        assert_eq!(pool.available(), 2);
+
+        // Same request again, as a retransmit would send it
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(error_code(&response), Some(437));
+        assert_eq!(pool.available(), 2);
+        assert_eq!(server.context.allocation_manager.allocation_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_relay_task_budget_exhausted_returns_508() {
+        use crate::turn::relay_address::RelayAddressPool;
+
+        let mut server = TestServer::new(alice_database()).await;
+        let pool = Arc::new(RelayAddressPool::new(vec!["127.0.0.1:0".parse().unwrap(); 3]));
+        server.context.allocation_manager = Arc::new(AllocationManager::with_provider(pool.clone()));
+        let budget = Arc::new(Semaphore::new(1));
+        server.context.relay_task_budget = Some(budget.clone());
+        let mut other = TestServer::new(alice_database()).await;
+        other.context = server.context.clone();
+
+        let request = server.sign(allocate_message(None)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+        assert_eq!(budget.available_permits(), 0);
+
+        // Relay addresses are left, but no relay task can be spawned
+        let request = other.sign(allocate_message(None)).await;
+        let response = other.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(error_code(&response), Some(508));
+        assert_eq!(pool.available(), 2);
+
+        // The first relay gives its permit back once stopped
+        server.context.allocation_manager.remove_allocation(&FiveTuple::udp(server.client.local_addr().unwrap()));
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while budget.available_permits() == 0 {
+                tokio::task::yield_now().await;
+            }
+        }).await.unwrap();
+
+        let request = other.sign(allocate_message(None)).await;
+        let response = other.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_advertises_bandwidth() {
+        let mut server = TestServer::new(alice_database()).await;
+        server.context.advertised_bandwidth_kbps = Some(2000);
+
+        let request = server.sign(allocate_message(None)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+
+        let attr = find_attribute(&response, AttributeType::Bandwidth).unwrap();
+        assert_eq!(attr.value, 2000u32.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_allocate_redirected_to_alternate_server() {
+        use crate::stun::attributes::decode_address;
+
+        let alternate_server: SocketAddr = "198.51.100.7:3478".parse().unwrap();
+        let mut server = TestServer::new(alice_database()).await;
+        server.context.alternate_server = Some(alternate_server);
+
+        let request = server.sign(allocate_message(None)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(error_code(&response), Some(300));
+
+        let attr = find_attribute(&response, AttributeType::AlternateServer).unwrap();
+        assert_eq!(decode_address(&attr.value), Some(alternate_server));
+        assert_eq!(server.context.allocation_manager.allocation_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_flood_rate_limited() {
+        use crate::server::rate_limit::AllocateRateLimit;
+
+        let mut server = TestServer::new(alice_database()).await;
+        let limit = AllocateRateLimit { per_second: 1, burst: 3 };
+        server.context.allocate_rate_limiter = Some(Arc::new(RateLimiter::new(limit)));
+
+        // Unauthenticated requests use up the bucket too
+        let mut codes = Vec::new();
+        for _ in 0..10 {
+            let response = server.exchange(allocate_message(None).serialize().to_vec()).await.unwrap();
+            codes.push(error_code(&response).unwrap());
+        }
+        assert_eq!(codes[..3], [401; 3]);
+        assert_eq!(codes[3..], [486; 7]);
+        assert_eq!(server.context.stats.rate_limited_allocates.load(Ordering::Relaxed), 7);
+
+        let request = server.sign(allocate_message(None)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(error_code(&response), Some(486));
+        assert_eq!(server.context.allocation_manager.allocation_count(), 0);
+    }
+
+    #[test]
+    fn test_routable_client_address() {
+        assert!(!routable_client_address("fe80::1".parse().unwrap()));
+        assert!(!routable_client_address("ff02::1".parse().unwrap()));
+        assert!(!routable_client_address("::".parse().unwrap()));
+        assert!(!routable_client_address("::ffff:224.0.0.1".parse().unwrap()));
+
+        assert!(routable_client_address("2001:db8::1".parse().unwrap()));
+        assert!(routable_client_address("192.0.2.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_allocate_from_link_local_client_rejected() {
+        let mut server = TestServer::new(alice_database()).await;
+        server.context.reject_link_local_clients = true;
+
+        // The response to a link-local source can't be delivered here, but
+        // no allocation is made for it
+        let link_local: SocketAddr = "[fe80::1]:40000".parse().unwrap();
+        let request = server.sign(allocate_message(None)).await;
+        let _ = handle_message(request.serialize().to_vec(), link_local, server.context.clone()).await;
+        assert_eq!(server.context.allocation_manager.allocation_count(), 0);
+
+        // Still served from an ordinary address
+        let request = server.sign(allocate_message(None)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_comprehension_required_attribute_returns_420() {
+        use crate::stun::attributes::decode_unknown_attributes;
+
+        let server = TestServer::new(alice_database()).await;
+        let mut request = allocate_message(None);
+        request.attributes.extend(RawAttribute::new(0x0099, vec![0; 4]).serialize());
+        request.length = request.attributes.len() as u16;
+        let request = server.sign(request).await;
+
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.method(), MessageMethod::Allocate);
+        assert_eq!(error_code(&response), Some(420));
+        let attr = find_attribute(&response, AttributeType::UnknownAttributes).unwrap();
+        assert_eq!(decode_unknown_attributes(&attr.value).unwrap(), vec![0x0099]);
+
+        // Comprehension-optional attributes are ignored
+        let mut request = allocate_message(None);
+        request.attributes.extend(RawAttribute::new(0x8099, vec![0; 4]).serialize());
+        request.length = request.attributes.len() as u16;
+        let request = server.sign(request).await;
+
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+    }
+
+    #[tokio::test]
+    async fn test_binding_request_reflects_source_address() {
+        let server = TestServer::new(UserDatabase::new()).await;
+        let request = Message::new(MessageType::new(
+            MessageMethod::Binding,
+            MessageClass::Request,
+        ));
+
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        assert_eq!(response.message_type.method(), MessageMethod::Binding);
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+        assert_eq!(response.transaction_id, request.transaction_id);
+        let attr = find_attribute(&response, AttributeType::XorMappedAddress).unwrap();
+        assert_eq!(
+            decode_xor_address(&attr.value, &response.transaction_id),
+            Some(server.client.local_addr().unwrap()),
+        );
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_request_logged_in_span() {
+        let server = TestServer::new(UserDatabase::new()).await;
+        let mut request = Message::new(MessageType::new(
+            MessageMethod::Binding,
+            MessageClass::Request,
+        ));
+        request.transaction_id = [0xab; 12];
+
+        server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        let client_addr = server.client.local_addr().unwrap();
+        assert!(logs_contain(&format!(
+            "stun{{src={} transaction_id={} method=Binding}}",
+            client_addr,
+            "ab".repeat(12),
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_datagram_with_trailing_bytes_ignored() {
+        let server = TestServer::new(UserDatabase::new()).await;
+        let request = Message::new(MessageType::new(
+            MessageMethod::Binding,
+            MessageClass::Request,
+        ));
+        let mut data = request.serialize().to_vec();
+        data.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+
+        assert!(server.exchange(data).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_allocate_without_requested_transport_returns_400() {
+        let server = TestServer::new(alice_database()).await;
+        let request = Message::new(MessageType::new(MessageMethod::Allocate, MessageClass::Request));
+        let request = server.sign(request).await;
+
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(error_code(&response), Some(400));
+        assert_eq!(server.context.allocation_manager.allocation_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_with_tcp_transport_returns_442() {
+        let server = TestServer::new(alice_database()).await;
+        let mut request = Message::new(MessageType::new(MessageMethod::Allocate, MessageClass::Request));
+        request.attributes = RawAttribute::new(AttributeType::RequestedTransport as u16, vec![6, 0, 0, 0]).serialize();
+        request.length = request.attributes.len() as u16;
+        let request = server.sign(request).await;
+
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(error_code(&response), Some(442));
+        assert_eq!(server.context.allocation_manager.allocation_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_with_malformed_requested_transport_returns_400() {
+        let server = TestServer::new(alice_database()).await;
+        let mut request = Message::new(MessageType::new(
+            MessageMethod::Allocate,
+            MessageClass::Request,
+        ));
+        request.attributes = RawAttribute::new(AttributeType::RequestedTransport as u16, vec![17, 1, 0, 0]).serialize();
+        request.length = request.attributes.len() as u16;
+        let request = server.sign(request).await;
+
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(error_code(&response), Some(400));
+    }
+
+    fn allocate_family_message(family: u8) -> Message {
+        let mut request = allocate_message(None);
+        request.attributes.extend(RawAttribute::new(AttributeType::RequestedAddressFamily as u16, vec![family, 0, 0, 0]).serialize());
+        request.length = request.attributes.len() as u16;
+        request
+    }
+
+    #[tokio::test]
+    async fn test_allocate_ipv6_relay_for_ipv4_client() {
+        let mut server = TestServer::new(alice_database()).await;
+        server.context.allocation_manager = Arc::new(AllocationManager::new(vec![
+            "127.0.0.1:0".parse().unwrap(),
+            "[::1]:0".parse().unwrap(),
+        ]));
+
+        let request = server.sign(allocate_family_message(0x02)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+        let attr = find_attribute(&response, AttributeType::XorRelayedAddress).unwrap();
+        assert_eq!(attr.value[1], 0x02);
+        let relayed = decode_xor_address(&attr.value, &response.transaction_id).unwrap();
+        assert!(relayed.is_ipv6());
+
+        // The client itself is still reached over IPv4
+        let attr = find_attribute(&response, AttributeType::XorMappedAddress).unwrap();
+        assert_eq!(attr.value[1], 0x01);
+        let mapped = decode_xor_address(&attr.value, &response.transaction_id).unwrap();
+        assert_eq!(mapped, server.client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_allocate_unavailable_address_family_returns_440() {
+        // The default pool has no IPv6 addresses
+        let server = TestServer::new(alice_database()).await;
+
+        let request = server.sign(allocate_family_message(0x02)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(error_code(&response), Some(440));
+        assert_eq!(server.context.stats.unsupported_families.load(Ordering::Relaxed), 0);
+
+        // Unknown family value
+        let request = server.sign(allocate_family_message(0x03)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(error_code(&response), Some(440));
+        assert_eq!(server.context.stats.unsupported_families.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_peer_address_with_unsupported_family_returns_400() {
+        let server = TestServer::new(alice_database()).await;
+
+        let request = server.sign(allocate_message(None)).await;
+        server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        let mut request = Message::new(MessageType::new(
+            MessageMethod::CreatePermission,
+            MessageClass::Request,
+        ));
+        let mut peer_address = encode_xor_address("192.0.2.1:80".parse().unwrap(), &request.transaction_id);
+        peer_address[1] = 0x03;
+        request.attributes = RawAttribute::new(AttributeType::XorPeerAddress as u16, peer_address).serialize();
+        request.length = request.attributes.len() as u16;
+        let request = server.sign(request).await;
+
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.method(), MessageMethod::CreatePermission);
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(error_code(&response), Some(400));
+        assert_eq!(server.context.stats.unsupported_families.load(Ordering::Relaxed), 1);
+    }
+
+    fn even_port_message(flags: u8) -> Message {
+        let mut request = allocate_message(None);
+        request.attributes.extend(RawAttribute::new(AttributeType::EvenPort as u16, vec![flags]).serialize());
+        request.length = request.attributes.len() as u16;
+        request
+    }
+
+    fn server_with_pool(mut server: TestServer, addresses: &[&str]) -> TestServer {
+        let addresses = addresses.iter().map(|addr| addr.parse().unwrap()).collect();
+        server.context.allocation_manager = Arc::new(AllocationManager::new(addresses));
+        server
+    }
+
+    #[tokio::test]
+    async fn test_allocate_even_port_without_reservation() {
+        let server = server_with_pool(TestServer::new(alice_database()).await, &["127.0.0.1:49271", "127.0.0.1:49272"]);
+
+        let request = server.sign(even_port_message(0x00)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+        let attr = find_attribute(&response, AttributeType::XorRelayedAddress).unwrap();
+        let relayed = decode_xor_address(&attr.value, &response.transaction_id).unwrap();
+        assert_eq!(relayed.port() % 2, 0);
+        assert!(find_attribute(&response, AttributeType::ReservationToken).is_none());
+
+        // The odd neighbour was not reserved
+        let client_addr = server.client.local_addr().unwrap();
+        let allocation = server.context.allocation_manager.get_allocation(&FiveTuple::udp(client_addr)).unwrap();
+        assert!(allocation.reservation_token.is_none());
+
+        // No even port left
+        let server = server_with_pool(TestServer::new(alice_database()).await, &["127.0.0.1:49273"]);
+        let request = server.sign(even_port_message(0x00)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(error_code(&response), Some(508));
+    }
+
+    // Rejects clients inside an IPv4 prefix
+    #[derive(Debug)]
+    struct RejectRange {
+        network: std::net::Ipv4Addr,
+        prefix_len: u32,
+    }
+
+    impl AdmissionPolicy for RejectRange {
+        fn allow<'a>(
+            &'a self,
+            _username: &'a str,
+            client_address: SocketAddr,
+            _realm: &'a str,
+        ) -> AdmissionFuture<'a> {
+            Box::pin(async move {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                match client_address.ip() {
+                    std::net::IpAddr::V4(ip) if u32::from(ip) & mask == u32::from(self.network) & mask => {
+                        Err(TurnError::Forbidden)
+                    }
+                    _ => Ok(()),
+                }
+            })
+        }
+    }
+
+    async fn server_with_policy(policy: RejectRange) -> TestServer {
+        let mut server = TestServer::new(alice_database()).await;
+        server.context.allocation_manager = Arc::new(
+            AllocationManager::new(vec!["127.0.0.1:0".parse().unwrap()])
+                .with_admission_policy(Arc::new(policy)),
+        );
+        server
+    }
+
+    #[tokio::test]
+    async fn test_admission_policy_rejection_returned() {
+        let server = server_with_policy(RejectRange {
+            network: "127.0.0.0".parse().unwrap(),
+            prefix_len: 8,
+        }).await;
+
+        let request = server.sign(allocate_message(None)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(error_code(&response), Some(403));
+
+        // Clients outside the range are admitted
+        let server = server_with_policy(RejectRange {
+            network: "10.0.0.0".parse().unwrap(),
+            prefix_len: 8,
+        }).await;
+
+        let request = server.sign(allocate_message(None)).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+    }
+
+    #[tokio::test]
+    async fn test_create_permission_persists() {
+        let server = TestServer::new(alice_database()).await;
+        let client_addr = server.client.local_addr().unwrap();
+        let peer_addr: SocketAddr = "192.0.2.1:80".parse().unwrap();
+
+        let request = server.sign(allocate_message(None)).await;
+        server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        let mut request = Message::new(MessageType::new(
+            MessageMethod::CreatePermission,
+            MessageClass::Request,
+        ));
+        request.attributes = RawAttribute::new(AttributeType::XorPeerAddress as u16, encode_xor_address(peer_addr, &request.transaction_id)).serialize();
+        request.length = request.attributes.len() as u16;
+        let request = server.sign(request).await;
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(response.message_type.class(), MessageClass::SuccessResponse);
+
+        let allocation = server.context.allocation_manager.get_allocation(&FiveTuple::udp(client_addr)).unwrap();
+        assert!(allocation.has_permission(&peer_addr));
+    }
+
+    #[tokio::test]
+    async fn test_responses_carry_software() {
+        let mut server = TestServer::new(UserDatabase::new()).await;
+        server.context.software = Some("toy-turn".to_string());
+
+        let request = Message::new(MessageType::new(MessageMethod::Binding, MessageClass::Request));
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert_eq!(find_attribute(&response, AttributeType::Software).unwrap().value, b"toy-turn");
+
+        // No SOFTWARE attribute unless configured
+        let server = TestServer::new(UserDatabase::new()).await;
+        let request = Message::new(MessageType::new(MessageMethod::Binding, MessageClass::Request));
+        let response = server.exchange(request.serialize().to_vec()).await.unwrap();
+        assert!(find_attribute(&response, AttributeType::Software).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_send_without_permission_counted() {
+        let server = TestServer::new(alice_database()).await;
+        let client_addr = server.client.local_addr().unwrap();
+        let request = server.sign(allocate_message(None)).await;
+        server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let send = SendIndication {
+            transaction_id: [1; 12],
+            peer_address: peer.local_addr().unwrap(),
+            data: b"payload".to_vec(),
+            dont_fragment: false,
+        };
+        handle_message(send.to_message().serialize().to_vec(), client_addr, server.context.clone()).await.unwrap();
+
+        let stats = server.context.stats.snapshot(&server.context.allocation_manager);
+        assert_eq!(stats.permissions_denied, 1);
+        assert_eq!(stats.bytes_relayed, 0);
+
+        // Once permitted the data goes through and is counted instead
+        server.context.allocation_manager.add_permission(&FiveTuple::udp(client_addr), send.peer_address);
+        handle_message(send.to_message().serialize().to_vec(), client_addr, server.context.clone()).await.unwrap();
+
+        let stats = server.context.stats.snapshot(&server.context.allocation_manager);
+        assert_eq!(stats.permissions_denied, 1);
+        assert_eq!(stats.bytes_relayed, b"payload".len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_send_refreshes_permission() {
+        let server = TestServer::new(alice_database()).await;
+        let client_addr = server.client.local_addr().unwrap();
+        let five_tuple = FiveTuple::udp(client_addr);
+        let request = server.sign(allocate_message(None)).await;
+        server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        // A permission a second away from expiring
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let nearly_expired = std::time::Instant::now().checked_sub(Duration::from_secs(299)).unwrap();
+        server.context.allocation_manager.with_allocation_mut(&five_tuple, |allocation| {
+            allocation.permissions.insert(peer_addr, nearly_expired);
+        });
+
+        let indication = SendIndication {
+            transaction_id: [1; 12],
+            peer_address: peer_addr,
+            data: b"payload".to_vec(),
+            dont_fragment: false,
+        };
+        handle_message(indication.to_message().serialize().to_vec(), client_addr, server.context.clone()).await.unwrap();
+
+        let allocation = server.context.allocation_manager.get_allocation(&five_tuple).unwrap();
+        assert!(allocation.permissions[&peer_addr] > nearly_expired);
+        assert!(allocation.permissions[&peer_addr].elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_relay_send_errors_recorded() {
+        let server = TestServer::new(alice_database()).await;
+        let client_addr = server.client.local_addr().unwrap();
+
+        let request = server.sign(allocate_message(None)).await;
+        server.exchange(request.serialize().to_vec()).await.unwrap();
+
+        // The relay socket is IPv4, so sends to an IPv6 peer fail
+        let peer_addr: SocketAddr = "[2001:db8::1]:9".parse().unwrap();
+        server.context.allocation_manager.add_permission(&FiveTuple::udp(client_addr), peer_addr);
+
+        for i in 0..20 {
+            let indication = SendIndication {
+                transaction_id: [i; 12],
+                peer_address: peer_addr,
+                data: b"payload".to_vec(),
+                dont_fragment: false,
+            };
+            // Indications get no response, so don't wait for one
+            handle_message(indication.to_message().serialize().to_vec(), client_addr, server.context.clone()).await.unwrap();
+        }
+
+        let errors = server.context.allocation_manager.relay_errors(&FiveTuple::udp(client_addr)).unwrap();
+        assert_eq!(errors.len(), crate::turn::allocation::RELAY_ERROR_LOG_CAPACITY);
+        assert!(errors.iter().all(|e| e.peer_address == peer_addr));
+    }
+}
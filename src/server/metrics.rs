@@ -0,0 +1,150 @@
+use std::fmt::Write;
+
+use crate::server::stats::ServerStatsSnapshot;
+
+// Prometheus text exposition format, version 0.0.4
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+pub fn render(snapshot: &ServerStatsSnapshot) -> String {
+    let counters = [
+        ("bytes_relayed_total", "Application data relayed in either direction", snapshot.bytes_relayed),
+        ("auth_failures_total", "Requests with credentials that failed to authenticate", snapshot.auth_failures),
+        ("stale_nonces_total", "Requests rejected with 438 Stale Nonce", snapshot.stale_nonces),
+        ("permissions_denied_total", "Send indications dropped for lack of a permission", snapshot.permissions_denied),
+        ("oversized_send_indications_total", "Send indications dropped for carrying too much data", snapshot.oversized_send_indications),
+        ("expired_allocation_drops_total", "Peer data dropped because its allocation had expired", snapshot.expired_allocation_drops),
+        ("peer_permission_drops_total", "Peer data dropped for lack of a permission", snapshot.peer_permission_drops),
+        ("unsupported_families_total", "Address attributes with a family other than IPv4 or IPv6", snapshot.unsupported_families),
+        ("rate_limited_allocates_total", "Allocate requests over a source IP's rate limit", snapshot.rate_limited_allocates),
+    ];
+    let gauges = [
+        ("allocations_active", "Allocations currently held", snapshot.allocations_active),
+        ("permissions_active", "Permissions currently installed", snapshot.permissions_active),
+        ("channels_active", "Channels currently bound", snapshot.channels_active),
+    ];
+
+    let mut text = String::new();
+    for (name, help, value) in counters {
+        write_metric(&mut text, name, help, "counter", value as u128);
+    }
+    for (name, help, value) in gauges {
+        write_metric(&mut text, name, help, "gauge", value as u128);
+    }
+    text
+}
+
+fn write_metric(text: &mut String, name: &str, help: &str, kind: &str, value: u128) {
+    let _ = writeln!(text, "# HELP toy_turn_{name} {help}");
+    let _ = writeln!(text, "# TYPE toy_turn_{name} {kind}");
+    let _ = writeln!(text, "toy_turn_{name} {value}");
+}
+
+// Minimal HTTP/1.1 server answering GET /metrics; one response per
+// connection, so scrapers don't need keep-alive
+#[cfg(feature = "metrics")]
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    stats: std::sync::Arc<crate::server::stats::ServerStats>,
+    allocation_manager: std::sync::Arc<crate::turn::allocation::AllocationManager>,
+) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tracing::{debug, warn};
+
+    // Request line and headers past this are not read
+    const MAX_REQUEST_HEAD: usize = 8192;
+
+    loop {
+        let (mut stream, scraper) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Error accepting metrics connection: {}", e);
+                continue;
+            }
+        };
+        let snapshot = stats.snapshot(&allocation_manager);
+
+        tokio::spawn(async move {
+            let mut head = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !head.windows(4).any(|window| window == b"\r\n\r\n") && head.len() < MAX_REQUEST_HEAD {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => head.extend_from_slice(&buf[..n]),
+                }
+            }
+
+            let response = if head.starts_with(b"GET /metrics ") {
+                let body = render(&snapshot);
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    CONTENT_TYPE,
+                    body.len(),
+                    body,
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+            };
+
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                debug!("Error answering metrics scrape from {}: {}", scraper, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::stats::ServerStats;
+    use crate::turn::allocation::AllocationManager;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_render_metrics() {
+        let stats = ServerStats::default();
+        stats.bytes_relayed.fetch_add(1500, Ordering::Relaxed);
+        stats.auth_failures.fetch_add(2, Ordering::Relaxed);
+        stats.stale_nonces.fetch_add(1, Ordering::Relaxed);
+        let allocation_manager = AllocationManager::new(vec!["127.0.0.1:0".parse().unwrap()]);
+
+        let text = render(&stats.snapshot(&allocation_manager));
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE toy_turn_bytes_relayed_total counter"));
+        assert!(lines.contains(&"toy_turn_bytes_relayed_total 1500"));
+        assert!(lines.contains(&"toy_turn_auth_failures_total 2"));
+        assert!(lines.contains(&"toy_turn_stale_nonces_total 1"));
+        assert!(lines.contains(&"# TYPE toy_turn_allocations_active gauge"));
+        assert!(lines.contains(&"toy_turn_allocations_active 0"));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        use std::sync::Arc;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::{TcpListener, TcpStream};
+
+        let stats = Arc::new(ServerStats::default());
+        stats.auth_failures.fetch_add(3, Ordering::Relaxed);
+        let allocation_manager = Arc::new(AllocationManager::new(vec!["127.0.0.1:0".parse().unwrap()]));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, stats, allocation_manager));
+
+        let scrape = |request: &'static str| async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let response = scrape("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(CONTENT_TYPE));
+        assert!(response.lines().any(|line| line == "toy_turn_auth_failures_total 3"));
+
+        let response = scrape("GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
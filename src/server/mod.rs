@@ -1,2 +1,11 @@
 pub mod turn_server;
-pub mod message_handler;
\ No newline at end of file
+pub mod buffer_pool;
+pub mod message_handler;
+pub mod metrics;
+pub mod rate_limit;
+pub mod relay;
+pub mod socket_options;
+pub mod stats;
+pub mod transport;
+#[cfg(all(unix, feature = "systemd"))]
+pub mod systemd;
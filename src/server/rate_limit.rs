@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Allocate requests each source IP may make: `per_second` on average, with
+// bursts of up to `burst`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocateRateLimit {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+// Token bucket per source IP. A bucket that has refilled is indistinguishable
+// from a new one, so cleanup_idle() can drop it.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: AllocateRateLimit,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: AllocateRateLimit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Takes a token for ip, or returns false if its bucket is empty
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.limit.burst as f64,
+            updated_at: now,
+        });
+
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated_at = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated_at).as_secs_f64();
+        (bucket.tokens + elapsed * self.limit.per_second as f64).min(self.limit.burst as f64)
+    }
+
+    pub fn cleanup_idle(&self) {
+        self.cleanup_idle_at(Instant::now());
+    }
+
+    fn cleanup_idle_at(&self, now: Instant) {
+        let burst = self.limit.burst as f64;
+        self.buckets.lock().unwrap().retain(|_, bucket| self.refilled(bucket, now) < burst);
+    }
+
+    pub fn tracked(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_burst_then_refill() {
+        let limiter = RateLimiter::new(AllocateRateLimit { per_second: 2, burst: 3 });
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.allow_at(ip, start));
+        }
+        assert!(!limiter.allow_at(ip, start));
+
+        // Other sources have their own buckets
+        assert!(limiter.allow_at("192.0.2.2".parse().unwrap(), start));
+
+        // One token back every half second
+        assert!(limiter.allow_at(ip, start + Duration::from_millis(500)));
+        assert!(!limiter.allow_at(ip, start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_idle_buckets_cleaned_up() {
+        let limiter = RateLimiter::new(AllocateRateLimit { per_second: 1, burst: 2 });
+        let start = Instant::now();
+        assert!(limiter.allow_at("192.0.2.1".parse().unwrap(), start));
+        assert!(limiter.allow_at("192.0.2.2".parse().unwrap(), start + Duration::from_secs(1)));
+
+        // The first bucket has refilled, the second hasn't yet
+        limiter.cleanup_idle_at(start + Duration::from_millis(1500));
+        assert_eq!(limiter.tracked(), 1);
+
+        limiter.cleanup_idle_at(start + Duration::from_secs(2));
+        assert_eq!(limiter.tracked(), 0);
+    }
+}
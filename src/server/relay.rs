@@ -0,0 +1,528 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::runtime::{Handle, Runtime};
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, info_span, warn, Instrument};
+use crate::server::socket_options::recv_from_with_tos;
+use crate::server::stats::ServerStats;
+use crate::server::transport::ClientConnection;
+use crate::turn::{
+    allocation::{Allocation, AllocationManager, FiveTuple, RelayShutdown, RelayStopReason},
+    channel::ChannelData,
+    data::DataIndication,
+};
+
+// Upper six bits of the TOS byte or traffic class
+const DSCP_MASK: u8 = 0xFC;
+
+// Worker threads of their own for relay tasks, so a flood of peer data
+// can't starve request handling of CPU. The relay sockets stay registered
+// with the runtime that bound them, so readiness still comes through its
+// I/O driver; only receiving, framing and sending move over. Each relayed
+// datagram also costs a cross-thread wakeup, and the threads sit idle when
+// relaying is light.
+pub struct RelayRuntime {
+    // Only None while being dropped
+    runtime: Option<Runtime>,
+}
+
+impl RelayRuntime {
+    pub fn new(worker_threads: usize) -> std::io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(worker_threads.max(1))
+            .thread_name("toy-turn-relay")
+            .enable_all()
+            .build()?;
+        Ok(RelayRuntime { runtime: Some(runtime) })
+    }
+
+    pub fn handle(&self) -> &Handle {
+        self.runtime.as_ref().unwrap().handle()
+    }
+}
+
+impl Drop for RelayRuntime {
+    // Dropped from async code, where waiting for the tasks would panic
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+// Forwards datagrams arriving on an allocation's relayed address to its
+// client over the connection it allocated on, until the allocation is
+// removed or expires. Each SO_REUSEPORT reader socket gets a task of its
+// own; the returned one reads relay_socket. Tasks run on runtime when
+// given, else on the current one.
+pub fn spawn_peer_relay(
+    allocation: &Allocation,
+    connection: ClientConnection,
+    allocation_manager: Arc<AllocationManager>,
+    stats: Arc<ServerStats>,
+    relay_tasks: Option<OwnedSemaphorePermit>,
+    runtime: Option<&Handle>,
+) -> JoinHandle<()> {
+    let runtime = runtime.cloned().unwrap_or_else(Handle::current);
+    let relay_socket = allocation.relay_socket.clone();
+    let client_address = allocation.client_address;
+    let allocation_id = allocation.id;
+    let relayed_address = allocation.relayed_address;
+
+    let relay = Arc::new(PeerRelay {
+        relay_shutdown: allocation.relay_shutdown.clone(),
+        client_address,
+        allocation_id,
+        five_tuple: allocation.five_tuple(),
+        recv_timeout: allocation_manager.relay_recv_timeout(),
+        preserve_dscp: allocation_manager.preserve_dscp(),
+        connection,
+        allocation_manager,
+        stats,
+        _relay_tasks: relay_tasks,
+    });
+
+    // Tags what the relay tasks log with the allocation they serve
+    let span = info_span!("relay", relayed = %relayed_address, client = %client_address);
+
+    for reader in allocation.relay_readers.clone() {
+        let relay = relay.clone();
+        runtime.spawn(async move {
+            relay.run(&reader).await;
+        }.instrument(span.clone()));
+    }
+
+    runtime.spawn(async move {
+        info!("Relay started for allocation {} on {} for client {}", allocation_id, relayed_address, client_address);
+
+        let reason = relay.run(&relay_socket).await;
+
+        info!(
+            "Relay stopped for allocation {} on {} for client {}: {}",
+            allocation_id, relayed_address, client_address, reason
+        );
+    }.instrument(span))
+}
+
+struct PeerRelay {
+    relay_shutdown: Arc<RelayShutdown>,
+    client_address: SocketAddr,
+    // A later allocation on the same 5-tuple is not this relay's
+    allocation_id: u64,
+    five_tuple: FiveTuple,
+    recv_timeout: Duration,
+    preserve_dscp: bool,
+    connection: ClientConnection,
+    allocation_manager: Arc<AllocationManager>,
+    stats: Arc<ServerStats>,
+    // Returned to the relay task budget once the last task exits
+    _relay_tasks: Option<OwnedSemaphorePermit>,
+}
+
+impl PeerRelay {
+    async fn run(&self, relay_socket: &UdpSocket) -> RelayStopReason {
+        let client_address = self.client_address;
+        let mut buf = vec![0u8; 65535];
+
+        loop {
+            let (len, peer_address, tos) = tokio::select! {
+                reason = self.relay_shutdown.stopped() => return reason,
+                result = tokio::time::timeout(self.recv_timeout, self.receive(relay_socket, &mut buf)) => match result {
+                    Ok(Ok(received)) => received,
+                    Ok(Err(e)) => {
+                        warn!("Error receiving on relay socket for {}: {}", client_address, e);
+                        return RelayStopReason::Error;
+                    }
+                    // Idle; exit if the allocation went away or expired
+                    // without the shutdown reaching us
+                    Err(_) => match self.allocation_manager.get_allocation(&self.five_tuple) {
+                        Some(allocation) if allocation.id != self.allocation_id => return RelayStopReason::Deleted,
+                        Some(allocation) if allocation.is_expired() => return RelayStopReason::Expired,
+                        Some(_) => continue,
+                        None => return RelayStopReason::Deleted,
+                    },
+                },
+            };
+
+            // Looked up per packet so permissions granted since are honoured
+            let Some(allocation) = self.allocation_manager.get_allocation(&self.five_tuple)
+                .filter(|allocation| allocation.id == self.allocation_id)
+            else {
+                return RelayStopReason::Deleted;
+            };
+            if allocation.is_expired() {
+                self.stats.expired_allocation_drops.fetch_add(1, Ordering::Relaxed);
+                debug!("Dropping data from {} for expired allocation of {}", peer_address, client_address);
+                continue;
+            }
+
+            match frame_for_client(&allocation, peer_address, &buf[..len]) {
+                Some(frame) => {
+                    // DSCP only; the ECN bits describe the peer's path, not the client's
+                    let tos = tos.map(|tos| tos & DSCP_MASK);
+                    match self.connection.send_to_with_tos(&frame, client_address, tos).await {
+                        Ok(()) => {
+                            self.stats.bytes_relayed.fetch_add(len as u64, Ordering::Relaxed);
+                        }
+                        Err(e) => {
+                            warn!("Error relaying peer data to {}: {}", client_address, e);
+                        }
+                    }
+                }
+                None => {
+                    self.stats.peer_permission_drops.fetch_add(1, Ordering::Relaxed);
+                    debug!("Dropping data from {} without permission on allocation for {}", peer_address, client_address);
+                }
+            }
+        }
+    }
+
+    async fn receive(&self, relay_socket: &UdpSocket, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr, Option<u8>)> {
+        if self.preserve_dscp {
+            recv_from_with_tos(relay_socket, buf).await
+        } else {
+            relay_socket.recv_from(buf).await.map(|(len, from)| (len, from, None))
+        }
+    }
+}
+
+// Peers with a bound channel get ChannelData framing, other permitted peers
+// a Data indication (RFC 5766 section 10.3 and 11.6). A peer whose binding
+// has lapsed while its permission hasn't falls in the second group.
+fn frame_for_client(allocation: &Allocation, peer_address: SocketAddr, data: &[u8]) -> Option<Vec<u8>> {
+    if !allocation.has_permission(&peer_address) {
+        return None;
+    }
+
+    match allocation.get_channel_by_peer(&peer_address) {
+        Some(channel_number) => ChannelData::new(channel_number, data.to_vec())
+            .ok()
+            .map(|channel_data| channel_data.serialize()),
+        None => Some(DataIndication::new(peer_address, data.to_vec()).to_message().serialize().to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stun::message::Message;
+
+    struct Relay {
+        allocation_manager: Arc<AllocationManager>,
+        allocation: Allocation,
+        client: UdpSocket,
+        peer: UdpSocket,
+        stats: Arc<ServerStats>,
+        task: JoinHandle<()>,
+    }
+
+    impl Relay {
+        async fn new() -> Self {
+            Self::with_manager(AllocationManager::new(vec!["127.0.0.1:0".parse().unwrap()])).await
+        }
+
+        async fn with_manager(allocation_manager: AllocationManager) -> Self {
+            Self::start(allocation_manager, None).await
+        }
+
+        async fn start(allocation_manager: AllocationManager, runtime: Option<&Handle>) -> Self {
+            let allocation_manager = Arc::new(allocation_manager);
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let stats = Arc::new(ServerStats::default());
+
+            let allocation = allocation_manager.create_allocation(
+                "testuser".to_string(),
+                "example.com".to_string(),
+                FiveTuple::udp(client.local_addr().unwrap()),
+                Duration::from_secs(600),
+            ).await.unwrap();
+            let task = spawn_peer_relay(&allocation, ClientConnection::Udp(socket), allocation_manager.clone(), stats.clone(), None, runtime);
+
+            Relay {
+                allocation_manager,
+                allocation,
+                client,
+                peer,
+                stats,
+                task,
+            }
+        }
+
+        async fn send_from_peer(&self, data: &[u8]) {
+            let relay_address = self.allocation.relay_socket.local_addr().unwrap();
+            self.peer.send_to(data, relay_address).await.unwrap();
+        }
+
+        async fn receive(&self) -> Option<Vec<u8>> {
+            let mut buf = vec![0u8; 1500];
+            let len = tokio::time::timeout(Duration::from_millis(200), self.client.recv(&mut buf))
+                .await
+                .ok()?
+                .unwrap();
+            Some(buf[..len].to_vec())
+        }
+
+        // Expired but not yet swept, so no shutdown is signalled
+        fn expire(&self) {
+            self.allocation_manager.with_allocation_mut(&self.allocation.five_tuple(), |allocation| {
+                allocation.lifetime = Duration::ZERO;
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peer_data_relayed_as_data_indication() {
+        let relay = Relay::new().await;
+        let peer_address = relay.peer.local_addr().unwrap();
+        relay.allocation_manager.add_permission(&relay.allocation.five_tuple(), peer_address);
+
+        relay.send_from_peer(b"hello client").await;
+
+        let frame = relay.receive().await.unwrap();
+        let indication = DataIndication::from_message(&Message::parse(&frame).unwrap()).unwrap();
+        assert_eq!(indication.peer_address, peer_address);
+        assert_eq!(indication.data, b"hello client");
+    }
+
+    #[tokio::test]
+    async fn test_peer_data_relayed_on_dedicated_runtime() {
+        let runtime = RelayRuntime::new(1).unwrap();
+        let relay = Relay::start(
+            AllocationManager::new(vec!["127.0.0.1:0".parse().unwrap()]),
+            Some(runtime.handle()),
+        ).await;
+        assert_eq!(runtime.handle().metrics().num_alive_tasks(), 1);
+        let peer_address = relay.peer.local_addr().unwrap();
+        relay.allocation_manager.add_permission(&relay.allocation.five_tuple(), peer_address);
+
+        relay.send_from_peer(b"hello client").await;
+
+        let frame = relay.receive().await.unwrap();
+        let indication = DataIndication::from_message(&Message::parse(&frame).unwrap()).unwrap();
+        assert_eq!(indication.peer_address, peer_address);
+        assert_eq!(indication.data, b"hello client");
+    }
+
+    #[tokio::test]
+    async fn test_peer_data_without_permission_dropped() {
+        let relay = Relay::new().await;
+
+        relay.send_from_peer(b"unsolicited").await;
+
+        assert!(relay.receive().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_channel_bound_peer_gets_channel_data() {
+        let peer_address: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let relay_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let mut allocation = Allocation::new(
+            "testuser".to_string(),
+            relay_socket.local_addr().unwrap(),
+            "127.0.0.1:40000".parse().unwrap(),
+            relay_socket,
+        );
+        allocation.add_channel_binding(0x4001, peer_address).unwrap();
+
+        let frame = frame_for_client(&allocation, peer_address, b"media").unwrap();
+        let channel_data = ChannelData::parse(&frame).unwrap();
+        assert_eq!(channel_data.channel_number, 0x4001);
+        assert_eq!(channel_data.data, b"media");
+
+        // Once unbound the peer falls back to Data indications
+        allocation.remove_channel_binding(0x4001);
+        let frame = frame_for_client(&allocation, peer_address, b"media").unwrap();
+        let indication = DataIndication::from_message(&Message::parse(&frame).unwrap()).unwrap();
+        assert_eq!(indication.peer_address, peer_address);
+    }
+
+    #[tokio::test]
+    async fn test_expired_channel_falls_back_to_data_indication() {
+        let relay = Relay::new().await;
+        let peer_address = relay.peer.local_addr().unwrap();
+        let five_tuple = relay.allocation.five_tuple();
+        relay.allocation_manager.add_permission(&five_tuple, peer_address);
+        relay.allocation_manager.add_channel_binding(&five_tuple, 0x4001, peer_address).unwrap();
+
+        relay.send_from_peer(b"bound").await;
+        let frame = relay.receive().await.unwrap();
+        assert_eq!(ChannelData::parse(&frame).unwrap().channel_number, 0x4001);
+
+        // The binding lapses but the permission is still live
+        let expired = std::time::Instant::now().checked_sub(Duration::from_secs(900)).unwrap();
+        relay.allocation_manager.with_allocation_mut(&five_tuple, |allocation| {
+            allocation.channel_bindings.get_mut(&0x4001).unwrap().1 = expired;
+        });
+
+        relay.send_from_peer(b"unbound").await;
+        let frame = relay.receive().await.unwrap();
+        let indication = DataIndication::from_message(&Message::parse(&frame).unwrap()).unwrap();
+        assert_eq!(indication.peer_address, peer_address);
+        assert_eq!(indication.data, b"unbound");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_reuseport_readers_relay_peer_data() {
+        let relay = Relay::with_manager(
+            AllocationManager::new(vec!["127.0.0.1:0".parse().unwrap()]).with_relay_reader_sockets(2),
+        ).await;
+        let five_tuple = relay.allocation.five_tuple();
+        let relay_address = relay.allocation.relay_socket.local_addr().unwrap();
+        assert_eq!(relay.allocation.relay_readers.len(), 1);
+        assert_eq!(relay.allocation.relay_readers[0].local_addr().unwrap(), relay_address);
+
+        // Distinct source ports, so the kernel spreads them over both readers
+        let mut peers = Vec::new();
+        for _ in 0..8 {
+            let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            relay.allocation_manager.add_permission(&five_tuple, peer.local_addr().unwrap());
+            peer.send_to(b"inbound", relay_address).await.unwrap();
+            peers.push(peer.local_addr().unwrap());
+        }
+
+        let mut senders = Vec::new();
+        for _ in 0..peers.len() {
+            let frame = relay.receive().await.unwrap();
+            let indication = DataIndication::from_message(&Message::parse(&frame).unwrap()).unwrap();
+            assert_eq!(indication.data, b"inbound");
+            senders.push(indication.peer_address);
+        }
+        senders.sort();
+        peers.sort();
+        assert_eq!(senders, peers);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_dscp_copied_to_client() {
+        use crate::server::socket_options::{send_to_with_tos, set_receive_tos};
+
+        for preserve_dscp in [true, false] {
+            let relay = Relay::with_manager(
+                AllocationManager::new(vec!["127.0.0.1:0".parse().unwrap()]).with_preserve_dscp(preserve_dscp),
+            ).await;
+            relay.allocation_manager.add_permission(&relay.allocation.five_tuple(), relay.peer.local_addr().unwrap());
+            set_receive_tos(&relay.client).unwrap();
+
+            // EF with ECT(1); only the DSCP part is carried over
+            let relay_address = relay.allocation.relay_socket.local_addr().unwrap();
+            send_to_with_tos(&relay.peer, b"voice", relay_address, 0xB9).await.unwrap();
+
+            let mut buf = vec![0u8; 1500];
+            let (_, _, tos) = tokio::time::timeout(Duration::from_millis(200), recv_from_with_tos(&relay.client, &mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(tos, Some(if preserve_dscp { 0xB8 } else { 0 }));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_task_exits_on_removal() {
+        let relay = Relay::new().await;
+
+        relay.allocation_manager.remove_allocation(&relay.allocation.five_tuple());
+
+        tokio::time::timeout(Duration::from_secs(1), relay.task).await.unwrap().unwrap();
+    }
+
+    // Log output collected by a test subscriber
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLogs {
+        // Captures what this thread logs until the guard drops. The test
+        // runtime is single threaded, so relay tasks log here too.
+        fn start() -> (Self, tracing::subscriber::DefaultGuard) {
+            let logs = CapturedLogs::default();
+            let writer = logs.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .with_writer(move || writer.clone())
+                .with_ansi(false)
+                .finish();
+            (logs, tracing::subscriber::set_default(subscriber))
+        }
+
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_start_and_stop_logged() {
+        let (logs, _guard) = CapturedLogs::start();
+
+        let relay = Relay::new().await;
+        let allocation = &relay.allocation;
+        let client_address = relay.client.local_addr().unwrap();
+
+        relay.allocation_manager.remove_allocation(&allocation.five_tuple());
+        tokio::time::timeout(Duration::from_secs(1), relay.task).await.unwrap().unwrap();
+
+        let logs = logs.text();
+        let started = format!(
+            "Relay started for allocation {} on {} for client {}",
+            allocation.id, allocation.relayed_address, client_address
+        );
+        let stopped = format!(
+            "Relay stopped for allocation {} on {} for client {}: delete",
+            allocation.id, allocation.relayed_address, client_address
+        );
+        assert!(logs.contains(&started), "{}", logs);
+        assert!(logs.contains(&stopped), "{}", logs);
+    }
+
+    #[tokio::test]
+    async fn test_peer_data_drop_reasons_counted() {
+        let relay = Relay::new().await;
+        let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_address = relay.allocation.relay_socket.local_addr().unwrap();
+        relay.allocation_manager.add_permission(&relay.allocation.five_tuple(), relay.peer.local_addr().unwrap());
+
+        stranger.send_to(b"who dis", relay_address).await.unwrap();
+        assert!(relay.receive().await.is_none());
+        assert_eq!(relay.stats.peer_permission_drops.load(Ordering::Relaxed), 1);
+        assert_eq!(relay.stats.expired_allocation_drops.load(Ordering::Relaxed), 0);
+
+        relay.expire();
+        relay.send_from_peer(b"too late").await;
+        assert!(relay.receive().await.is_none());
+        assert_eq!(relay.stats.peer_permission_drops.load(Ordering::Relaxed), 1);
+        assert_eq!(relay.stats.expired_allocation_drops.load(Ordering::Relaxed), 1);
+        assert_eq!(relay.stats.bytes_relayed.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_relay_task_exits_after_expiry_within_recv_timeout() {
+        let (logs, _guard) = CapturedLogs::start();
+        let relay = Relay::with_manager(
+            AllocationManager::new(vec!["127.0.0.1:0".parse().unwrap()])
+                .with_relay_recv_timeout(Duration::from_millis(50)),
+        ).await;
+
+        relay.expire();
+
+        tokio::time::timeout(Duration::from_millis(500), relay.task).await.unwrap().unwrap();
+        let logs = logs.text();
+        let stopped = format!("Relay stopped for allocation {} on {}", relay.allocation.id, relay.allocation.relayed_address);
+        assert!(logs.lines().any(|line| line.contains(&stopped) && line.ends_with(": expiry")), "{}", logs);
+    }
+}
@@ -0,0 +1,407 @@
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+// Whether set_dont_fragment can work on this platform at all
+pub const DONT_FRAGMENT_SUPPORTED: bool = cfg!(target_os = "linux");
+
+// Whether bind_reuseport can work on this platform at all
+pub const REUSEPORT_SUPPORTED: bool = cfg!(target_os = "linux");
+
+// Whether the TOS byte can be read off received datagrams and set per
+// datagram sent, for copying DSCP markings through the relay
+pub const DSCP_SUPPORTED: bool = cfg!(target_os = "linux");
+
+// Sets or clears the DF bit on outgoing datagrams. On Linux this is path MTU
+// discovery mode: DO sets DF, DONT lets the kernel fragment.
+#[cfg(target_os = "linux")]
+pub fn set_dont_fragment(socket: &UdpSocket, enabled: bool) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let (level, name, value) = if socket.local_addr()?.is_ipv4() {
+        let value = if enabled { libc::IP_PMTUDISC_DO } else { libc::IP_PMTUDISC_DONT };
+        (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, value)
+    } else {
+        let value = if enabled { libc::IPV6_PMTUDISC_DO } else { libc::IPV6_PMTUDISC_DONT };
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, value)
+    };
+
+    // SAFETY: the descriptor is owned by socket and value outlives the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_dont_fragment(_socket: &UdpSocket, _enabled: bool) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+pub fn dont_fragment(socket: &UdpSocket) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    let (level, name, enabled) = if socket.local_addr()?.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_DO)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_DO)
+    };
+
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: value and len are valid for writes of the sizes given
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result == 0 {
+        Ok(value == enabled)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn dont_fragment(_socket: &UdpSocket) -> io::Result<bool> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// Has recv_from_with_tos report the TOS byte (IPv4) or traffic class
+// (IPv6) each datagram arrived with
+#[cfg(target_os = "linux")]
+pub fn set_receive_tos(socket: &UdpSocket) -> io::Result<()> {
+    let (level, name) = if socket.local_addr()?.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_RECVTOS)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS)
+    };
+    set_int_option(socket, level, name, 1)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_receive_tos(_socket: &UdpSocket) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// Like recv_from, plus the TOS byte if set_receive_tos was called first
+#[cfg(target_os = "linux")]
+pub async fn recv_from_with_tos(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<u8>)> {
+    use std::os::fd::AsRawFd;
+
+    socket.async_io(tokio::io::Interest::READABLE, || {
+        // SAFETY: all-zero is a valid sockaddr_storage
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // Room for the one control message asked for; u64s for alignment
+        let mut control = [0u64; 8];
+        // SAFETY: all-zero is a valid msghdr
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_name = &mut storage as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+        // SAFETY: msg points at storage, iov and control, all of which
+        // outlive the call, with their sizes
+        let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut tos = None;
+        // SAFETY: the kernel filled in msg_control, and the CMSG_* macros
+        // stay within msg_controllen
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                let data = libc::CMSG_DATA(cmsg);
+                match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                    (libc::IPPROTO_IP, libc::IP_TOS) => tos = Some(*data),
+                    (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                        tos = Some((data as *const libc::c_int).read_unaligned() as u8);
+                    }
+                    _ => {}
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+
+        Ok((len as usize, socket_addr(&storage)?, tos))
+    }).await
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn recv_from_with_tos(_socket: &UdpSocket, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<u8>)> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// Like send_to, with the TOS byte (IPv4) or traffic class (IPv6) set on
+// this datagram only, leaving the socket's own setting alone
+#[cfg(target_os = "linux")]
+pub async fn send_to_with_tos(socket: &UdpSocket, data: &[u8], dst_addr: SocketAddr, tos: u8) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    let (level, name) = if socket.local_addr()?.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_TOS)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+    };
+
+    socket.async_io(tokio::io::Interest::WRITABLE, || {
+        let (mut storage, storage_len) = sockaddr(dst_addr);
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut control = [0u64; 4];
+        // SAFETY: all-zero is a valid msghdr
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_name = &mut storage as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = storage_len;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+
+        // SAFETY: control has room for one c_int control message, and
+        // msg points at buffers that outlive the sendmsg call
+        let sent = unsafe {
+            msg.msg_controllen = libc::CMSG_SPACE(std::mem::size_of::<libc::c_int>() as u32) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = level;
+            (*cmsg).cmsg_type = name;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<libc::c_int>() as u32) as _;
+            (libc::CMSG_DATA(cmsg) as *mut libc::c_int).write_unaligned(tos as libc::c_int);
+
+            libc::sendmsg(socket.as_raw_fd(), &msg, 0)
+        };
+        if sent < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(sent as usize)
+        }
+    }).await
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn send_to_with_tos(_socket: &UdpSocket, _data: &[u8], _dst_addr: SocketAddr, _tos: u8) -> io::Result<usize> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+fn set_int_option(socket: &UdpSocket, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the descriptor is owned by socket and value outlives the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// Binds a UDP socket with SO_REUSEPORT set, so further sockets from this
+// process can bind the same address. The kernel spreads inbound datagrams
+// across them by source address.
+#[cfg(target_os = "linux")]
+pub fn bind_reuseport(address: SocketAddr) -> io::Result<UdpSocket> {
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    let domain = if address.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+    // SAFETY: no pointers are passed; the descriptor is checked below
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd was just created and nothing else owns it; it is closed
+    // when socket drops on any error below
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+
+    let value: libc::c_int = 1;
+    // SAFETY: the descriptor is owned by socket and value outlives the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_REUSEPORT,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let (storage, len) = sockaddr(address);
+    // SAFETY: storage holds a sockaddr of len bytes for the socket's family
+    let result = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &storage as *const libc::sockaddr_storage as *const libc::sockaddr,
+            len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    UdpSocket::from_std(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_reuseport(_address: SocketAddr) -> io::Result<UdpSocket> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+fn sockaddr(address: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: all-zero is a valid sockaddr_storage
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+
+    let len = match address {
+        SocketAddr::V4(address) => {
+            // SAFETY: sockaddr_storage is large and aligned enough for any sockaddr
+            let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = address.port().to_be();
+            sin.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(address.ip().octets()) };
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(address) => {
+            // SAFETY: as above
+            let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = address.port().to_be();
+            sin6.sin6_flowinfo = address.flowinfo();
+            sin6.sin6_addr = libc::in6_addr { s6_addr: address.ip().octets() };
+            sin6.sin6_scope_id = address.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
+
+#[cfg(target_os = "linux")]
+fn socket_addr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the family says storage holds a sockaddr_in
+            let sin = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+            let ip = std::net::Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+            Ok(SocketAddr::new(ip.into(), u16::from_be(sin.sin_port)))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the family says storage holds a sockaddr_in6
+            let sin6 = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+            let ip = std::net::Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            Ok(std::net::SocketAddrV6::new(ip, u16::from_be(sin6.sin6_port), sin6.sin6_flowinfo, sin6.sin6_scope_id).into())
+        }
+        _ => Err(io::ErrorKind::InvalidData.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_set_dont_fragment() {
+        for address in ["127.0.0.1:0", "[::1]:0"] {
+            let socket = UdpSocket::bind(address).await.unwrap();
+
+            set_dont_fragment(&socket, true).unwrap();
+            assert!(dont_fragment(&socket).unwrap());
+
+            set_dont_fragment(&socket, false).unwrap();
+            assert!(!dont_fragment(&socket).unwrap());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_tos_sent_and_received_per_datagram() {
+        for address in ["127.0.0.1:0", "[::1]:0"] {
+            let sender = UdpSocket::bind(address).await.unwrap();
+            let receiver = UdpSocket::bind(address).await.unwrap();
+            set_receive_tos(&receiver).unwrap();
+            let receiver_address = receiver.local_addr().unwrap();
+
+            let mut buf = [0u8; 16];
+            send_to_with_tos(&sender, b"marked", receiver_address, 0xB8).await.unwrap();
+            let (len, from, tos) = recv_from_with_tos(&receiver, &mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"marked");
+            assert_eq!(from, sender.local_addr().unwrap());
+            assert_eq!(tos, Some(0xB8));
+
+            // The marking was for that datagram only
+            sender.send_to(b"plain", receiver_address).await.unwrap();
+            let (_, _, tos) = recv_from_with_tos(&receiver, &mut buf).await.unwrap();
+            assert_eq!(tos, Some(0));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_bind_reuseport_shares_address() {
+        for address in ["127.0.0.1:0", "[::1]:0"] {
+            let first = bind_reuseport(address.parse().unwrap()).unwrap();
+            let relay_address = first.local_addr().unwrap();
+            let second = bind_reuseport(relay_address).unwrap();
+            assert_eq!(second.local_addr().unwrap(), relay_address);
+
+            // A plain bind still conflicts
+            assert!(UdpSocket::bind(relay_address).await.is_err());
+
+            // Packets from many source ports all arrive, on one socket or the other
+            let (mut first_buf, mut second_buf) = ([0u8; 16], [0u8; 16]);
+            for _ in 0..8 {
+                let peer = UdpSocket::bind(if relay_address.is_ipv4() { "127.0.0.1:0" } else { "[::1]:0" }).await.unwrap();
+                peer.send_to(b"hello", relay_address).await.unwrap();
+
+                let (data, from) = tokio::select! {
+                    received = first.recv_from(&mut first_buf) => {
+                        let (len, from) = received.unwrap();
+                        (&first_buf[..len], from)
+                    }
+                    received = second.recv_from(&mut second_buf) => {
+                        let (len, from) = received.unwrap();
+                        (&second_buf[..len], from)
+                    }
+                };
+                assert_eq!(data, b"hello");
+                assert_eq!(from, peer.local_addr().unwrap());
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::turn::allocation::AllocationManager;
+
+// Counters bumped by the message handlers and relay tasks
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    pub permissions_denied: AtomicU64,
+    pub bytes_relayed: AtomicU64,
+    pub oversized_send_indications: AtomicU64,
+    pub expired_allocation_drops: AtomicU64,
+    pub peer_permission_drops: AtomicU64,
+    pub unsupported_families: AtomicU64,
+    pub rate_limited_allocates: AtomicU64,
+    pub auth_failures: AtomicU64,
+    pub stale_nonces: AtomicU64,
+}
+
+impl ServerStats {
+    // Counters are read from here, gauges from the current allocations
+    pub fn snapshot(&self, allocation_manager: &AllocationManager) -> ServerStatsSnapshot {
+        ServerStatsSnapshot {
+            permissions_denied: self.permissions_denied.load(Ordering::Relaxed),
+            bytes_relayed: self.bytes_relayed.load(Ordering::Relaxed),
+            oversized_send_indications: self.oversized_send_indications.load(Ordering::Relaxed),
+            expired_allocation_drops: self.expired_allocation_drops.load(Ordering::Relaxed),
+            peer_permission_drops: self.peer_permission_drops.load(Ordering::Relaxed),
+            unsupported_families: self.unsupported_families.load(Ordering::Relaxed),
+            rate_limited_allocates: self.rate_limited_allocates.load(Ordering::Relaxed),
+            auth_failures: self.auth_failures.load(Ordering::Relaxed),
+            stale_nonces: self.stale_nonces.load(Ordering::Relaxed),
+            allocations_active: allocation_manager.allocation_count(),
+            permissions_active: allocation_manager.permission_count(),
+            channels_active: allocation_manager.channel_count(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ServerStatsSnapshot {
+    // Send indications dropped because the peer had no permission
+    pub permissions_denied: u64,
+    // Application data relayed in either direction
+    pub bytes_relayed: u64,
+    pub oversized_send_indications: u64,
+    // Peer data that arrived after its allocation expired but before the
+    // allocation was swept
+    pub expired_allocation_drops: u64,
+    // Peer data dropped because the allocation had no permission for the
+    // peer, as opposed to having expired
+    pub peer_permission_drops: u64,
+    // Peer addresses and REQUESTED-ADDRESS-FAMILY values that were neither
+    // IPv4 nor IPv6
+    pub unsupported_families: u64,
+    // Allocate requests over a source IP's rate limit
+    pub rate_limited_allocates: u64,
+    // Requests carrying credentials that didn't verify; a first request
+    // without any isn't counted
+    pub auth_failures: u64,
+    // Requests answered with 438 because their nonce was unknown or expired
+    pub stale_nonces: u64,
+    pub allocations_active: usize,
+    pub permissions_active: usize,
+    pub channels_active: usize,
+}
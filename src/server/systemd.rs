@@ -0,0 +1,77 @@
+use std::net::SocketAddr;
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tracing::info;
+
+// First inherited descriptor, see sd_listen_fds(3)
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+fn listen_fd(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Option<RawFd> {
+    // LISTEN_PID guards against picking up variables meant for a parent
+    let listen_pid: u32 = listen_pid?.parse().ok()?;
+    if listen_pid != pid {
+        return None;
+    }
+
+    let listen_fds: u32 = listen_fds?.parse().ok()?;
+    if listen_fds == 0 {
+        return None;
+    }
+
+    Some(SD_LISTEN_FDS_START)
+}
+
+pub fn activation_fd() -> Option<RawFd> {
+    listen_fd(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+// Adopts the socket passed in by systemd if the process was socket
+// activated, otherwise binds listen_address as usual
+pub async fn listen_socket(listen_address: SocketAddr) -> std::io::Result<Arc<UdpSocket>> {
+    match activation_fd() {
+        Some(fd) => {
+            // SAFETY: systemd hands the process ownership of the descriptor
+            let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+            socket.set_nonblocking(true)?;
+            info!("Using socket-activated listen socket (fd {})", fd);
+            Ok(Arc::new(UdpSocket::from_std(socket)?))
+        }
+        None => Ok(Arc::new(UdpSocket::bind(listen_address).await?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activation_selected() {
+        assert_eq!(listen_fd(Some("1234"), Some("1"), 1234), Some(3));
+    }
+
+    #[test]
+    fn test_activation_not_selected() {
+        // Not socket activated
+        assert_eq!(listen_fd(None, None, 1234), None);
+
+        // Variables meant for another process
+        assert_eq!(listen_fd(Some("999"), Some("1"), 1234), None);
+
+        // No descriptors passed
+        assert_eq!(listen_fd(Some("1234"), Some("0"), 1234), None);
+
+        // Garbage
+        assert_eq!(listen_fd(Some("abc"), Some("1"), 1234), None);
+    }
+
+    #[tokio::test]
+    async fn test_listen_socket_falls_back_to_bind() {
+        let socket = listen_socket("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        assert!(socket.local_addr().unwrap().ip().is_loopback());
+    }
+}
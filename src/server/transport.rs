@@ -0,0 +1,284 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, info, warn};
+
+use crate::server::message_handler::{handle_message, HandlerContext};
+use crate::server::socket_options::send_to_with_tos;
+use crate::turn::allocation::{FiveTuple, Transport};
+
+// Frames queued for a TCP client before its handler waits on the writer
+const TCP_SEND_QUEUE: usize = 64;
+
+// The way back to a client: the shared UDP socket, or the writer half of
+// the client's own TCP connection
+#[derive(Debug, Clone)]
+pub enum ClientConnection {
+    Udp(Arc<UdpSocket>),
+    Tcp(mpsc::Sender<Vec<u8>>),
+}
+
+impl ClientConnection {
+    pub fn transport(&self) -> Transport {
+        match self {
+            ClientConnection::Udp(_) => Transport::Udp,
+            ClientConnection::Tcp(_) => Transport::Tcp,
+        }
+    }
+
+    // dst_addr only matters for UDP; a TCP connection has a single client
+    pub async fn send_to(&self, data: &[u8], dst_addr: SocketAddr) -> io::Result<()> {
+        match self {
+            ClientConnection::Udp(socket) => socket.send_to(data, dst_addr).await.map(|_| ()),
+            ClientConnection::Tcp(sender) => sender
+                .send(data.to_vec())
+                .await
+                .map_err(|_| io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    // Marks a UDP datagram with tos; a TCP stream has no per-frame marking,
+    // so it is sent as send_to would
+    pub async fn send_to_with_tos(&self, data: &[u8], dst_addr: SocketAddr, tos: Option<u8>) -> io::Result<()> {
+        match (self, tos) {
+            (ClientConnection::Udp(socket), Some(tos)) => send_to_with_tos(socket, data, dst_addr, tos).await.map(|_| ()),
+            _ => self.send_to(data, dst_addr).await,
+        }
+    }
+}
+
+// Length of the STUN message or ChannelData frame at the start of a TCP
+// stream, once enough of its header has arrived. ChannelData over TCP is
+// always padded to a multiple of four (RFC 5766 section 11.5).
+pub fn frame_length(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 4 {
+        return None;
+    }
+
+    let length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    if buf[0] & 0xC0 == 0x40 {
+        Some((4 + length).next_multiple_of(4))
+    } else {
+        Some(20 + length)
+    }
+}
+
+// Reassembles frames from a TCP stream whose reads may end anywhere, even
+// partway through a header
+#[derive(Debug, Default)]
+pub struct TcpFramer {
+    buf: Vec<u8>,
+}
+
+impl TcpFramer {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    // The next complete frame, or None until more of it has been pushed
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let len = frame_length(&self.buf).filter(|len| self.buf.len() >= *len)?;
+        Some(self.buf.drain(..len).collect())
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+// With max_connections set, connections past it are closed as soon as
+// they are accepted; a slot frees up when a connection closes
+pub async fn serve_tcp(listener: Arc<TcpListener>, context: HandlerContext, max_connections: Option<usize>) {
+    let connection_slots = max_connections.map(|max| Arc::new(Semaphore::new(max)));
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, client_address)) => {
+                let permit = match &connection_slots {
+                    Some(slots) => match slots.clone().try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            debug!("Refusing TCP connection from {}: at the connection limit", client_address);
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                tokio::spawn(serve_tcp_connection(stream, client_address, context.clone(), permit));
+            }
+            Err(e) => {
+                warn!("Error accepting TCP connection: {}", e);
+            }
+        }
+    }
+}
+
+async fn serve_tcp_connection(
+    stream: TcpStream,
+    client_address: SocketAddr,
+    mut context: HandlerContext,
+    // Held until the connection closes
+    _permit: Option<OwnedSemaphorePermit>,
+) {
+    debug!("TCP connection from {}", client_address);
+
+    let (mut reader, mut writer) = stream.into_split();
+    let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(TCP_SEND_QUEUE);
+    context.connection = ClientConnection::Tcp(sender);
+
+    tokio::spawn(async move {
+        while let Some(frame) = receiver.recv().await {
+            if writer.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut framer = TcpFramer::default();
+    let mut chunk = vec![0u8; 65535];
+    loop {
+        match reader.read(&mut chunk).await {
+            Ok(0) => break,
+            Ok(len) => framer.push(&chunk[..len]),
+            Err(e) => {
+                debug!("Error reading from TCP client {}: {}", client_address, e);
+                break;
+            }
+        }
+
+        // Handled in order, so responses go out in the order requests came in
+        while let Some(frame) = framer.next_frame() {
+            if let Err(e) = handle_message(frame, client_address, context.clone()).await {
+                debug!("Error handling message from {}: {}", client_address, e);
+            }
+        }
+    }
+
+    // The allocation lives only as long as the connection it was made on
+    if context.allocation_manager.remove_allocation(&FiveTuple::tcp(client_address)).is_some() {
+        info!("Removed allocation for closed TCP connection from {}", client_address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_length() {
+        // Incomplete header
+        assert_eq!(frame_length(&[0x00, 0x03, 0x00]), None);
+
+        // STUN message with 8 bytes of attributes
+        assert_eq!(frame_length(&[0x00, 0x03, 0x00, 0x08]), Some(28));
+
+        // ChannelData with 5 bytes of data, padded to 12
+        assert_eq!(frame_length(&[0x40, 0x00, 0x00, 0x05]), Some(12));
+        assert_eq!(frame_length(&[0x40, 0x00, 0x00, 0x04]), Some(8));
+    }
+
+    #[test]
+    fn test_framer_assembles_byte_at_a_time() {
+        use crate::stun::attributes::{AttributeType, RawAttribute};
+        use crate::stun::message::{Message, MessageClass, MessageMethod, MessageType};
+
+        let mut message = Message::new(MessageType::new(MessageMethod::Binding, MessageClass::Request));
+        message.push_attribute(RawAttribute::new(AttributeType::Software as u16, b"toy-turn".to_vec()));
+        let mut stream = message.serialize().to_vec();
+        // A ChannelData frame straight after, padded from 5 to 8 bytes of data
+        stream.extend_from_slice(&[0x40, 0x01, 0x00, 0x05, 1, 2, 3, 4, 5, 0, 0, 0]);
+
+        let mut framer = TcpFramer::default();
+        let mut frames = Vec::new();
+        for byte in &stream {
+            framer.push(std::slice::from_ref(byte));
+            frames.extend(std::iter::from_fn(|| framer.next_frame()));
+        }
+        assert_eq!(framer.buffered(), 0);
+        assert_eq!(frames.len(), 2);
+
+        let parsed = Message::parse(&frames[0]).unwrap();
+        assert_eq!(parsed.transaction_id, message.transaction_id);
+        assert_eq!(parsed.attributes, message.attributes);
+        assert_eq!(frames[1], stream[stream.len() - 12..]);
+    }
+
+    #[test]
+    fn test_framer_header_split_across_reads() {
+        let mut framer = TcpFramer::default();
+        let header = [0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xA4, 0x42, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        framer.push(&header[..10]);
+        assert_eq!(framer.next_frame(), None);
+        framer.push(&header[10..]);
+        assert_eq!(framer.next_frame(), Some(header.to_vec()));
+        assert_eq!(framer.next_frame(), None);
+    }
+
+    async fn test_context() -> HandlerContext {
+        use crate::server::stats::ServerStats;
+        use crate::turn::allocation::AllocationManager;
+        use crate::turn::auth::{NonceManager, UserDatabase};
+        use std::time::Duration;
+
+        HandlerContext {
+            connection: ClientConnection::Udp(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap())),
+            allocation_manager: Arc::new(AllocationManager::new(vec!["127.0.0.1:0".parse().unwrap()])),
+            nonce_manager: Arc::new(tokio::sync::RwLock::new(NonceManager::new(Duration::from_secs(300)))),
+            user_database: Arc::new(UserDatabase::new()),
+            realm: "example.org".to_string(),
+            max_send_data_bytes: None,
+            advertised_bandwidth_kbps: None,
+            software: None,
+            stats: Arc::new(ServerStats::default()),
+            turn_enabled: true,
+            alternate_server: None,
+            reject_link_local_clients: false,
+            allocate_rate_limiter: None,
+            relay_task_budget: None,
+            relay_runtime: None,
+        }
+    }
+
+    // Whether a Binding request on the connection gets a response; a
+    // refused connection is closed instead
+    async fn binding_answered(stream: &mut TcpStream) -> bool {
+        use crate::stun::message::{Message, MessageClass, MessageMethod, MessageType};
+        use std::time::Duration;
+
+        let request = Message::new(MessageType::new(MessageMethod::Binding, MessageClass::Request));
+        if stream.write_all(&request.serialize()).await.is_err() {
+            return false;
+        }
+        let mut buf = [0u8; 512];
+        matches!(
+            tokio::time::timeout(Duration::from_secs(1), stream.read(&mut buf)).await,
+            Ok(Ok(len)) if len > 0
+        )
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        let listener = Arc::new(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_tcp(listener, test_context().await, Some(2)));
+
+        let mut first = TcpStream::connect(address).await.unwrap();
+        let mut second = TcpStream::connect(address).await.unwrap();
+        assert!(binding_answered(&mut first).await);
+        assert!(binding_answered(&mut second).await);
+
+        let mut refused = TcpStream::connect(address).await.unwrap();
+        assert!(!binding_answered(&mut refused).await);
+
+        // Closing a connection frees its slot once the server notices
+        drop(first);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let mut third = TcpStream::connect(address).await.unwrap();
+        assert!(binding_answered(&mut third).await);
+        assert!(binding_answered(&mut second).await);
+    }
+}
@@ -1,68 +1,427 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::UdpSocket;
-use tokio::sync::RwLock;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{Notify, RwLock, Semaphore};
+use thiserror::Error;
 use tokio::time::interval;
-use tracing::{info, error};
+use tracing::{info, error, warn};
 
+use crate::config::ServerConfigFile;
+use crate::server::buffer_pool::BufferPool;
+use crate::server::message_handler::{handle_datagram, HandlerContext};
+use crate::server::rate_limit::{AllocateRateLimit, RateLimiter};
+use crate::server::relay::RelayRuntime;
+use crate::server::socket_options::{DSCP_SUPPORTED, REUSEPORT_SUPPORTED};
+use crate::server::stats::{ServerStats, ServerStatsSnapshot};
+use crate::server::transport::{serve_tcp, ClientConnection};
 use crate::turn::{
-    allocation::AllocationManager,
+    allocation::{AllocationManager, DEFAULT_RELAY_RECV_TIMEOUT},
     auth::{NonceManager, UserDatabase},
+    reservation::RESERVATION_LIFETIME,
 };
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct TurnServerConfig {
     pub listen_address: SocketAddr,
+    // TURN over TCP (RFC 6062) is served here as well when set
+    pub tcp_listen_address: Option<SocketAddr>,
+    // Concurrent TCP connections; more are closed on accept
+    pub max_tcp_connections: Option<usize>,
     pub realm: String,
     pub relay_address_start: SocketAddr,
     pub relay_address_count: u16,
+    // Optional IPv6 relay range of the same size, for REQUESTED-ADDRESS-FAMILY
+    pub ipv6_relay_address_start: Option<SocketAddr>,
+    pub realm_allocation_quotas: HashMap<String, usize>,
+    pub max_allocations_per_user: Option<usize>,
+    // Relay tasks run on a runtime of their own with this many worker
+    // threads when set; see RelayRuntime for the trade-offs
+    pub relay_runtime_threads: Option<usize>,
+    // Relay tasks across all allocations, each taking one per reader
+    // socket; Allocate past it gets a 508 even with relay addresses free
+    pub max_relay_tasks: Option<usize>,
+    pub max_send_data_bytes: Option<usize>,
+    // Advertised in Allocate success responses through BANDWIDTH, in
+    // kbit/s, so clients can limit themselves. Not enforced.
+    pub advertised_bandwidth_kbps: Option<u32>,
+    // Server-wide cap on new nonces; challenges past it are dropped
+    pub max_nonces_per_second: Option<u32>,
+    // How often idle peer relay tasks check that their allocation is live
+    pub relay_recv_timeout: Duration,
+    // Allocate waits this long for a relay address when the pool is empty,
+    // instead of failing with 508 straight away
+    pub relay_address_wait_timeout: Option<Duration>,
+    // Sockets bound to each relayed address, each read by its own task.
+    // Above one they share the port through SO_REUSEPORT (Linux only).
+    pub relay_reader_sockets: usize,
+    // Peer data is relayed to the client with the DSCP marking it arrived
+    // with (Linux only); TCP clients get no marking
+    pub preserve_dscp: bool,
+    // How long a port reserved by EVEN-PORT is held for its RESERVATION-TOKEN
+    pub reservation_lifetime: Duration,
+    // SOFTWARE attribute for responses; realm_software overrides it per realm
+    pub software: Option<String>,
+    pub realm_software: HashMap<String, String>,
+    // When false only Binding is served, as a plain STUN server
+    pub turn_enabled: bool,
+    // Allocate requests are redirected here with a 300 when set
+    pub alternate_server: Option<SocketAddr>,
+    // Allocate from a link-local, multicast or unspecified client address
+    // is rejected with 400 when set
+    pub reject_link_local_clients: bool,
+    // Allocate requests past this rate from one source IP get a 486
+    pub allocate_rate_limit: Option<AllocateRateLimit>,
+}
+
+impl TurnServerConfig {
+    pub fn builder() -> TurnServerConfigBuilder {
+        TurnServerConfigBuilder::default()
+    }
+
+    pub fn software_for(&self, realm: &str) -> Option<&str> {
+        self.realm_software
+            .get(realm)
+            .or(self.software.as_ref())
+            .map(String::as_str)
+    }
 }
 
 impl Default for TurnServerConfig {
     fn default() -> Self {
         TurnServerConfig {
             listen_address: "0.0.0.0:3478".parse().unwrap(),
+            tcp_listen_address: None,
+            max_tcp_connections: None,
             realm: "turn.example.com".to_string(),
             relay_address_start: "0.0.0.0:49152".parse().unwrap(),
             relay_address_count: 100,
+            ipv6_relay_address_start: None,
+            realm_allocation_quotas: HashMap::new(),
+            max_allocations_per_user: None,
+            relay_runtime_threads: None,
+            max_relay_tasks: None,
+            max_send_data_bytes: None,
+            advertised_bandwidth_kbps: None,
+            max_nonces_per_second: None,
+            relay_recv_timeout: DEFAULT_RELAY_RECV_TIMEOUT,
+            relay_address_wait_timeout: None,
+            relay_reader_sockets: 1,
+            preserve_dscp: false,
+            reservation_lifetime: RESERVATION_LIFETIME,
+            software: None,
+            realm_software: HashMap::new(),
+            turn_enabled: true,
+            alternate_server: None,
+            reject_link_local_clients: false,
+            allocate_rate_limit: None,
         }
     }
 }
 
+// Receive buffers kept for reuse once their datagram is handled
+const RECEIVE_BUFFERS_POOLED: usize = 256;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Relay range of {count} ports from {start} runs past port 65535")]
+    RelayRangeOverflow { start: SocketAddr, count: u16 },
+
+    #[error("Cannot use {0} relay reader sockets: at least one is needed, and more than one needs SO_REUSEPORT")]
+    UnsupportedRelayReaderSockets(usize),
+
+    #[error("Preserving DSCP markings is not supported on this platform")]
+    UnsupportedDscpPreservation,
+
+    #[error("Allocate rate limit of {per_second}/s with burst {burst} would reject every request")]
+    InvalidAllocateRateLimit { per_second: u32, burst: u32 },
+}
+
+// Starts from TurnServerConfig::default(); build() checks the result
+#[derive(Default)]
+pub struct TurnServerConfigBuilder {
+    config: TurnServerConfig,
+}
+
+impl TurnServerConfigBuilder {
+    pub fn listen_address(mut self, listen_address: SocketAddr) -> Self {
+        self.config.listen_address = listen_address;
+        self
+    }
+
+    pub fn tcp_listen_address(mut self, tcp_listen_address: SocketAddr) -> Self {
+        self.config.tcp_listen_address = Some(tcp_listen_address);
+        self
+    }
+
+    pub fn max_tcp_connections(mut self, max_tcp_connections: usize) -> Self {
+        self.config.max_tcp_connections = Some(max_tcp_connections);
+        self
+    }
+
+    pub fn realm(mut self, realm: impl Into<String>) -> Self {
+        self.config.realm = realm.into();
+        self
+    }
+
+    pub fn relay_range(mut self, start: SocketAddr, count: u16) -> Self {
+        self.config.relay_address_start = start;
+        self.config.relay_address_count = count;
+        self
+    }
+
+    pub fn ipv6_relay_address_start(mut self, start: SocketAddr) -> Self {
+        self.config.ipv6_relay_address_start = Some(start);
+        self
+    }
+
+    pub fn realm_allocation_quota(mut self, realm: impl Into<String>, max_allocations: usize) -> Self {
+        self.config.realm_allocation_quotas.insert(realm.into(), max_allocations);
+        self
+    }
+
+    pub fn max_allocations_per_user(mut self, max_allocations: usize) -> Self {
+        self.config.max_allocations_per_user = Some(max_allocations);
+        self
+    }
+
+    pub fn relay_runtime_threads(mut self, relay_runtime_threads: usize) -> Self {
+        self.config.relay_runtime_threads = Some(relay_runtime_threads);
+        self
+    }
+
+    pub fn max_relay_tasks(mut self, max_relay_tasks: usize) -> Self {
+        self.config.max_relay_tasks = Some(max_relay_tasks);
+        self
+    }
+
+    pub fn max_send_data_bytes(mut self, max_send_data_bytes: usize) -> Self {
+        self.config.max_send_data_bytes = Some(max_send_data_bytes);
+        self
+    }
+
+    pub fn advertised_bandwidth_kbps(mut self, advertised_bandwidth_kbps: u32) -> Self {
+        self.config.advertised_bandwidth_kbps = Some(advertised_bandwidth_kbps);
+        self
+    }
+
+    pub fn max_nonces_per_second(mut self, max_nonces_per_second: u32) -> Self {
+        self.config.max_nonces_per_second = Some(max_nonces_per_second);
+        self
+    }
+
+    pub fn relay_recv_timeout(mut self, relay_recv_timeout: Duration) -> Self {
+        self.config.relay_recv_timeout = relay_recv_timeout;
+        self
+    }
+
+    pub fn relay_address_wait_timeout(mut self, relay_address_wait_timeout: Duration) -> Self {
+        self.config.relay_address_wait_timeout = Some(relay_address_wait_timeout);
+        self
+    }
+
+    pub fn relay_reader_sockets(mut self, relay_reader_sockets: usize) -> Self {
+        self.config.relay_reader_sockets = relay_reader_sockets;
+        self
+    }
+
+    pub fn preserve_dscp(mut self, preserve_dscp: bool) -> Self {
+        self.config.preserve_dscp = preserve_dscp;
+        self
+    }
+
+    pub fn reservation_lifetime(mut self, reservation_lifetime: Duration) -> Self {
+        self.config.reservation_lifetime = reservation_lifetime;
+        self
+    }
+
+    pub fn software(mut self, software: impl Into<String>) -> Self {
+        self.config.software = Some(software.into());
+        self
+    }
+
+    pub fn realm_software(mut self, realm: impl Into<String>, software: impl Into<String>) -> Self {
+        self.config.realm_software.insert(realm.into(), software.into());
+        self
+    }
+
+    pub fn turn_enabled(mut self, turn_enabled: bool) -> Self {
+        self.config.turn_enabled = turn_enabled;
+        self
+    }
+
+    pub fn alternate_server(mut self, alternate_server: SocketAddr) -> Self {
+        self.config.alternate_server = Some(alternate_server);
+        self
+    }
+
+    pub fn reject_link_local_clients(mut self, reject_link_local_clients: bool) -> Self {
+        self.config.reject_link_local_clients = reject_link_local_clients;
+        self
+    }
+
+    pub fn allocate_rate_limit(mut self, per_second: u32, burst: u32) -> Self {
+        self.config.allocate_rate_limit = Some(AllocateRateLimit { per_second, burst });
+        self
+    }
+
+    pub fn build(self) -> Result<TurnServerConfig, ConfigError> {
+        let count = self.config.relay_address_count;
+        let starts = std::iter::once(self.config.relay_address_start).chain(self.config.ipv6_relay_address_start);
+        
+        for start in starts {
+            // The last port handed out is start + count - 1
+            if start.port() as u32 + count as u32 > u16::MAX as u32 + 1 {
+                return Err(ConfigError::RelayRangeOverflow { start, count });
+            }
+        }
+        
+        let readers = self.config.relay_reader_sockets;
+        if readers == 0 || (readers > 1 && !REUSEPORT_SUPPORTED) {
+            return Err(ConfigError::UnsupportedRelayReaderSockets(readers));
+        }
+        
+        if self.config.preserve_dscp && !DSCP_SUPPORTED {
+            return Err(ConfigError::UnsupportedDscpPreservation);
+        }
+        
+        if let Some(AllocateRateLimit { per_second, burst }) = self.config.allocate_rate_limit
+            && (per_second == 0 || burst == 0)
+        {
+            return Err(ConfigError::InvalidAllocateRateLimit { per_second, burst });
+        }
+        
+        Ok(self.config)
+    }
+}
+
 pub struct TurnServer {
     config: TurnServerConfig,
     socket: Arc<UdpSocket>,
+    tcp_listener: Option<Arc<TcpListener>>,
     allocation_manager: Arc<AllocationManager>,
     nonce_manager: Arc<RwLock<NonceManager>>,
     user_database: Arc<UserDatabase>,
+    stats: Arc<ServerStats>,
+    allocate_rate_limiter: Option<Arc<RateLimiter>>,
+    // Stops run(); a permit is stored if it isn't running yet
+    shutdown: Notify,
 }
 
 impl TurnServer {
     pub async fn new(config: TurnServerConfig) -> Result<Self, Box<dyn std::error::Error>> {
         let socket = Arc::new(UdpSocket::bind(&config.listen_address).await?);
-        info!("TURN server listening on {}", config.listen_address);
+        Self::from_socket(config, socket).bind_tcp().await
+    }
 
-        // Generate relay addresses
+    pub async fn from_config_file(path: impl AsRef<std::path::Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let config_file = ServerConfigFile::load(path)?;
+        let mut server = Self::new(config_file.server_config()?).await?;
+        server.user_database = Arc::new(config_file.user_database());
+        Ok(server)
+    }
+
+    #[cfg(all(unix, feature = "systemd"))]
+    pub async fn with_socket_activation(config: TurnServerConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let socket = crate::server::systemd::listen_socket(config.listen_address).await?;
+        Self::from_socket(config, socket).bind_tcp().await
+    }
+
+    async fn bind_tcp(mut self) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Some(tcp_listen_address) = self.config.tcp_listen_address {
+            let listener = TcpListener::bind(tcp_listen_address).await?;
+            info!("TURN server listening on TCP {}", listener.local_addr()?);
+            self.tcp_listener = Some(Arc::new(listener));
+        }
+        Ok(self)
+    }
+
+    // Uses a listen socket created elsewhere (custom socket options, an fd
+    // inherited from a service manager, ...). config.listen_address is
+    // not rebound.
+    pub fn from_socket(config: TurnServerConfig, socket: Arc<UdpSocket>) -> Self {
+        match socket.local_addr() {
+            Ok(addr) => info!("TURN server listening on {}", addr),
+            Err(_) => info!("TURN server listening on {}", config.listen_address),
+        }
+        // The config holds no secrets; user credentials live in the
+        // UserDatabase and are never logged
+        info!("Effective configuration: {:?}", config);
+
+        // Generate relay addresses; STUN-only mode has no use for any
         let mut relay_addresses = Vec::new();
-        let base_port = config.relay_address_start.port();
-        for i in 0..config.relay_address_count {
-            let mut addr = config.relay_address_start;
-            addr.set_port(base_port + i);
-            relay_addresses.push(addr);
+        let relay_starts = std::iter::once(config.relay_address_start).chain(config.ipv6_relay_address_start);
+        for start in relay_starts.filter(|_| config.turn_enabled) {
+            // Relay sockets are bound to exactly these addresses, so a
+            // specific IP pins the source of relayed packets. A wildcard
+            // leaves the choice to the kernel on multi-homed hosts.
+            if start.ip().is_unspecified() {
+                warn!("Relay addresses from {} are unspecified; relayed packets use the kernel's choice of source IP", start);
+            }
+            for i in 0..config.relay_address_count {
+                let mut addr = start;
+                addr.set_port(start.port() + i);
+                relay_addresses.push(addr);
+            }
         }
 
-        let allocation_manager = Arc::new(AllocationManager::new(relay_addresses));
-        let nonce_manager = Arc::new(RwLock::new(NonceManager::new(Duration::from_secs(300))));
+        let mut allocation_manager = AllocationManager::new(relay_addresses)
+            .with_relay_recv_timeout(config.relay_recv_timeout)
+            .with_relay_reader_sockets(config.relay_reader_sockets)
+            .with_preserve_dscp(config.preserve_dscp)
+            .with_reservation_lifetime(config.reservation_lifetime);
+        for (realm, max_allocations) in &config.realm_allocation_quotas {
+            allocation_manager = allocation_manager.with_realm_quota(realm.clone(), *max_allocations);
+        }
+        if let Some(max_allocations) = config.max_allocations_per_user {
+            allocation_manager = allocation_manager.with_max_allocations_per_user(max_allocations);
+        }
+        if let Some(wait_timeout) = config.relay_address_wait_timeout {
+            allocation_manager = allocation_manager.with_address_wait_timeout(wait_timeout);
+        }
+        let allocation_manager = Arc::new(allocation_manager);
+        let mut nonce_manager = NonceManager::new(Duration::from_secs(300));
+        if let Some(max_nonces_per_second) = config.max_nonces_per_second {
+            nonce_manager = nonce_manager.with_rate_limit(max_nonces_per_second);
+        }
+        let nonce_manager = Arc::new(RwLock::new(nonce_manager));
         let user_database = Arc::new(UserDatabase::new());
+        let allocate_rate_limiter = config.allocate_rate_limit.map(|limit| Arc::new(RateLimiter::new(limit)));
 
-        Ok(TurnServer {
+        TurnServer {
             config,
             socket,
+            tcp_listener: None,
             allocation_manager,
             nonce_manager,
             user_database,
-        })
+            stats: Arc::new(ServerStats::default()),
+            allocate_rate_limiter,
+            shutdown: Notify::new(),
+        }
+    }
+
+    pub fn stats(&self) -> ServerStatsSnapshot {
+        self.stats.snapshot(&self.allocation_manager)
+    }
+
+    // Serves GET /metrics in Prometheus text format until the task is aborted
+    #[cfg(feature = "metrics")]
+    pub fn spawn_metrics_endpoint(&self, listener: TcpListener) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(crate::server::metrics::serve(listener, self.stats.clone(), self.allocation_manager.clone()))
+    }
+
+    pub fn effective_config(&self) -> &TurnServerConfig {
+        &self.config
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn tcp_local_addr(&self) -> Option<std::io::Result<SocketAddr>> {
+        self.tcp_listener.as_ref().map(|listener| listener.local_addr())
     }
 
     pub fn add_user(&mut self, username: String, password: String) {
@@ -71,45 +430,81 @@ impl TurnServer {
             .add_user(username, password);
     }
 
+    // Stops run() and removes every allocation, closing relay sockets and
+    // returning their addresses to the pool. Clients are not told; their
+    // next Refresh gets a 437.
+    pub fn shutdown(&self) {
+        self.shutdown.notify_one();
+        let removed = self.allocation_manager.remove_all();
+        info!("Shutting down; removed {} allocations", removed);
+    }
+
     pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
         let mut buf = vec![0u8; 65535];
         
+        // Shut down when run() returns, stopping the relay tasks on it
+        let relay_runtime = self.config.relay_runtime_threads.map(RelayRuntime::new).transpose()?;
+        
         // Spawn cleanup task
         let allocation_mgr = self.allocation_manager.clone();
         let nonce_mgr = self.nonce_manager.clone();
-        tokio::spawn(async move {
+        let rate_limiter = self.allocate_rate_limiter.clone();
+        let cleanup = tokio::spawn(async move {
             let mut cleanup_interval = interval(Duration::from_secs(60));
             loop {
                 cleanup_interval.tick().await;
                 allocation_mgr.cleanup_expired();
+                allocation_mgr.cleanup_expired_channels();
+                allocation_mgr.cleanup_expired_permissions();
                 nonce_mgr.write().await.cleanup_expired();
+                if let Some(rate_limiter) = &rate_limiter {
+                    rate_limiter.cleanup_idle();
+                }
             }
         });
 
+        let context = HandlerContext {
+            connection: ClientConnection::Udp(self.socket.clone()),
+            allocation_manager: self.allocation_manager.clone(),
+            nonce_manager: self.nonce_manager.clone(),
+            user_database: self.user_database.clone(),
+            realm: self.config.realm.clone(),
+            max_send_data_bytes: self.config.max_send_data_bytes,
+            advertised_bandwidth_kbps: self.config.advertised_bandwidth_kbps,
+            software: self.config.software_for(&self.config.realm).map(str::to_string),
+            stats: self.stats.clone(),
+            turn_enabled: self.config.turn_enabled,
+            alternate_server: self.config.alternate_server,
+            reject_link_local_clients: self.config.reject_link_local_clients,
+            allocate_rate_limiter: self.allocate_rate_limiter.clone(),
+            relay_task_budget: self.config.max_relay_tasks.map(|max_relay_tasks| Arc::new(Semaphore::new(max_relay_tasks))),
+            relay_runtime: relay_runtime.as_ref().map(|relay_runtime| relay_runtime.handle().clone()),
+        };
+
+        let tcp = self.tcp_listener
+            .as_ref()
+            .map(|listener| tokio::spawn(serve_tcp(listener.clone(), context.clone(), self.config.max_tcp_connections)));
+
+        // Buffers for datagrams still being handled, up to a bound
+        let buffers = BufferPool::new(RECEIVE_BUFFERS_POOLED);
+
         // Main server loop
         loop {
-            match self.socket.recv_from(&mut buf).await {
+            let result = tokio::select! {
+                _ = self.shutdown.notified() => break,
+                result = self.socket.recv_from(&mut buf) => result,
+            };
+            match result {
                 Ok((len, src_addr)) => {
-                    let data = buf[..len].to_vec();
+                    let data = buffers.copy_from(&buf[..len]);
                     
                     // Clone necessary components for the spawned task
-                    let socket = self.socket.clone();
-                    let allocation_manager = self.allocation_manager.clone();
-                    let nonce_manager = self.nonce_manager.clone();
-                    let user_database = self.user_database.clone();
-                    let realm = self.config.realm.clone();
+                    let context = context.clone();
                     
-                    // Handle message in a separate task
+                    // Handle message in a separate task; the buffer goes
+                    // back to the pool when it finishes
                     tokio::spawn(async move {
-                        if let Err(e) = crate::server::message_handler::handle_message(
-                            data,
-                            src_addr,
-                            socket,
-                            allocation_manager,
-                            nonce_manager,
-                            user_database,
-                            realm,
-                        ).await {
+                        if let Err(e) = handle_datagram(&data, src_addr, &context).await {
                             error!("Error handling message from {}: {}", src_addr, e);
                         }
                     });
@@ -119,12 +514,20 @@ impl TurnServer {
                 }
             }
         }
+
+        cleanup.abort();
+        if let Some(tcp) = tcp {
+            tcp.abort();
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::stun::attributes::AttributeType;
+    use crate::stun::message::{Message, MessageClass, MessageMethod, MessageType};
 
     #[tokio::test]
     async fn test_server_creation() {
@@ -133,10 +536,12 @@ mod tests {
             realm: "test.realm".to_string(),
             relay_address_start: "127.0.0.1:50000".parse().unwrap(),
             relay_address_count: 10,
+            ..Default::default()
         };
 
         let server = TurnServer::new(config).await.unwrap();
         assert_eq!(server.config.realm, "test.realm");
+        assert_eq!(server.stats(), ServerStatsSnapshot::default());
     }
 
     #[tokio::test]
@@ -146,6 +551,7 @@ mod tests {
             realm: "test.realm".to_string(),
             relay_address_start: "127.0.0.1:51000".parse().unwrap(),
             relay_address_count: 10,
+            ..Default::default()
         };
         let mut server = TurnServer::new(config).await.unwrap();
         
@@ -154,4 +560,293 @@ mod tests {
         let has_user = server.user_database.authenticate("alice", "password123");
         assert!(has_user);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_from_config_file() {
+        let path = std::env::temp_dir().join(format!("toy-turn-{}.toml", std::process::id()));
+        std::fs::write(&path, r#"
+            listen_address = "127.0.0.1:0"
+            realm = "file.realm"
+            relay_address_start = "127.0.0.1:50100"
+            relay_address_count = 5
+
+            [[users]]
+            username = "alice"
+            password = "password123"
+        "#).unwrap();
+
+        let server = TurnServer::from_config_file(&path).await;
+        std::fs::remove_file(&path).unwrap();
+        let server = server.unwrap();
+
+        assert_eq!(server.config.realm, "file.realm");
+        assert_eq!(server.config.relay_address_count, 5);
+        assert!(server.user_database.authenticate("alice", "password123"));
+
+        assert!(TurnServer::from_config_file(&path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_from_socket() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let server_addr = socket.local_addr().unwrap();
+        let config = TurnServerConfig {
+            listen_address: "127.0.0.1:0".parse().unwrap(),
+            realm: "test.realm".to_string(),
+            relay_address_start: "127.0.0.1:52000".parse().unwrap(),
+            relay_address_count: 10,
+            ..Default::default()
+        };
+
+        let server = Arc::new(TurnServer::from_socket(config, socket));
+        assert_eq!(server.local_addr().unwrap(), server_addr);
+
+        let running = server.clone();
+        tokio::spawn(async move {
+            let _ = running.run().await;
+        });
+
+        // An unauthenticated Allocate is answered on the adopted socket
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = Message::new(MessageType::new(MessageMethod::Allocate, MessageClass::Request));
+        client.send_to(&request.serialize(), server_addr).await.unwrap();
+
+        let mut buf = vec![0u8; 1500];
+        let (len, from) = tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = Message::parse(&buf[..len]).unwrap();
+
+        assert_eq!(from, server_addr);
+        assert_eq!(response.transaction_id, request.transaction_id);
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_replenishes_relay_pool() {
+        use crate::turn::allocation::FiveTuple;
+
+        let config = TurnServerConfig {
+            listen_address: "127.0.0.1:0".parse().unwrap(),
+            relay_address_start: "127.0.0.1:54100".parse().unwrap(),
+            relay_address_count: 1,
+            ..Default::default()
+        };
+        let server = Arc::new(TurnServer::new(config).await.unwrap());
+
+        let running = server.clone();
+        let task = tokio::spawn(async move { running.run().await.is_ok() });
+
+        let client = FiveTuple::udp("127.0.0.1:40000".parse().unwrap());
+        let allocation = server.allocation_manager
+            .create_allocation("alice".to_string(), "test.realm".to_string(), client, Duration::from_secs(600))
+            .await
+            .unwrap();
+        drop(allocation);
+
+        // The only relay address is taken
+        let other = FiveTuple::udp("127.0.0.1:40001".parse().unwrap());
+        assert!(server.allocation_manager
+            .create_allocation("bob".to_string(), "test.realm".to_string(), other, Duration::from_secs(600))
+            .await
+            .is_err());
+
+        server.shutdown();
+        assert!(tokio::time::timeout(Duration::from_secs(1), task).await.unwrap().unwrap());
+        assert_eq!(server.stats().allocations_active, 0);
+
+        // Back in the pool, and the relay socket was closed
+        let allocation = server.allocation_manager
+            .create_allocation("bob".to_string(), "test.realm".to_string(), other, Duration::from_secs(600))
+            .await
+            .unwrap();
+        assert_eq!(allocation.relayed_address, "127.0.0.1:54100".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_allocate_over_tcp() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = TurnServerConfig {
+            listen_address: "127.0.0.1:0".parse().unwrap(),
+            tcp_listen_address: Some("127.0.0.1:0".parse().unwrap()),
+            relay_address_start: "127.0.0.1:54000".parse().unwrap(),
+            relay_address_count: 10,
+            ..Default::default()
+        };
+        let server = Arc::new(TurnServer::new(config).await.unwrap());
+        let tcp_addr = server.tcp_local_addr().unwrap().unwrap();
+
+        let running = server.clone();
+        tokio::spawn(async move {
+            let _ = running.run().await;
+        });
+
+        // Sent in two writes to exercise reassembly
+        let mut stream = tokio::net::TcpStream::connect(tcp_addr).await.unwrap();
+        let request = Message::new(MessageType::new(MessageMethod::Allocate, MessageClass::Request));
+        let data = request.serialize();
+        stream.write_all(&data[..10]).await.unwrap();
+        stream.write_all(&data[10..]).await.unwrap();
+
+        let mut header = [0u8; 20];
+        tokio::time::timeout(Duration::from_secs(1), stream.read_exact(&mut header))
+            .await
+            .unwrap()
+            .unwrap();
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut response = header.to_vec();
+        response.resize(20 + length, 0);
+        stream.read_exact(&mut response[20..]).await.unwrap();
+        let response = Message::parse(&response).unwrap();
+
+        // Unauthenticated, so challenged
+        assert_eq!(response.transaction_id, request.transaction_id);
+        assert_eq!(response.message_type.method(), MessageMethod::Allocate);
+        assert_eq!(response.message_type.class(), MessageClass::ErrorResponse);
+    }
+
+    #[test]
+    fn test_config_builder() {
+        let config = TurnServerConfig::builder()
+            .listen_address("127.0.0.1:3478".parse().unwrap())
+            .realm("example.org")
+            .relay_range("127.0.0.1:65500".parse().unwrap(), 36)
+            .max_allocations_per_user(2)
+            .build()
+            .unwrap();
+
+        assert_eq!(config.listen_address, "127.0.0.1:3478".parse().unwrap());
+        assert_eq!(config.realm, "example.org");
+        assert_eq!(config.relay_address_start.port(), 65500);
+        assert_eq!(config.relay_address_count, 36);
+        assert_eq!(config.max_allocations_per_user, Some(2));
+        // Untouched fields keep their defaults
+        assert_eq!(config.relay_recv_timeout, DEFAULT_RELAY_RECV_TIMEOUT);
+        assert_eq!(config.reservation_lifetime, RESERVATION_LIFETIME);
+        assert!(config.turn_enabled);
+
+        let config = TurnServerConfig::builder().turn_enabled(false).build().unwrap();
+        assert!(!config.turn_enabled);
+    }
+
+    #[test]
+    fn test_config_builder_rejects_relay_range_overflow() {
+        let result = TurnServerConfig::builder()
+            .relay_range("127.0.0.1:65500".parse().unwrap(), 37)
+            .build();
+        assert!(matches!(result, Err(ConfigError::RelayRangeOverflow { count: 37, .. })));
+
+        let result = TurnServerConfig::builder()
+            .ipv6_relay_address_start("[::1]:65535".parse().unwrap())
+            .build();
+        assert!(matches!(result, Err(ConfigError::RelayRangeOverflow { .. })));
+    }
+
+    #[test]
+    fn test_config_builder_relay_reader_sockets() {
+        let result = TurnServerConfig::builder().relay_reader_sockets(0).build();
+        assert!(matches!(result, Err(ConfigError::UnsupportedRelayReaderSockets(0))));
+
+        let result = TurnServerConfig::builder().relay_reader_sockets(4).build();
+        if REUSEPORT_SUPPORTED {
+            assert_eq!(result.unwrap().relay_reader_sockets, 4);
+        } else {
+            assert!(matches!(result, Err(ConfigError::UnsupportedRelayReaderSockets(4))));
+        }
+    }
+
+    #[test]
+    fn test_config_builder_preserve_dscp() {
+        let result = TurnServerConfig::builder().preserve_dscp(true).build();
+        if DSCP_SUPPORTED {
+            assert!(result.unwrap().preserve_dscp);
+        } else {
+            assert!(matches!(result, Err(ConfigError::UnsupportedDscpPreservation)));
+        }
+    }
+
+    #[test]
+    fn test_config_builder_allocate_rate_limit() {
+        let result = TurnServerConfig::builder().allocate_rate_limit(0, 10).build();
+        assert!(matches!(result, Err(ConfigError::InvalidAllocateRateLimit { per_second: 0, burst: 10 })));
+
+        let config = TurnServerConfig::builder().allocate_rate_limit(5, 10).build().unwrap();
+        assert_eq!(config.allocate_rate_limit, Some(AllocateRateLimit { per_second: 5, burst: 10 }));
+    }
+
+    #[tokio::test]
+    async fn test_effective_config_applies_defaults() {
+        let config = TurnServerConfig {
+            listen_address: "127.0.0.1:0".parse().unwrap(),
+            relay_address_start: "127.0.0.1:53000".parse().unwrap(),
+            ..Default::default()
+        };
+
+        let server = TurnServer::new(config).await.unwrap();
+        let effective = server.effective_config();
+
+        assert_eq!(effective.realm, "turn.example.com");
+        assert_eq!(effective.relay_address_count, 100);
+        assert_eq!(effective.relay_address_start, "127.0.0.1:53000".parse().unwrap());
+        assert!(effective.realm_allocation_quotas.is_empty());
+    }
+
+    #[test]
+    fn test_software_for_realm() {
+        let config = TurnServerConfig {
+            software: Some("toy-turn".to_string()),
+            realm_software: HashMap::from([
+                ("tenant-a.com".to_string(), "Tenant A Relay".to_string()),
+                ("tenant-b.com".to_string(), "Tenant B Relay".to_string()),
+            ]),
+            ..Default::default()
+        };
+
+        assert_eq!(config.software_for("tenant-a.com"), Some("Tenant A Relay"));
+        assert_eq!(config.software_for("tenant-b.com"), Some("Tenant B Relay"));
+        assert_eq!(config.software_for("other.com"), Some("toy-turn"));
+        assert_eq!(TurnServerConfig::default().software_for("tenant-a.com"), None);
+    }
+
+    #[tokio::test]
+    async fn test_responses_carry_realm_software() {
+        let mut banners = Vec::new();
+        for realm in ["tenant-a.com", "tenant-b.com"] {
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let server_addr = socket.local_addr().unwrap();
+            let config = TurnServerConfig::builder()
+                .realm(realm)
+                .software("toy-turn")
+                .realm_software("tenant-a.com", "Tenant A Relay")
+                .realm_software("tenant-b.com", "Tenant B Relay")
+                .build()
+                .unwrap();
+            let server = Arc::new(TurnServer::from_socket(config, socket));
+            let running = server.clone();
+            tokio::spawn(async move {
+                let _ = running.run().await;
+            });
+
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let request = Message::new(MessageType::new(MessageMethod::Binding, MessageClass::Request));
+            client.send_to(&request.serialize(), server_addr).await.unwrap();
+
+            let mut buf = vec![0u8; 1500];
+            let (len, _) = tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let response = Message::parse(&buf[..len]).unwrap();
+            let software = response
+                .attributes_iter()
+                .map(Result::unwrap)
+                .find(|attr| attr.attribute_type == AttributeType::Software as u16)
+                .unwrap();
+            banners.push(software.value);
+        }
+
+        assert_eq!(banners, [b"Tenant A Relay".to_vec(), b"Tenant B Relay".to_vec()]);
+    }
+}
@@ -1,4 +1,7 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use crate::stun::error::StunError;
+use crate::stun::message::Message;
+use crate::stun::xor_addr::{decode_xor_address, encode_xor_address};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AttributeType {
@@ -16,6 +19,16 @@ pub enum AttributeType {
     XorPeerAddress = 0x0012,
     Data = 0x0013,
     ChannelNumber = 0x000C,
+    MessageIntegritySha256 = 0x001C,
+    EvenPort = 0x0018,
+    ReservationToken = 0x0022,
+    Software = 0x8022,
+    RequestedAddressFamily = 0x0017,
+    DontFragment = 0x001A,
+    Fingerprint = 0x8028,
+    AlternateServer = 0x8023,
+    // From the pre-RFC TURN drafts; advisory only
+    Bandwidth = 0x0010,
 }
 
 impl AttributeType {
@@ -35,6 +48,15 @@ impl AttributeType {
             0x0012 => Some(AttributeType::XorPeerAddress),
             0x0013 => Some(AttributeType::Data),
             0x000C => Some(AttributeType::ChannelNumber),
+            0x001C => Some(AttributeType::MessageIntegritySha256),
+            0x0018 => Some(AttributeType::EvenPort),
+            0x0022 => Some(AttributeType::ReservationToken),
+            0x8022 => Some(AttributeType::Software),
+            0x0017 => Some(AttributeType::RequestedAddressFamily),
+            0x001A => Some(AttributeType::DontFragment),
+            0x8028 => Some(AttributeType::Fingerprint),
+            0x8023 => Some(AttributeType::AlternateServer),
+            0x0010 => Some(AttributeType::Bandwidth),
             _ => None,
         }
     }
@@ -93,11 +115,278 @@ impl RawAttribute {
         
         result
     }
+    
+    // Bytes taken on the wire, padding included
+    pub fn encoded_len(&self) -> usize {
+        4 + self.value.len().next_multiple_of(4)
+    }
+}
+
+// MAPPED-ADDRESS style encoding, without the XOR; used by ALTERNATE-SERVER
+pub fn encode_address(addr: SocketAddr) -> Vec<u8> {
+    let mut data = vec![0];
+    
+    match addr {
+        SocketAddr::V4(v4) => {
+            data.push(0x01);
+            data.extend_from_slice(&addr.port().to_be_bytes());
+            data.extend_from_slice(&v4.ip().octets());
+        }
+        SocketAddr::V6(v6) => {
+            data.push(0x02);
+            data.extend_from_slice(&addr.port().to_be_bytes());
+            data.extend_from_slice(&v6.ip().octets());
+        }
+    }
+    
+    data
+}
+
+pub fn decode_address(data: &[u8]) -> Option<SocketAddr> {
+    if data.len() < 4 {
+        return None;
+    }
+    
+    let port = u16::from_be_bytes([data[2], data[3]]);
+    
+    match (data[1], &data[4..]) {
+        (0x01, ip) if ip.len() == 4 => {
+            let ip_bytes: [u8; 4] = ip.try_into().ok()?;
+            Some(SocketAddr::from((Ipv4Addr::from(ip_bytes), port)))
+        }
+        (0x02, ip) if ip.len() == 16 => {
+            let ip_bytes: [u8; 16] = ip.try_into().ok()?;
+            Some(SocketAddr::from((Ipv6Addr::from(ip_bytes), port)))
+        }
+        _ => None,
+    }
+}
+
+pub fn encode_error_code(code: u16, reason: &str) -> RawAttribute {
+    // 21 reserved bits, 3-bit class (hundreds digit), 8-bit number, reason phrase
+    let mut value = vec![0, 0, ((code / 100) & 0x07) as u8, (code % 100) as u8];
+    value.extend_from_slice(reason.as_bytes());
+    
+    RawAttribute::new(AttributeType::ErrorCode as u16, value)
+}
+
+pub fn decode_error_code(value: &[u8]) -> Result<(u16, String), StunError> {
+    if value.len() < 4 {
+        return Err(StunError::InvalidAttribute);
+    }
+    
+    let class = (value[2] & 0x07) as u16;
+    let number = value[3] as u16;
+    if !(3..=6).contains(&class) || number > 99 {
+        return Err(StunError::InvalidAttribute);
+    }
+    
+    let reason = String::from_utf8(value[4..].to_vec())
+        .map_err(|_| StunError::InvalidAttribute)?;
+    
+    Ok((class * 100 + number, reason))
+}
+
+// Types below 0x8000 are comprehension-required: a request carrying one
+// the server doesn't know must be rejected with 420 (RFC 5389 section 15)
+pub fn unknown_comprehension_required(message: &Message) -> Result<Vec<u16>, StunError> {
+    let mut unknown = Vec::new();
+    for attr in message.attributes_iter() {
+        let attr = attr?;
+        if attr.attribute_type < 0x8000
+            && AttributeType::from_u16(attr.attribute_type).is_none()
+            && !unknown.contains(&attr.attribute_type)
+        {
+            unknown.push(attr.attribute_type);
+        }
+    }
+    
+    Ok(unknown)
+}
+
+pub fn encode_unknown_attributes(attribute_types: &[u16]) -> RawAttribute {
+    // Consecutive 16-bit types; serialize() pads an odd count to 4 bytes
+    let value = attribute_types
+        .iter()
+        .flat_map(|t| t.to_be_bytes())
+        .collect();
+    
+    RawAttribute::new(AttributeType::UnknownAttributes as u16, value)
+}
+
+pub fn decode_unknown_attributes(value: &[u8]) -> Result<Vec<u16>, StunError> {
+    if !value.len().is_multiple_of(2) {
+        return Err(StunError::InvalidAttribute);
+    }
+    
+    Ok(value
+        .chunks_exact(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+        .collect())
+}
+
+// Decoded value of an attribute this server knows. XOR address variants
+// hold the plain address; encode and decode apply the transaction ID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Attribute {
+    MappedAddress(SocketAddr),
+    Username(String),
+    MessageIntegrity(Vec<u8>),
+    ErrorCode(u16, String),
+    UnknownAttributes(Vec<u16>),
+    Realm(String),
+    Nonce(Vec<u8>),
+    XorRelayedAddress(SocketAddr),
+    // Protocol number, 17 for UDP
+    RequestedTransport(u8),
+    XorMappedAddress(SocketAddr),
+    Lifetime(u32),
+    XorPeerAddress(SocketAddr),
+    Data(Vec<u8>),
+    ChannelNumber(u16),
+    MessageIntegritySha256(Vec<u8>),
+    // Whether the R bit asks for the next port to be reserved as well
+    EvenPort(bool),
+    ReservationToken([u8; 8]),
+    Software(String),
+    // 0x01 IPv4, 0x02 IPv6
+    RequestedAddressFamily(u8),
+    DontFragment,
+    Fingerprint(u32),
+    AlternateServer(SocketAddr),
+    // Kilobits per second
+    Bandwidth(u32),
+}
+
+impl Attribute {
+    pub fn attribute_type(&self) -> AttributeType {
+        match self {
+            Attribute::MappedAddress(_) => AttributeType::MappedAddress,
+            Attribute::Username(_) => AttributeType::Username,
+            Attribute::MessageIntegrity(_) => AttributeType::MessageIntegrity,
+            Attribute::ErrorCode(..) => AttributeType::ErrorCode,
+            Attribute::UnknownAttributes(_) => AttributeType::UnknownAttributes,
+            Attribute::Realm(_) => AttributeType::Realm,
+            Attribute::Nonce(_) => AttributeType::Nonce,
+            Attribute::XorRelayedAddress(_) => AttributeType::XorRelayedAddress,
+            Attribute::RequestedTransport(_) => AttributeType::RequestedTransport,
+            Attribute::XorMappedAddress(_) => AttributeType::XorMappedAddress,
+            Attribute::Lifetime(_) => AttributeType::Lifetime,
+            Attribute::XorPeerAddress(_) => AttributeType::XorPeerAddress,
+            Attribute::Data(_) => AttributeType::Data,
+            Attribute::ChannelNumber(_) => AttributeType::ChannelNumber,
+            Attribute::MessageIntegritySha256(_) => AttributeType::MessageIntegritySha256,
+            Attribute::EvenPort(_) => AttributeType::EvenPort,
+            Attribute::ReservationToken(_) => AttributeType::ReservationToken,
+            Attribute::Software(_) => AttributeType::Software,
+            Attribute::RequestedAddressFamily(_) => AttributeType::RequestedAddressFamily,
+            Attribute::DontFragment => AttributeType::DontFragment,
+            Attribute::Fingerprint(_) => AttributeType::Fingerprint,
+            Attribute::AlternateServer(_) => AttributeType::AlternateServer,
+            Attribute::Bandwidth(_) => AttributeType::Bandwidth,
+        }
+    }
+
+    pub fn encode(&self, transaction_id: &[u8; 12]) -> RawAttribute {
+        let value = match self {
+            Attribute::MappedAddress(addr) | Attribute::AlternateServer(addr) => encode_address(*addr),
+            Attribute::XorRelayedAddress(addr)
+            | Attribute::XorMappedAddress(addr)
+            | Attribute::XorPeerAddress(addr) => encode_xor_address(*addr, transaction_id),
+            Attribute::Username(text) | Attribute::Realm(text) | Attribute::Software(text) => {
+                text.as_bytes().to_vec()
+            }
+            Attribute::MessageIntegrity(bytes)
+            | Attribute::MessageIntegritySha256(bytes)
+            | Attribute::Nonce(bytes)
+            | Attribute::Data(bytes) => bytes.clone(),
+            Attribute::ErrorCode(code, reason) => return encode_error_code(*code, reason),
+            Attribute::UnknownAttributes(types) => return encode_unknown_attributes(types),
+            Attribute::RequestedTransport(value) | Attribute::RequestedAddressFamily(value) => {
+                vec![*value, 0, 0, 0]
+            }
+            Attribute::Lifetime(seconds) => seconds.to_be_bytes().to_vec(),
+            Attribute::Bandwidth(kbps) => kbps.to_be_bytes().to_vec(),
+            // Channel number followed by two reserved bytes
+            Attribute::ChannelNumber(channel_number) => {
+                let mut value = channel_number.to_be_bytes().to_vec();
+                value.extend_from_slice(&[0, 0]);
+                value
+            }
+            Attribute::EvenPort(reserve_next) => vec![if *reserve_next { 0x80 } else { 0 }],
+            Attribute::ReservationToken(token) => token.to_vec(),
+            Attribute::DontFragment => Vec::new(),
+            Attribute::Fingerprint(crc) => crc.to_be_bytes().to_vec(),
+        };
+
+        RawAttribute::new(self.attribute_type() as u16, value)
+    }
+
+    // UnknownAttribute for types not listed in AttributeType, so callers can
+    // tell those from malformed values
+    pub fn decode(raw: &RawAttribute, transaction_id: &[u8; 12]) -> Result<Self, StunError> {
+        let attribute_type = AttributeType::from_u16(raw.attribute_type)
+            .ok_or(StunError::UnknownAttribute(raw.attribute_type))?;
+        let value = raw.value.as_slice();
+
+        let text = || String::from_utf8(value.to_vec()).map_err(|_| StunError::InvalidAttribute);
+        let address = || decode_address(value).ok_or(StunError::InvalidAttribute);
+        let xor_address = || decode_xor_address(value, transaction_id).ok_or(StunError::InvalidAttribute);
+        // One significant byte and three reserved ones that must be zero
+        let padded_byte = || match value {
+            [byte, 0, 0, 0] => Ok(*byte),
+            _ => Err(StunError::InvalidAttribute),
+        };
+        let word = || {
+            value
+                .try_into()
+                .map(u32::from_be_bytes)
+                .map_err(|_| StunError::InvalidAttribute)
+        };
+
+        Ok(match attribute_type {
+            AttributeType::MappedAddress => Attribute::MappedAddress(address()?),
+            AttributeType::AlternateServer => Attribute::AlternateServer(address()?),
+            AttributeType::XorRelayedAddress => Attribute::XorRelayedAddress(xor_address()?),
+            AttributeType::XorMappedAddress => Attribute::XorMappedAddress(xor_address()?),
+            AttributeType::XorPeerAddress => Attribute::XorPeerAddress(xor_address()?),
+            AttributeType::Username => Attribute::Username(text()?),
+            AttributeType::Realm => Attribute::Realm(text()?),
+            AttributeType::Software => Attribute::Software(text()?),
+            AttributeType::MessageIntegrity => Attribute::MessageIntegrity(value.to_vec()),
+            AttributeType::MessageIntegritySha256 => Attribute::MessageIntegritySha256(value.to_vec()),
+            AttributeType::Nonce => Attribute::Nonce(value.to_vec()),
+            AttributeType::Data => Attribute::Data(value.to_vec()),
+            AttributeType::ErrorCode => {
+                let (code, reason) = decode_error_code(value)?;
+                Attribute::ErrorCode(code, reason)
+            }
+            AttributeType::UnknownAttributes => Attribute::UnknownAttributes(decode_unknown_attributes(value)?),
+            AttributeType::RequestedTransport => Attribute::RequestedTransport(padded_byte()?),
+            AttributeType::RequestedAddressFamily => Attribute::RequestedAddressFamily(padded_byte()?),
+            AttributeType::Lifetime => Attribute::Lifetime(word()?),
+            AttributeType::Fingerprint => Attribute::Fingerprint(word()?),
+            AttributeType::Bandwidth => Attribute::Bandwidth(word()?),
+            AttributeType::ChannelNumber => match value {
+                [high, low, _, _] => Attribute::ChannelNumber(u16::from_be_bytes([*high, *low])),
+                _ => return Err(StunError::InvalidAttribute),
+            },
+            AttributeType::EvenPort => match value {
+                [flags] => Attribute::EvenPort(flags & 0x80 != 0),
+                _ => return Err(StunError::InvalidAttribute),
+            },
+            AttributeType::ReservationToken => {
+                Attribute::ReservationToken(value.try_into().map_err(|_| StunError::InvalidAttribute)?)
+            }
+            AttributeType::DontFragment => Attribute::DontFragment,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::stun::message::{MessageClass, MessageMethod, MessageType};
 
     #[test]
     fn test_attribute_type_conversion() {
@@ -106,6 +395,72 @@ mod tests {
         assert_eq!(AttributeType::from_u16(0xFFFF), None);
     }
 
+    const ALL_ATTRIBUTE_TYPES: [AttributeType; 23] = [
+        AttributeType::MappedAddress,
+        AttributeType::Username,
+        AttributeType::MessageIntegrity,
+        AttributeType::ErrorCode,
+        AttributeType::UnknownAttributes,
+        AttributeType::Realm,
+        AttributeType::Nonce,
+        AttributeType::XorRelayedAddress,
+        AttributeType::RequestedTransport,
+        AttributeType::XorMappedAddress,
+        AttributeType::Lifetime,
+        AttributeType::XorPeerAddress,
+        AttributeType::Data,
+        AttributeType::ChannelNumber,
+        AttributeType::MessageIntegritySha256,
+        AttributeType::EvenPort,
+        AttributeType::ReservationToken,
+        AttributeType::Software,
+        AttributeType::RequestedAddressFamily,
+        AttributeType::DontFragment,
+        AttributeType::Fingerprint,
+        AttributeType::AlternateServer,
+        AttributeType::Bandwidth,
+    ];
+
+    // Exhaustive, so adding a variant fails to compile until it is listed
+    // in ALL_ATTRIBUTE_TYPES as well
+    fn listed(attribute_type: AttributeType) -> bool {
+        match attribute_type {
+            AttributeType::MappedAddress
+            | AttributeType::Username
+            | AttributeType::MessageIntegrity
+            | AttributeType::ErrorCode
+            | AttributeType::UnknownAttributes
+            | AttributeType::Realm
+            | AttributeType::Nonce
+            | AttributeType::XorRelayedAddress
+            | AttributeType::RequestedTransport
+            | AttributeType::XorMappedAddress
+            | AttributeType::Lifetime
+            | AttributeType::XorPeerAddress
+            | AttributeType::Data
+            | AttributeType::ChannelNumber
+            | AttributeType::MessageIntegritySha256
+            | AttributeType::EvenPort
+            | AttributeType::ReservationToken
+            | AttributeType::Software
+            | AttributeType::RequestedAddressFamily
+            | AttributeType::DontFragment
+            | AttributeType::Fingerprint
+            | AttributeType::AlternateServer
+            | AttributeType::Bandwidth => ALL_ATTRIBUTE_TYPES.contains(&attribute_type),
+        }
+    }
+
+    #[test]
+    fn test_attribute_type_values_unique_and_round_trip() {
+        let mut seen = std::collections::HashSet::new();
+        for attribute_type in ALL_ATTRIBUTE_TYPES {
+            assert!(listed(attribute_type));
+            assert_eq!(AttributeType::from_u16(attribute_type as u16), Some(attribute_type));
+            assert!(seen.insert(attribute_type as u16), "duplicate value {:#06x}", attribute_type as u16);
+        }
+    }
+
     #[test]
     fn test_parse_attribute() {
         let data = vec![
@@ -157,4 +512,184 @@ mod tests {
         assert_eq!(&serialized[4..9], b"hello");
         assert_eq!(&serialized[9..12], &[0x00, 0x00, 0x00]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_unknown_attributes_round_trip() {
+        let attr = encode_unknown_attributes(&[0x0099, 0x0042]);
+        let serialized = attr.serialize();
+        
+        assert_eq!(serialized.len(), 8);
+        assert_eq!(&serialized[0..2], &[0x00, 0x0A]);
+        assert_eq!(&serialized[2..4], &[0x00, 0x04]);
+        assert_eq!(&serialized[4..8], &[0x00, 0x99, 0x00, 0x42]);
+        
+        let (parsed, _) = RawAttribute::parse(&serialized).unwrap();
+        assert_eq!(decode_unknown_attributes(&parsed.value).unwrap(), vec![0x0099, 0x0042]);
+    }
+
+    #[test]
+    fn test_unknown_attributes_odd_count_padding() {
+        let attr = encode_unknown_attributes(&[0x0001, 0x0002, 0x0003]);
+        let serialized = attr.serialize();
+        
+        // 4 header + 6 value + 2 padding
+        assert_eq!(serialized.len(), 12);
+        assert_eq!(&serialized[2..4], &[0x00, 0x06]);
+        
+        let (parsed, consumed) = RawAttribute::parse(&serialized).unwrap();
+        assert_eq!(consumed, 12);
+        assert_eq!(decode_unknown_attributes(&parsed.value).unwrap(), vec![0x0001, 0x0002, 0x0003]);
+    }
+
+    #[test]
+    fn test_unknown_comprehension_required() {
+        let empty = Message::new(MessageType::new(MessageMethod::Binding, MessageClass::Request));
+        let mut message = empty.clone();
+        message.push_attribute(RawAttribute::new(AttributeType::Username as u16, b"alice".to_vec()));
+        message.push_attribute(RawAttribute::new(0x0099, vec![1, 2, 3, 4]));
+        // Comprehension-optional, so ignored even though unknown
+        message.push_attribute(RawAttribute::new(0x8099, vec![1, 2, 3, 4]));
+        message.push_attribute(RawAttribute::new(0x0099, vec![]));
+        message.push_attribute(RawAttribute::new(0x0042, vec![]));
+        
+        assert_eq!(unknown_comprehension_required(&message).unwrap(), vec![0x0099, 0x0042]);
+        assert!(unknown_comprehension_required(&empty).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_decode_unknown_attributes_invalid_length() {
+        assert!(decode_unknown_attributes(&[0x00, 0x01, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_address_round_trip() {
+        for addr in ["192.0.2.1:3478", "[2001:db8::1]:5349"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let encoded = encode_address(addr);
+            assert_eq!(encoded.len(), if addr.is_ipv4() { 8 } else { 20 });
+            assert_eq!(decode_address(&encoded), Some(addr));
+        }
+        
+        // Not XORed
+        let encoded = encode_address("192.0.2.1:3478".parse().unwrap());
+        assert_eq!(&encoded[2..], &[0x0D, 0x96, 192, 0, 2, 1]);
+        
+        // Family and length disagree
+        assert_eq!(decode_address(&[0, 0x02, 0x0D, 0x96, 192, 0, 2, 1]), None);
+    }
+
+    #[test]
+    fn test_error_code_round_trip() {
+        let attr = encode_error_code(401, "Unauthorized");
+        let serialized = attr.serialize();
+        
+        // 4 header + 4 code + 12 reason, already 4-byte aligned
+        assert_eq!(serialized.len(), 20);
+        assert_eq!(&serialized[4..8], &[0x00, 0x00, 0x04, 0x01]);
+        
+        let (parsed, _) = RawAttribute::parse(&serialized).unwrap();
+        assert_eq!(parsed.attribute_type, AttributeType::ErrorCode as u16);
+        assert_eq!(decode_error_code(&parsed.value).unwrap(), (401, "Unauthorized".to_string()));
+    }
+
+    #[test]
+    fn test_error_code_padding() {
+        let attr = encode_error_code(438, "Stale Nonce");
+        let serialized = attr.serialize();
+        
+        // 4 header + 4 code + 11 reason + 1 padding
+        assert_eq!(serialized.len(), 20);
+        
+        let (parsed, _) = RawAttribute::parse(&serialized).unwrap();
+        assert_eq!(decode_error_code(&parsed.value).unwrap(), (438, "Stale Nonce".to_string()));
+    }
+
+    #[test]
+    fn test_decode_error_code_invalid() {
+        assert!(decode_error_code(&[0x00, 0x00, 0x04]).is_err());
+        // Class 7 is out of range
+        assert!(decode_error_code(&[0x00, 0x00, 0x07, 0x00]).is_err());
+    }
+
+    // One of each variant, with an IPv6 address where the variant holds one
+    fn sample_attributes() -> Vec<Attribute> {
+        let v4: SocketAddr = "192.0.2.1:3478".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:49152".parse().unwrap();
+
+        vec![
+            Attribute::MappedAddress(v4),
+            Attribute::Username("alice".to_string()),
+            Attribute::MessageIntegrity(vec![0xAB; 20]),
+            Attribute::ErrorCode(438, "Stale Nonce".to_string()),
+            Attribute::UnknownAttributes(vec![0x0099, 0x0042]),
+            Attribute::Realm("example.org".to_string()),
+            Attribute::Nonce(b"f00dcafe".to_vec()),
+            Attribute::XorRelayedAddress(v6),
+            Attribute::RequestedTransport(17),
+            Attribute::XorMappedAddress(v4),
+            Attribute::Lifetime(600),
+            Attribute::XorPeerAddress(v6),
+            Attribute::Data(b"hello".to_vec()),
+            Attribute::ChannelNumber(0x4001),
+            Attribute::MessageIntegritySha256(vec![0xCD; 32]),
+            Attribute::EvenPort(true),
+            Attribute::ReservationToken([1, 2, 3, 4, 5, 6, 7, 8]),
+            Attribute::Software("toy-turn".to_string()),
+            Attribute::RequestedAddressFamily(0x02),
+            Attribute::DontFragment,
+            Attribute::Fingerprint(0xDEADBEEF),
+            Attribute::AlternateServer(v6),
+            Attribute::Bandwidth(2000),
+        ]
+    }
+
+    #[test]
+    fn test_typed_attribute_round_trip() {
+        let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let attributes = sample_attributes();
+        assert_eq!(attributes.len(), ALL_ATTRIBUTE_TYPES.len());
+        for attribute in attributes {
+            let raw = attribute.encode(&transaction_id);
+            assert_eq!(raw.attribute_type, attribute.attribute_type() as u16);
+
+            // Through the wire format, padding included
+            let (parsed, _) = RawAttribute::parse(&raw.serialize()).unwrap();
+            assert_eq!(Attribute::decode(&parsed, &transaction_id).unwrap(), attribute);
+        }
+    }
+
+    #[test]
+    fn test_typed_attribute_wire_format() {
+        let transaction_id = [0; 12];
+
+        assert_eq!(Attribute::RequestedTransport(17).encode(&transaction_id).value, vec![17, 0, 0, 0]);
+        assert_eq!(Attribute::ChannelNumber(0x4001).encode(&transaction_id).value, vec![0x40, 0x01, 0, 0]);
+        assert_eq!(Attribute::Lifetime(600).encode(&transaction_id).value, 600u32.to_be_bytes());
+        assert_eq!(Attribute::EvenPort(false).encode(&transaction_id).value, vec![0]);
+        assert!(Attribute::DontFragment.encode(&transaction_id).value.is_empty());
+        assert_eq!(
+            Attribute::ErrorCode(401, "Unauthorized".to_string()).encode(&transaction_id).value,
+            encode_error_code(401, "Unauthorized").value
+        );
+    }
+
+    #[test]
+    fn test_typed_attribute_decode_errors() {
+        let transaction_id = [0; 12];
+        let decode = |attribute_type: AttributeType, value: Vec<u8>| {
+            Attribute::decode(&RawAttribute::new(attribute_type as u16, value), &transaction_id)
+        };
+
+        assert!(matches!(decode(AttributeType::Lifetime, vec![0, 0, 2]), Err(StunError::InvalidAttribute)));
+        assert!(matches!(decode(AttributeType::RequestedTransport, vec![17, 0, 1, 0]), Err(StunError::InvalidAttribute)));
+        assert!(matches!(decode(AttributeType::ChannelNumber, vec![0x40]), Err(StunError::InvalidAttribute)));
+        assert!(matches!(decode(AttributeType::EvenPort, vec![]), Err(StunError::InvalidAttribute)));
+        assert!(matches!(decode(AttributeType::ReservationToken, vec![1, 2, 3]), Err(StunError::InvalidAttribute)));
+        assert!(matches!(decode(AttributeType::Username, vec![0xFF, 0xFE]), Err(StunError::InvalidAttribute)));
+        assert!(matches!(decode(AttributeType::XorPeerAddress, vec![0, 0x03, 0, 0, 0, 0, 0, 0]), Err(StunError::InvalidAttribute)));
+
+        let unknown = Attribute::decode(&RawAttribute::new(0x0099, vec![]), &transaction_id);
+        assert!(matches!(unknown, Err(StunError::UnknownAttribute(0x0099))));
+    }
+}
@@ -1,10 +1,13 @@
 use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
 use sha1::Sha1;
+use sha2::Sha256;
 use crate::stun::error::StunError;
 use crate::stun::message::Message;
-use crate::stun::attributes::{RawAttribute, AttributeType};
+use crate::stun::attributes::AttributeType;
 
 type HmacSha1 = Hmac<Sha1>;
+type HmacSha256 = Hmac<Sha256>;
 
 #[derive(Debug, Clone)]
 pub struct Credentials {
@@ -23,27 +26,36 @@ impl Credentials {
     }
 
     pub fn compute_key(&self) -> Vec<u8> {
-        // Key = MD5(username:realm:password)
-        // Note: In production, MD5 should be replaced with a more secure hash
+        // Key = MD5(username:realm:password), RFC 5389 section 15.4.
+        // The password is expected to already be SASLprep'd.
         let key_string = format!("{}:{}:{}", self.username, self.realm, self.password);
-        // For now, we'll use the string directly as the key
-        // In a real implementation, this should be MD5 hashed
-        key_string.into_bytes()
+        Md5::digest(key_string.as_bytes()).to_vec()
+    }
+
+    pub fn compute_key_sha256(&self) -> Vec<u8> {
+        // Key = SHA-256(username:realm:password), RFC 8489 section 9.2.2
+        // with PASSWORD-ALGORITHM SHA-256
+        let key_string = format!("{}:{}:{}", self.username, self.realm, self.password);
+        Sha256::digest(key_string.as_bytes()).to_vec()
     }
 }
 
-pub fn calculate_message_integrity(message: &Message, key: &[u8]) -> Result<Vec<u8>, StunError> {
-    // Create a copy of the message for integrity calculation
-    let mut msg_bytes = message.serialize();
-    
-    // The message length must be adjusted to include MESSAGE-INTEGRITY
-    let original_length = message.length;
-    let new_length = original_length + 24; // MESSAGE-INTEGRITY attribute is 24 bytes (4 header + 20 HMAC)
+// Serializes the message with its length field covering an integrity
+// attribute of attribute_len bytes appended after the current attributes
+pub(crate) fn integrity_input(message: &Message, attribute_len: u16) -> Vec<u8> {
+    let mut msg_bytes = message.serialize().to_vec();
     
-    // Update the length field in the serialized message
+    let new_length = message.length + attribute_len;
     msg_bytes[2] = (new_length >> 8) as u8;
     msg_bytes[3] = new_length as u8;
     
+    msg_bytes
+}
+
+pub fn calculate_message_integrity(message: &Message, key: &[u8]) -> Result<Vec<u8>, StunError> {
+    // MESSAGE-INTEGRITY attribute is 24 bytes (4 header + 20 HMAC)
+    let msg_bytes = integrity_input(message, 24);
+    
     // Calculate HMAC-SHA1 over the message up to (but not including) the MESSAGE-INTEGRITY attribute
     let mut mac = HmacSha1::new_from_slice(key)
         .map_err(|_| StunError::ParseError("Invalid key length".to_string()))?;
@@ -52,43 +64,82 @@ pub fn calculate_message_integrity(message: &Message, key: &[u8]) -> Result<Vec<
     Ok(mac.finalize().into_bytes().to_vec())
 }
 
-pub fn verify_message_integrity(message: &Message, key: &[u8]) -> Result<bool, StunError> {
-    // Find the MESSAGE-INTEGRITY attribute
-    let mut offset = 0;
-    let mut found_integrity = false;
-    let mut integrity_value = Vec::new();
-    let mut integrity_offset = 0;
+pub fn calculate_message_integrity_sha256(message: &Message, key: &[u8]) -> Result<Vec<u8>, StunError> {
+    // MESSAGE-INTEGRITY-SHA256 attribute is 36 bytes (4 header + 32 HMAC)
+    let msg_bytes = integrity_input(message, 36);
     
-    while offset < message.attributes.len() {
-        let (attr, consumed) = RawAttribute::parse(&message.attributes[offset..])?;
-        
-        if AttributeType::from_u16(attr.attribute_type) == Some(AttributeType::MessageIntegrity) {
-            found_integrity = true;
-            integrity_value = attr.value;
-            integrity_offset = offset;
-            break;
+    let mut mac = HmacSha256::new_from_slice(key)
+        .map_err(|_| StunError::ParseError("Invalid key length".to_string()))?;
+    mac.update(&msg_bytes);
+    
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+// Returns the offset and value of the first attribute of the given type
+fn find_integrity_attribute(
+    message: &Message,
+    attribute_type: AttributeType,
+) -> Result<Option<(usize, Vec<u8>)>, StunError> {
+    let mut offset = 0;
+    for attr in message.attributes_iter() {
+        let attr = attr?;
+        if AttributeType::from_u16(attr.attribute_type) == Some(attribute_type) {
+            return Ok(Some((offset, attr.value)));
         }
         
-        offset += consumed;
+        offset += attr.encoded_len();
     }
     
-    if !found_integrity {
+    Ok(None)
+}
+
+// Copy of the message truncated just before the attribute at offset
+fn message_before(message: &Message, offset: usize) -> Message {
+    let mut verify_msg = message.clone();
+    verify_msg.attributes = message.attributes[..offset].to_vec();
+    verify_msg.length = offset as u16;
+    verify_msg
+}
+
+pub fn verify_message_integrity(message: &Message, key: &[u8]) -> Result<bool, StunError> {
+    let Some((integrity_offset, integrity_value)) =
+        find_integrity_attribute(message, AttributeType::MessageIntegrity)?
+    else {
         return Ok(false);
-    }
+    };
     
-    // Create a message copy for verification
-    let mut verify_msg = message.clone();
-    verify_msg.attributes = message.attributes[..integrity_offset].to_vec();
-    verify_msg.length = integrity_offset as u16;
+    let calculated = calculate_message_integrity(&message_before(message, integrity_offset), key)?;
     
-    let calculated = calculate_message_integrity(&verify_msg, key)?;
+    Ok(calculated == integrity_value)
+}
+
+pub fn verify_message_integrity_sha256(message: &Message, key: &[u8]) -> Result<bool, StunError> {
+    let Some((integrity_offset, integrity_value)) =
+        find_integrity_attribute(message, AttributeType::MessageIntegritySha256)?
+    else {
+        return Ok(false);
+    };
+    
+    let calculated = calculate_message_integrity_sha256(&message_before(message, integrity_offset), key)?;
     
     Ok(calculated == integrity_value)
 }
 
+// Checks the long-term credentials on a message, preferring
+// MESSAGE-INTEGRITY-SHA256 when the client sent it. A bad SHA-256 value is
+// not rescued by a good SHA-1 one.
+pub fn verify_long_term_integrity(message: &Message, credentials: &Credentials) -> Result<bool, StunError> {
+    if find_integrity_attribute(message, AttributeType::MessageIntegritySha256)?.is_some() {
+        return verify_message_integrity_sha256(message, &credentials.compute_key_sha256());
+    }
+    
+    verify_message_integrity(message, &credentials.compute_key())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::stun::attributes::RawAttribute;
     use crate::stun::message::{MessageType, MessageMethod, MessageClass};
 
     #[test]
@@ -104,7 +155,26 @@ mod tests {
         assert_eq!(creds.realm, "realm");
         
         let key = creds.compute_key();
-        assert!(!key.is_empty());
+        assert_eq!(key.len(), 16);
+    }
+
+    #[test]
+    fn test_compute_key_md5() {
+        let creds = Credentials::new(
+            "user".to_string(),
+            "pass".to_string(),
+            "realm".to_string(),
+        );
+        assert_eq!(hex::encode(creds.compute_key()), "8493fbc53ba582fb4c044c456bdc40eb");
+
+        // Long-term credentials from the RFC 5769 section 2.4 sample request,
+        // with the password after SASLprep
+        let creds = Credentials::new(
+            "\u{30DE}\u{30C8}\u{30EA}\u{30C3}\u{30AF}\u{30B9}".to_string(),
+            "TheMatrIX".to_string(),
+            "example.org".to_string(),
+        );
+        assert_eq!(hex::encode(creds.compute_key()), "e8ca7ad59d5eb0518e312911d2dab2a9");
     }
 
     #[test]
@@ -159,4 +229,61 @@ mod tests {
         let valid = verify_message_integrity(&message, wrong_key).unwrap();
         assert!(!valid);
     }
-}
\ No newline at end of file
+
+    fn sign(message: &mut Message, attribute_type: AttributeType, integrity: Vec<u8>) {
+        message.attributes.extend(RawAttribute::new(attribute_type as u16, integrity).serialize());
+        message.length = message.attributes.len() as u16;
+    }
+
+    fn username_message() -> Message {
+        let mut message = Message::new(MessageType::new(
+            MessageMethod::Allocate,
+            MessageClass::Request,
+        ));
+        message.attributes = RawAttribute::new(AttributeType::Username as u16, b"user".to_vec()).serialize();
+        message.length = message.attributes.len() as u16;
+        message
+    }
+
+    #[test]
+    fn test_message_integrity_sha256_round_trip() {
+        let mut message = username_message();
+        let key = b"secret-key";
+
+        let integrity = calculate_message_integrity_sha256(&message, key).unwrap();
+        assert_eq!(integrity.len(), 32);
+        sign(&mut message, AttributeType::MessageIntegritySha256, integrity);
+
+        assert!(verify_message_integrity_sha256(&message, key).unwrap());
+        assert!(!verify_message_integrity_sha256(&message, b"wrong-key").unwrap());
+
+        // No SHA-1 attribute to check
+        assert!(!verify_message_integrity(&message, key).unwrap());
+    }
+
+    #[test]
+    fn test_long_term_integrity_with_both_attributes() {
+        let creds = Credentials::new("user".to_string(), "pass".to_string(), "realm".to_string());
+        let mut message = username_message();
+
+        // MESSAGE-INTEGRITY-SHA256 follows MESSAGE-INTEGRITY and covers it
+        let integrity = calculate_message_integrity(&message, &creds.compute_key()).unwrap();
+        sign(&mut message, AttributeType::MessageIntegrity, integrity);
+        let unsigned_sha256 = message.clone();
+        let integrity = calculate_message_integrity_sha256(&message, &creds.compute_key_sha256()).unwrap();
+        sign(&mut message, AttributeType::MessageIntegritySha256, integrity);
+
+        assert!(verify_message_integrity(&message, &creds.compute_key()).unwrap());
+        assert!(verify_message_integrity_sha256(&message, &creds.compute_key_sha256()).unwrap());
+        assert!(verify_long_term_integrity(&message, &creds).unwrap());
+
+        // A valid SHA-1 value does not rescue a bad SHA-256 one
+        let mut tampered = unsigned_sha256.clone();
+        sign(&mut tampered, AttributeType::MessageIntegritySha256, vec![0; 32]);
+        assert!(verify_message_integrity(&tampered, &creds.compute_key()).unwrap());
+        assert!(!verify_long_term_integrity(&tampered, &creds).unwrap());
+
+        // SHA-1 only falls back to the MD5 key
+        assert!(verify_long_term_integrity(&unsigned_sha256, &creds).unwrap());
+    }
+}
@@ -0,0 +1,60 @@
+use std::net::SocketAddr;
+use crate::stun::{
+    message::{IntoStunMessage, Message, MessageClass, MessageMethod, MessageType},
+    attributes::{RawAttribute, AttributeType},
+    xor_addr::encode_xor_address,
+};
+
+// Plain STUN Binding (RFC 5389 section 7.3.1), so the TURN port can also
+// answer ICE connectivity checks
+#[derive(Debug, Clone)]
+pub struct BindingResponse {
+    pub transaction_id: [u8; 12],
+    pub mapped_address: SocketAddr,
+}
+
+impl BindingResponse {
+    pub fn success(transaction_id: [u8; 12], mapped_address: SocketAddr) -> Self {
+        BindingResponse {
+            transaction_id,
+            mapped_address,
+        }
+    }
+}
+
+impl IntoStunMessage for BindingResponse {
+    fn to_message(&self) -> Message {
+        let mut message = Message::new(MessageType::new(
+            MessageMethod::Binding,
+            MessageClass::SuccessResponse,
+        ));
+        message.transaction_id = self.transaction_id;
+
+        message.push_attribute(RawAttribute::new(
+            AttributeType::XorMappedAddress as u16,
+            encode_xor_address(self.mapped_address, &self.transaction_id),
+        ));
+
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stun::xor_addr::decode_xor_address;
+
+    #[test]
+    fn test_binding_response() {
+        let mapped_address: SocketAddr = "[2001:db8::1]:3478".parse().unwrap();
+        let response = BindingResponse::success([7; 12], mapped_address);
+
+        let message = Message::parse(&response.to_message().serialize()).unwrap();
+        assert_eq!(message.message_type.method(), MessageMethod::Binding);
+        assert_eq!(message.message_type.class(), MessageClass::SuccessResponse);
+
+        let (attr, _) = RawAttribute::parse(&message.attributes).unwrap();
+        assert_eq!(attr.attribute_type, AttributeType::XorMappedAddress as u16);
+        assert_eq!(decode_xor_address(&attr.value, &message.transaction_id), Some(mapped_address));
+    }
+}
@@ -20,6 +20,9 @@ pub enum StunError {
     #[error("Unknown attribute: {0}")]
     UnknownAttribute(u16),
     
+    #[error("Attribute after FINGERPRINT")]
+    AttributeAfterFingerprint,
+    
     #[error("Invalid transaction ID")]
     InvalidTransactionId,
     
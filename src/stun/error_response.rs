@@ -0,0 +1,85 @@
+use crate::stun::{
+    message::{IntoStunMessage, Message, MessageClass, MessageMethod, MessageType},
+    attributes::{encode_error_code, encode_unknown_attributes},
+};
+
+// Error response to a request of any method, for rejections made before
+// the method-specific request is parsed
+#[derive(Debug, Clone)]
+pub struct ErrorResponse {
+    pub method: MessageMethod,
+    pub transaction_id: [u8; 12],
+    pub error_code: u16,
+    pub error_reason: String,
+    // Listed in UNKNOWN-ATTRIBUTES, for a 420
+    pub unknown_attributes: Vec<u16>,
+}
+
+impl ErrorResponse {
+    pub fn new(request: &Message, error_code: u16, error_reason: String) -> Self {
+        ErrorResponse {
+            method: request.message_type.method(),
+            transaction_id: request.transaction_id,
+            error_code,
+            error_reason,
+            unknown_attributes: Vec::new(),
+        }
+    }
+
+    pub fn unknown_attributes(request: &Message, unknown_attributes: Vec<u16>) -> Self {
+        let mut response = Self::new(request, 420, "Unknown Attribute".to_string());
+        response.unknown_attributes = unknown_attributes;
+        response
+    }
+}
+
+impl IntoStunMessage for ErrorResponse {
+    fn to_message(&self) -> Message {
+        let mut message = Message::new(MessageType::new(self.method, MessageClass::ErrorResponse));
+        message.transaction_id = self.transaction_id;
+
+        message.push_attribute(encode_error_code(self.error_code, &self.error_reason));
+        if !self.unknown_attributes.is_empty() {
+            message.push_attribute(encode_unknown_attributes(&self.unknown_attributes));
+        }
+
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stun::attributes::{decode_error_code, decode_unknown_attributes, AttributeType, RawAttribute};
+
+    #[test]
+    fn test_error_response_matches_request() {
+        let mut request = Message::new(MessageType::new(MessageMethod::ChannelBind, MessageClass::Request));
+        request.transaction_id = [9; 12];
+
+        let response = ErrorResponse::new(&request, 403, "Forbidden".to_string());
+        let message = Message::parse(&response.to_message().serialize()).unwrap();
+        assert_eq!(message.message_type.method(), MessageMethod::ChannelBind);
+        assert_eq!(message.message_type.class(), MessageClass::ErrorResponse);
+        assert_eq!(message.transaction_id, [9; 12]);
+
+        let (attr, _) = RawAttribute::parse(&message.attributes).unwrap();
+        assert_eq!(attr.attribute_type, AttributeType::ErrorCode as u16);
+        assert_eq!(decode_error_code(&attr.value).unwrap(), (403, "Forbidden".to_string()));
+        assert_eq!(message.attributes.len(), 4 + attr.value.len().next_multiple_of(4));
+    }
+
+    #[test]
+    fn test_unknown_attributes_response() {
+        let request = Message::new(MessageType::new(MessageMethod::Refresh, MessageClass::Request));
+
+        let response = ErrorResponse::unknown_attributes(&request, vec![0x0099]);
+        let message = Message::parse(&response.to_message().serialize()).unwrap();
+
+        let (attr, consumed) = RawAttribute::parse(&message.attributes).unwrap();
+        assert_eq!(decode_error_code(&attr.value).unwrap().0, 420);
+        let (attr, _) = RawAttribute::parse(&message.attributes[consumed..]).unwrap();
+        assert_eq!(attr.attribute_type, AttributeType::UnknownAttributes as u16);
+        assert_eq!(decode_unknown_attributes(&attr.value).unwrap(), vec![0x0099]);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::stun::attributes::{AttributeType, RawAttribute};
+use crate::stun::auth::integrity_input;
+use crate::stun::error::StunError;
+use crate::stun::message::Message;
+
+// XORed into the CRC so it differs from the CRC of an application protocol
+// sharing the port (RFC 5389 section 15.5)
+const FINGERPRINT_XOR: u32 = 0x5354_554E;
+
+// FINGERPRINT attribute is 8 bytes (4 header + 4 CRC)
+const FINGERPRINT_ATTRIBUTE_LEN: u16 = 8;
+
+// CRC-32 over the message with its length field covering a FINGERPRINT
+// appended after the current attributes
+pub fn calculate_fingerprint(message: &Message) -> u32 {
+    crc32fast::hash(&integrity_input(message, FINGERPRINT_ATTRIBUTE_LEN)) ^ FINGERPRINT_XOR
+}
+
+pub fn add_fingerprint(message: &mut Message) {
+    let fingerprint = calculate_fingerprint(message);
+    let attr = RawAttribute::new(AttributeType::Fingerprint as u16, fingerprint.to_be_bytes().to_vec());
+    message.push_attribute(attr);
+}
+
+// Ok(false) when there is no FINGERPRINT or its CRC doesn't match. Anything
+// after FINGERPRINT is an error: the CRC must cover the whole message
+// except itself.
+pub fn verify_fingerprint(message: &Message) -> Result<bool, StunError> {
+    let mut offset = 0;
+    for attr in message.attributes_iter() {
+        let attr = attr?;
+        if AttributeType::from_u16(attr.attribute_type) == Some(AttributeType::Fingerprint) {
+            if offset + attr.encoded_len() != message.attributes.len() {
+                return Err(StunError::AttributeAfterFingerprint);
+            }
+
+            let value: [u8; 4] = attr.value.try_into().map_err(|_| StunError::InvalidAttribute)?;
+
+            let mut before = message.clone();
+            before.attributes.truncate(offset);
+            before.length = offset as u16;
+
+            return Ok(calculate_fingerprint(&before) == u32::from_be_bytes(value));
+        }
+
+        offset += attr.encoded_len();
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stun::message::{MessageClass, MessageMethod, MessageType};
+
+    fn binding_request() -> Message {
+        let mut message = Message::new(MessageType::new(MessageMethod::Binding, MessageClass::Request));
+        message.transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        message.attributes.extend(RawAttribute::new(AttributeType::Software as u16, b"toy-turn".to_vec()).serialize());
+        message.length = message.attributes.len() as u16;
+        message
+    }
+
+    #[test]
+    fn test_fingerprint_round_trip() {
+        let mut message = binding_request();
+        add_fingerprint(&mut message);
+
+        // Survives serialization
+        let parsed = Message::parse(&message.serialize()).unwrap();
+        assert!(verify_fingerprint(&parsed).unwrap());
+
+        // No FINGERPRINT at all
+        assert!(!verify_fingerprint(&binding_request()).unwrap());
+    }
+
+    #[test]
+    fn test_fingerprint_corrupted_crc_rejected() {
+        let mut message = binding_request();
+        add_fingerprint(&mut message);
+
+        let last = message.attributes.len() - 1;
+        message.attributes[last] ^= 0x01;
+        assert!(!verify_fingerprint(&message).unwrap());
+
+        // Or a covered byte changed instead
+        let mut message = binding_request();
+        add_fingerprint(&mut message);
+        message.transaction_id[0] ^= 0x01;
+        assert!(!verify_fingerprint(&message).unwrap());
+    }
+
+    #[test]
+    fn test_attribute_after_fingerprint_rejected() {
+        let mut message = binding_request();
+        add_fingerprint(&mut message);
+        message.attributes.extend(RawAttribute::new(AttributeType::Realm as u16, b"example.org".to_vec()).serialize());
+        message.length = message.attributes.len() as u16;
+
+        assert!(matches!(verify_fingerprint(&message), Err(StunError::AttributeAfterFingerprint)));
+    }
+}
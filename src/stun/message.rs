@@ -1,4 +1,5 @@
 use bytes::{BufMut, BytesMut};
+use crate::stun::attributes::RawAttribute;
 use crate::stun::error::StunError;
 
 pub const MAGIC_COOKIE: u32 = 0x2112A442;
@@ -104,6 +105,20 @@ impl MessageType {
     }
 }
 
+pub trait IntoStunMessage {
+    fn to_message(&self) -> Message;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParseOptions {
+    // UDP carries exactly one STUN message per datagram, so anything after
+    // the declared length is a protocol error rather than another message
+    pub reject_trailing_bytes: bool,
+    // An all-zero transaction ID is legal but points at a broken or
+    // hostile client's generator
+    pub reject_zero_transaction_id: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct Message {
     pub message_type: MessageType,
@@ -128,6 +143,10 @@ impl Message {
     }
     
     pub fn parse(data: &[u8]) -> Result<Self, StunError> {
+        Self::parse_with_options(data, ParseOptions::default())
+    }
+    
+    pub fn parse_with_options(data: &[u8], options: ParseOptions) -> Result<Self, StunError> {
         if data.len() < STUN_HEADER_SIZE {
             return Err(StunError::MessageTooShort);
         }
@@ -136,8 +155,12 @@ impl Message {
         let msg_type_value = u16::from_be_bytes([data[0], data[1]]);
         let message_type = MessageType::from_u16(msg_type_value)?;
         
-        // Parse length
+        // Parse length; attributes are padded to 4 bytes, so any other
+        // length can't be a STUN message (RFC 5389 section 6)
         let length = u16::from_be_bytes([data[2], data[3]]);
+        if !length.is_multiple_of(4) {
+            return Err(StunError::InvalidMessageLength);
+        }
         
         // Check magic cookie
         let magic_cookie = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
@@ -149,11 +172,19 @@ impl Message {
         let mut transaction_id = [0u8; 12];
         transaction_id.copy_from_slice(&data[8..20]);
         
+        if options.reject_zero_transaction_id && transaction_id == [0u8; 12] {
+            return Err(StunError::InvalidTransactionId);
+        }
+        
         // Check if we have enough data for the attributes
         if data.len() < STUN_HEADER_SIZE + length as usize {
             return Err(StunError::InvalidMessageLength);
         }
         
+        if options.reject_trailing_bytes && data.len() > STUN_HEADER_SIZE + length as usize {
+            return Err(StunError::InvalidMessageLength);
+        }
+        
         // Parse attributes (for now, just store raw bytes)
         let attributes = data[STUN_HEADER_SIZE..STUN_HEADER_SIZE + length as usize].to_vec();
         
@@ -165,6 +196,44 @@ impl Message {
         })
     }
     
+    // Appends the attribute, padded, keeping length in step
+    pub fn push_attribute(&mut self, attr: RawAttribute) {
+        self.attributes.extend(attr.serialize());
+        self.length = self.attributes.len() as u16;
+    }
+    
+    pub fn with_attributes(mut self, attributes: impl IntoIterator<Item = RawAttribute>) -> Self {
+        for attr in attributes {
+            self.push_attribute(attr);
+        }
+        self
+    }
+    
+    // Attributes in order, padding skipped. A malformed attribute is
+    // reported once and ends the iteration, as nothing after it can be
+    // located.
+    pub fn attributes_iter(&self) -> impl Iterator<Item = Result<RawAttribute, StunError>> + '_ {
+        let mut offset = 0;
+        let mut failed = false;
+        
+        std::iter::from_fn(move || {
+            if failed || offset >= self.attributes.len() {
+                return None;
+            }
+            
+            match RawAttribute::parse(&self.attributes[offset..]) {
+                Ok((attr, consumed)) => {
+                    offset += consumed;
+                    Some(Ok(attr))
+                }
+                Err(e) => {
+                    failed = true;
+                    Some(Err(e))
+                }
+            }
+        })
+    }
+    
     pub fn serialize(&self) -> BytesMut {
         let mut buf = BytesMut::with_capacity(STUN_HEADER_SIZE + self.attributes.len());
         
@@ -290,4 +359,111 @@ mod tests {
         assert_eq!(parsed.message_type.class(), original.message_type.class());
         assert_eq!(parsed.transaction_id, original.transaction_id);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_trailing_bytes() {
+        let message = Message::new(MessageType::new(MessageMethod::Binding, MessageClass::Request));
+        let mut data = message.serialize();
+        data.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        
+        // Tolerated by default
+        let parsed = Message::parse(&data).expect("Trailing bytes should be ignored by default");
+        assert_eq!(parsed.transaction_id, message.transaction_id);
+        
+        // Rejected in strict mode
+        let options = ParseOptions { reject_trailing_bytes: true, ..Default::default() };
+        let result = Message::parse_with_options(&data, options);
+        assert!(matches!(result.unwrap_err(), StunError::InvalidMessageLength));
+        
+        // An exact datagram still parses in strict mode
+        let exact = message.serialize();
+        assert!(Message::parse_with_options(&exact, options).is_ok());
+    }
+
+    #[test]
+    fn test_unaligned_length_rejected() {
+        let mut message = Message::new(MessageType::new(MessageMethod::Binding, MessageClass::Request));
+        message.attributes = vec![0; 6];
+        message.length = 6;
+        let mut data = message.serialize();
+        
+        // Rejected even though the data covers the declared length
+        assert!(matches!(Message::parse(&data).unwrap_err(), StunError::InvalidMessageLength));
+        
+        data[3] = 8;
+        data.extend_from_slice(&[0, 0]);
+        assert!(Message::parse(&data).is_ok());
+    }
+
+    #[test]
+    fn test_zero_transaction_id() {
+        let mut message = Message::new(MessageType::new(MessageMethod::Binding, MessageClass::Request));
+        message.transaction_id = [0; 12];
+        let data = message.serialize();
+        
+        // Tolerated by default
+        assert!(Message::parse(&data).is_ok());
+        
+        // Rejected in strict mode
+        let options = ParseOptions { reject_zero_transaction_id: true, ..Default::default() };
+        let result = Message::parse_with_options(&data, options);
+        assert!(matches!(result.unwrap_err(), StunError::InvalidTransactionId));
+        
+        // Any non-zero byte is enough
+        message.transaction_id[11] = 1;
+        assert!(Message::parse_with_options(&message.serialize(), options).is_ok());
+    }
+
+    #[test]
+    fn test_push_attribute_keeps_length() {
+        use crate::stun::attributes::AttributeType;
+        
+        let mut message = Message::new(MessageType::new(MessageMethod::Refresh, MessageClass::Request));
+        message.push_attribute(RawAttribute::new(AttributeType::Lifetime as u16, 600u32.to_be_bytes().to_vec()));
+        assert_eq!(message.length, 8);
+        
+        // Padded to 8 and 4 bytes of value
+        message.push_attribute(RawAttribute::new(AttributeType::Username as u16, b"alice".to_vec()));
+        message.push_attribute(RawAttribute::new(AttributeType::DontFragment as u16, Vec::new()));
+        assert_eq!(message.length, 8 + 12 + 4);
+        assert_eq!(message.length as usize, message.attributes.len());
+        
+        let parsed = Message::parse(&message.serialize()).unwrap();
+        assert_eq!(parsed.attributes_iter().count(), 3);
+        
+        let built = Message::new(MessageType::new(MessageMethod::Refresh, MessageClass::Request))
+            .with_attributes(message.attributes_iter().map(Result::unwrap));
+        assert_eq!(built.attributes, message.attributes);
+        assert_eq!(built.length, message.length);
+    }
+    
+    #[test]
+    fn test_attributes_iter() {
+        use crate::stun::attributes::AttributeType;
+        
+        let mut message = Message::new(MessageType::new(MessageMethod::Allocate, MessageClass::Request));
+        // 5-byte username and 1-byte EVEN-PORT both need padding
+        for attr in [
+            RawAttribute::new(AttributeType::Username as u16, b"alice".to_vec()),
+            RawAttribute::new(AttributeType::EvenPort as u16, vec![0x80]),
+            RawAttribute::new(AttributeType::Lifetime as u16, 600u32.to_be_bytes().to_vec()),
+        ] {
+            message.push_attribute(attr);
+        }
+        
+        let attributes: Vec<_> = message.attributes_iter().map(Result::unwrap).collect();
+        assert_eq!(attributes.len(), 3);
+        assert_eq!(attributes[0].attribute_type, AttributeType::Username as u16);
+        assert_eq!(attributes[0].value, b"alice");
+        assert_eq!(attributes[1].attribute_type, AttributeType::EvenPort as u16);
+        assert_eq!(attributes[1].value, vec![0x80]);
+        assert_eq!(attributes[2].attribute_type, AttributeType::Lifetime as u16);
+        assert_eq!(attributes[2].value, 600u32.to_be_bytes());
+        
+        // Trailing bytes too short for an attribute header are one error
+        message.attributes.extend([0x00, 0x06]);
+        let results: Vec<_> = message.attributes_iter().collect();
+        assert_eq!(results.len(), 4);
+        assert!(matches!(results[3], Err(StunError::InvalidAttribute)));
+    }
+}
@@ -1,4 +1,8 @@
 pub mod message;
 pub mod attributes;
 pub mod error;
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod binding;
+pub mod error_response;
+pub mod fingerprint;
+pub mod xor_addr;
\ No newline at end of file
@@ -0,0 +1,137 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use crate::stun::message::MAGIC_COOKIE;
+
+// XOR-MAPPED-ADDRESS style encoding shared by XOR-PEER-ADDRESS,
+// XOR-RELAYED-ADDRESS and XOR-MAPPED-ADDRESS
+pub fn encode_xor_address(addr: SocketAddr, transaction_id: &[u8; 12]) -> Vec<u8> {
+    let mut data = Vec::new();
+    
+    // Padding
+    data.push(0);
+    
+    // Family
+    data.push(match addr {
+        SocketAddr::V4(_) => 0x01,
+        SocketAddr::V6(_) => 0x02,
+    });
+    
+    // XOR Port
+    let xor_port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    data.extend_from_slice(&xor_port.to_be_bytes());
+    
+    match addr {
+        SocketAddr::V4(v4) => {
+            let ip = u32::from_be_bytes(v4.ip().octets());
+            data.extend_from_slice(&(ip ^ MAGIC_COOKIE).to_be_bytes());
+        }
+        SocketAddr::V6(v6) => {
+            let mut ip_bytes = v6.ip().octets();
+            xor_ipv6(&mut ip_bytes, transaction_id);
+            data.extend_from_slice(&ip_bytes);
+        }
+    }
+    
+    data
+}
+
+pub fn decode_xor_address(data: &[u8], transaction_id: &[u8; 12]) -> Option<SocketAddr> {
+    if data.len() < 8 {
+        return None;
+    }
+    
+    let family = data[1];
+    let port = u16::from_be_bytes([data[2], data[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+    
+    match family {
+        0x01 => {
+            let xor_ip = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+            let ip_addr = Ipv4Addr::from(xor_ip ^ MAGIC_COOKIE);
+            Some(SocketAddr::from((ip_addr, port)))
+        }
+        0x02 => {
+            if data.len() < 20 {
+                return None;
+            }
+            
+            let mut ip_bytes = [0u8; 16];
+            ip_bytes.copy_from_slice(&data[4..20]);
+            xor_ipv6(&mut ip_bytes, transaction_id);
+            Some(SocketAddr::from((Ipv6Addr::from(ip_bytes), port)))
+        }
+        _ => None,
+    }
+}
+
+// The family byte of an encoded address when it is neither IPv4 (0x01) nor
+// IPv6 (0x02), so callers can say why decoding failed
+pub fn unsupported_family(data: &[u8]) -> Option<u8> {
+    data.get(1).copied().filter(|family| !matches!(family, 0x01 | 0x02))
+}
+
+// High 32 bits are XORed with the magic cookie, low 96 with the transaction ID
+fn xor_ipv6(ip_bytes: &mut [u8; 16], transaction_id: &[u8; 12]) {
+    for (byte, mask) in ip_bytes.iter_mut().zip(MAGIC_COOKIE.to_be_bytes().iter().chain(transaction_id)) {
+        *byte ^= mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xor_address_ipv4_round_trip() {
+        let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let addr: SocketAddr = "192.0.2.1:49152".parse().unwrap();
+        
+        let encoded = encode_xor_address(addr, &transaction_id);
+        assert_eq!(encoded.len(), 8);
+        assert_eq!(encoded[1], 0x01);
+        
+        assert_eq!(decode_xor_address(&encoded, &transaction_id), Some(addr));
+    }
+
+    #[test]
+    fn test_xor_address_ipv6_round_trip() {
+        let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let addr: SocketAddr = "[2001:db8::1]:49152".parse().unwrap();
+        
+        let encoded = encode_xor_address(addr, &transaction_id);
+        assert_eq!(encoded.len(), 20);
+        assert_eq!(encoded[1], 0x02);
+        
+        // The low 96 bits are masked with the transaction ID
+        let SocketAddr::V6(v6) = addr else { unreachable!() };
+        let octets = v6.ip().octets();
+        for i in 4..16 {
+            assert_eq!(encoded[4 + i], octets[i] ^ transaction_id[i - 4]);
+        }
+        
+        assert_eq!(decode_xor_address(&encoded, &transaction_id), Some(addr));
+        
+        // A different transaction ID decodes to a different address
+        assert_ne!(decode_xor_address(&encoded, &[0u8; 12]), Some(addr));
+    }
+
+    #[test]
+    fn test_unsupported_family() {
+        let transaction_id = [0; 12];
+        let mut encoded = encode_xor_address("192.0.2.1:80".parse().unwrap(), &transaction_id);
+        assert_eq!(unsupported_family(&encoded), None);
+
+        encoded[1] = 0x03;
+        assert_eq!(unsupported_family(&encoded), Some(0x03));
+        assert_eq!(decode_xor_address(&encoded, &transaction_id), None);
+
+        // Too short to carry a family at all
+        assert_eq!(unsupported_family(&[0]), None);
+    }
+
+    #[test]
+    fn test_xor_address_ipv6_truncated() {
+        let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let encoded = encode_xor_address("[2001:db8::1]:8080".parse().unwrap(), &transaction_id);
+
+        assert_eq!(decode_xor_address(&encoded[..8], &transaction_id), None);
+    }
+}
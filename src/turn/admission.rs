@@ -0,0 +1,33 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use crate::turn::error::TurnError;
+
+pub type AdmissionFuture<'a> = Pin<Box<dyn Future<Output = Result<(), TurnError>> + Send + 'a>>;
+
+// Deployment-specific admission control (time of day, geo-IP, ...) consulted
+// before an allocation is created. A rejection's error code is what the
+// client sees in the Allocate error response.
+pub trait AdmissionPolicy: Debug + Send + Sync {
+    fn allow<'a>(
+        &'a self,
+        username: &'a str,
+        client_address: SocketAddr,
+        realm: &'a str,
+    ) -> AdmissionFuture<'a>;
+}
+
+#[derive(Debug, Default)]
+pub struct AllowAll;
+
+impl AdmissionPolicy for AllowAll {
+    fn allow<'a>(
+        &'a self,
+        _username: &'a str,
+        _client_address: SocketAddr,
+        _realm: &'a str,
+    ) -> AdmissionFuture<'a> {
+        Box::pin(async { Ok(()) })
+    }
+}
@@ -1,10 +1,15 @@
 use std::net::SocketAddr;
 use crate::stun::{
-    message::{Message, MessageClass, MessageMethod},
-    attributes::{RawAttribute, AttributeType},
+    message::{IntoStunMessage, Message, MessageClass, MessageMethod, MessageType},
+    attributes::{encode_address, encode_error_code, Attribute, RawAttribute, AttributeType},
+    xor_addr::encode_xor_address,
 };
+use crate::turn::auth::decode_username;
 use crate::turn::error::TurnError;
 
+// REQUESTED-TRANSPORT protocol number for UDP, the only relay transport
+pub const TRANSPORT_UDP: u8 = 17;
+
 #[derive(Debug, Clone)]
 pub struct AllocateRequest {
     pub transaction_id: [u8; 12],
@@ -12,7 +17,9 @@ pub struct AllocateRequest {
     pub dont_fragment: bool,
     pub reservation_token: Option<[u8; 8]>,
     pub even_port: bool,
+    pub reserve_next_port: bool,
     pub requested_address_family: Option<u8>,
+    pub lifetime: Option<u32>,
     pub username: Option<String>,
     pub realm: Option<String>,
     pub nonce: Option<Vec<u8>>,
@@ -32,26 +39,37 @@ impl AllocateRequest {
             dont_fragment: false,
             reservation_token: None,
             even_port: false,
+            reserve_next_port: false,
             requested_address_family: None,
+            lifetime: None,
             username: None,
             realm: None,
             nonce: None,
         };
 
         // Parse attributes
-        let mut offset = 0;
-        while offset < message.attributes.len() {
-            let (attr, consumed) = RawAttribute::parse(&message.attributes[offset..])?;
-            offset += consumed;
+        for attr in message.attributes_iter() {
+            let attr = attr?;
 
             match AttributeType::from_u16(attr.attribute_type) {
                 Some(AttributeType::RequestedTransport) => {
-                    if attr.value.len() >= 4 {
-                        request.requested_transport = Some(attr.value[0]);
+                    // Protocol byte followed by three reserved bytes that must be zero
+                    if attr.value.len() != 4 || attr.value[1..] != [0, 0, 0] {
+                        return Err(TurnError::BadRequest);
                     }
+                    request.requested_transport = Some(attr.value[0]);
+                }
+                Some(AttributeType::Lifetime) if attr.value.len() >= 4 => {
+                    let lifetime = u32::from_be_bytes([
+                        attr.value[0],
+                        attr.value[1],
+                        attr.value[2],
+                        attr.value[3],
+                    ]);
+                    request.lifetime = Some(lifetime);
                 }
                 Some(AttributeType::Username) => {
-                    request.username = String::from_utf8(attr.value).ok();
+                    request.username = decode_username(attr.value)?;
                 }
                 Some(AttributeType::Realm) => {
                     request.realm = String::from_utf8(attr.value).ok();
@@ -59,7 +77,27 @@ impl AllocateRequest {
                 Some(AttributeType::Nonce) => {
                     request.nonce = Some(attr.value);
                 }
-                _ => {} // Ignore unknown attributes for now
+                Some(AttributeType::EvenPort) => {
+                    // One byte whose top bit (R) asks to reserve the next port too
+                    let flags = attr.value.first().ok_or(TurnError::BadRequest)?;
+                    request.even_port = true;
+                    request.reserve_next_port = flags & 0x80 != 0;
+                }
+                Some(AttributeType::ReservationToken) => {
+                    let token = attr.value.as_slice().try_into().map_err(|_| TurnError::BadRequest)?;
+                    request.reservation_token = Some(token);
+                }
+                Some(AttributeType::DontFragment) => {
+                    request.dont_fragment = true;
+                }
+                Some(AttributeType::RequestedAddressFamily) => {
+                    // Family byte (0x01 IPv4, 0x02 IPv6) and three reserved bytes
+                    if attr.value.len() != 4 || attr.value[1..] != [0, 0, 0] {
+                        return Err(TurnError::BadRequest);
+                    }
+                    request.requested_address_family = Some(attr.value[0]);
+                }
+                _ => {} // Unknown comprehension-required types are turned away by the handler
             }
         }
 
@@ -74,6 +112,10 @@ pub struct AllocateResponse {
     pub mapped_address: Option<SocketAddr>,
     pub lifetime: Option<u32>,
     pub reservation_token: Option<[u8; 8]>,
+    // Sent with a 300 to redirect the client (RFC 5389 section 11)
+    pub alternate_server: Option<SocketAddr>,
+    // Advisory relay limit in kbit/s, sent as the legacy BANDWIDTH attribute
+    pub bandwidth: Option<u32>,
     pub error_code: Option<(u16, String)>,
     pub realm: Option<String>,
     pub nonce: Option<Vec<u8>>,
@@ -92,6 +134,8 @@ impl AllocateResponse {
             mapped_address: Some(mapped_address),
             lifetime: Some(lifetime),
             reservation_token: None,
+            alternate_server: None,
+            bandwidth: None,
             error_code: None,
             realm: None,
             nonce: None,
@@ -111,17 +155,79 @@ impl AllocateResponse {
             mapped_address: None,
             lifetime: None,
             reservation_token: None,
+            alternate_server: None,
+            bandwidth: None,
             error_code: Some((error_code, error_reason)),
             realm,
             nonce,
         }
     }
+
+    pub fn try_alternate(transaction_id: [u8; 12], alternate_server: SocketAddr) -> Self {
+        let e = TurnError::TryAlternate;
+        let mut response = Self::error(transaction_id, e.error_code(), e.to_string(), None, None);
+        response.alternate_server = Some(alternate_server);
+        response
+    }
+}
+
+impl IntoStunMessage for AllocateResponse {
+    fn to_message(&self) -> Message {
+        let class = if self.error_code.is_some() {
+            MessageClass::ErrorResponse
+        } else {
+            MessageClass::SuccessResponse
+        };
+
+        let mut message = Message::new(MessageType::new(MessageMethod::Allocate, class));
+        message.transaction_id = self.transaction_id;
+
+        if let Some((code, reason)) = &self.error_code {
+            message.push_attribute(encode_error_code(*code, reason));
+        }
+
+        if let Some(realm) = &self.realm {
+            message.push_attribute(RawAttribute::new(AttributeType::Realm as u16, realm.as_bytes().to_vec()));
+        }
+
+        if let Some(nonce) = &self.nonce {
+            message.push_attribute(RawAttribute::new(AttributeType::Nonce as u16, nonce.clone()));
+        }
+
+        if let Some(relayed_address) = self.relayed_address {
+            let value = encode_xor_address(relayed_address, &self.transaction_id);
+            message.push_attribute(RawAttribute::new(AttributeType::XorRelayedAddress as u16, value));
+        }
+
+        if let Some(mapped_address) = self.mapped_address {
+            let value = encode_xor_address(mapped_address, &self.transaction_id);
+            message.push_attribute(RawAttribute::new(AttributeType::XorMappedAddress as u16, value));
+        }
+
+        if let Some(lifetime) = self.lifetime {
+            message.push_attribute(RawAttribute::new(AttributeType::Lifetime as u16, lifetime.to_be_bytes().to_vec()));
+        }
+
+        if let Some(reservation_token) = self.reservation_token {
+            message.push_attribute(RawAttribute::new(AttributeType::ReservationToken as u16, reservation_token.to_vec()));
+        }
+
+        if let Some(alternate_server) = self.alternate_server {
+            message.push_attribute(RawAttribute::new(AttributeType::AlternateServer as u16, encode_address(alternate_server)));
+        }
+
+        if let Some(kbps) = self.bandwidth {
+            message.push_attribute(Attribute::Bandwidth(kbps).encode(&self.transaction_id));
+        }
+
+        message
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::stun::message::MessageType;
+    use crate::stun::xor_addr::decode_xor_address;
 
     fn create_allocate_request_message(attributes: Vec<RawAttribute>) -> Message {
         let mut message = Message::new(MessageType::new(
@@ -156,7 +262,93 @@ mod tests {
         let request = AllocateRequest::from_message(&message).unwrap();
 
         assert_eq!(request.username, Some("testuser".to_string()));
-        assert_eq!(request.requested_transport, Some(17)); // UDP
+        assert_eq!(request.requested_transport, Some(TRANSPORT_UDP));
+    }
+
+    #[test]
+    fn test_parse_requested_transport_strict() {
+        let message = create_allocate_request_message(vec![
+            RawAttribute::new(AttributeType::RequestedTransport as u16, vec![17, 0, 0, 0]),
+        ]);
+        assert_eq!(AllocateRequest::from_message(&message).unwrap().requested_transport, Some(17));
+
+        // Non-zero reserved bytes
+        let message = create_allocate_request_message(vec![
+            RawAttribute::new(AttributeType::RequestedTransport as u16, vec![17, 0, 1, 0]),
+        ]);
+        assert!(matches!(AllocateRequest::from_message(&message), Err(TurnError::BadRequest)));
+
+        // Wrong length
+        let message = create_allocate_request_message(vec![
+            RawAttribute::new(AttributeType::RequestedTransport as u16, vec![17, 0, 0, 0, 0, 0, 0, 0]),
+        ]);
+        assert!(matches!(AllocateRequest::from_message(&message), Err(TurnError::BadRequest)));
+
+        let message = create_allocate_request_message(vec![
+            RawAttribute::new(AttributeType::RequestedTransport as u16, vec![17]),
+        ]);
+        assert!(matches!(AllocateRequest::from_message(&message), Err(TurnError::BadRequest)));
+    }
+
+    #[test]
+    fn test_parse_even_port_and_reservation_token() {
+        let message = create_allocate_request_message(vec![
+            RawAttribute::new(AttributeType::EvenPort as u16, vec![0x80]),
+        ]);
+        let request = AllocateRequest::from_message(&message).unwrap();
+        assert!(request.even_port);
+        assert!(request.reserve_next_port);
+
+        let message = create_allocate_request_message(vec![
+            RawAttribute::new(AttributeType::EvenPort as u16, vec![0x00]),
+        ]);
+        let request = AllocateRequest::from_message(&message).unwrap();
+        assert!(request.even_port);
+        assert!(!request.reserve_next_port);
+
+        let message = create_allocate_request_message(vec![
+            RawAttribute::new(AttributeType::ReservationToken as u16, vec![1, 2, 3, 4, 5, 6, 7, 8]),
+        ]);
+        let request = AllocateRequest::from_message(&message).unwrap();
+        assert_eq!(request.reservation_token, Some([1, 2, 3, 4, 5, 6, 7, 8]));
+
+        // Tokens are exactly 8 bytes
+        let message = create_allocate_request_message(vec![
+            RawAttribute::new(AttributeType::ReservationToken as u16, vec![1, 2, 3, 4]),
+        ]);
+        assert!(matches!(AllocateRequest::from_message(&message), Err(TurnError::BadRequest)));
+    }
+
+    #[test]
+    fn test_parse_requested_address_family() {
+        let message = create_allocate_request_message(vec![
+            RawAttribute::new(AttributeType::RequestedAddressFamily as u16, vec![0x02, 0, 0, 0]),
+        ]);
+        let request = AllocateRequest::from_message(&message).unwrap();
+        assert_eq!(request.requested_address_family, Some(0x02));
+
+        let message = create_allocate_request_message(vec![
+            RawAttribute::new(AttributeType::RequestedAddressFamily as u16, vec![0x02]),
+        ]);
+        assert!(matches!(AllocateRequest::from_message(&message), Err(TurnError::BadRequest)));
+    }
+
+    #[test]
+    fn test_parse_allocate_request_lifetime() {
+        let transport_attr = RawAttribute::new(
+            AttributeType::RequestedTransport as u16,
+            vec![17, 0, 0, 0],
+        );
+        
+        let lifetime_attr = RawAttribute::new(
+            AttributeType::Lifetime as u16,
+            7200u32.to_be_bytes().to_vec(),
+        );
+
+        let message = create_allocate_request_message(vec![transport_attr, lifetime_attr]);
+        let request = AllocateRequest::from_message(&message).unwrap();
+
+        assert_eq!(request.lifetime, Some(7200));
     }
 
     #[test]
@@ -208,4 +400,109 @@ mod tests {
         assert_eq!(response.error_code, Some((401, "Unauthorized".to_string())));
         assert_eq!(response.realm, Some("example.com".to_string()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_allocate_response_to_message() {
+        let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let response = AllocateResponse::success(
+            transaction_id,
+            "192.0.2.1:49152".parse().unwrap(),
+            "10.0.0.1:54321".parse().unwrap(),
+            600,
+        );
+
+        let serialized = response.to_message().serialize();
+        let parsed = Message::parse(&serialized).unwrap();
+
+        assert_eq!(parsed.message_type.method(), MessageMethod::Allocate);
+        assert_eq!(parsed.message_type.class(), MessageClass::SuccessResponse);
+        assert_eq!(parsed.transaction_id, transaction_id);
+    }
+
+    fn find_attribute(message: &Message, attribute_type: AttributeType) -> Option<RawAttribute> {
+        message.attributes_iter()
+            .map(Result::unwrap)
+            .find(|attr| attr.attribute_type == attribute_type as u16)
+    }
+
+    #[test]
+    fn test_allocate_response_xor_relayed_address() {
+        let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let relayed_addr: SocketAddr = "[2001:db8::10]:49152".parse().unwrap();
+        let mapped_addr: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+
+        let response = AllocateResponse::success(transaction_id, relayed_addr, mapped_addr, 600);
+        let parsed = Message::parse(&response.to_message().serialize()).unwrap();
+
+        let attr = find_attribute(&parsed, AttributeType::XorRelayedAddress).unwrap();
+        assert_eq!(decode_xor_address(&attr.value, &parsed.transaction_id), Some(relayed_addr));
+
+        // Each address is encoded in its own family
+        let attr = find_attribute(&parsed, AttributeType::XorMappedAddress).unwrap();
+        assert_eq!(attr.value.len(), 8);
+        assert_eq!(decode_xor_address(&attr.value, &parsed.transaction_id), Some(mapped_addr));
+    }
+
+    #[test]
+    fn test_allocate_response_xor_mapped_address() {
+        let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let relayed_addr: SocketAddr = "192.0.2.1:49152".parse().unwrap();
+
+        for mapped_addr in ["10.0.0.1:54321", "[2001:db8::2]:54321"] {
+            let mapped_addr: SocketAddr = mapped_addr.parse().unwrap();
+            let response = AllocateResponse::success(transaction_id, relayed_addr, mapped_addr, 600);
+            let parsed = Message::parse(&response.to_message().serialize()).unwrap();
+
+            let attr = find_attribute(&parsed, AttributeType::XorMappedAddress).unwrap();
+            let decoded = decode_xor_address(&attr.value, &parsed.transaction_id).unwrap();
+            assert_eq!(decoded, mapped_addr);
+            assert_eq!(decoded.port(), 54321);
+            assert_eq!(decoded.ip(), mapped_addr.ip());
+        }
+    }
+
+    #[test]
+    fn test_allocate_response_lifetime_attribute() {
+        let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let response = AllocateResponse::success(
+            transaction_id,
+            "192.0.2.1:49152".parse().unwrap(),
+            "10.0.0.1:54321".parse().unwrap(),
+            1200,
+        );
+
+        let parsed = Message::parse(&response.to_message().serialize()).unwrap();
+        let attr = find_attribute(&parsed, AttributeType::Lifetime).unwrap();
+        assert_eq!(attr.value, 1200u32.to_be_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_allocate_response_reservation_token() {
+        let relayed_addr: SocketAddr = "192.0.2.1:49152".parse().unwrap();
+        let mapped_addr: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+
+        let mut response = AllocateResponse::success([1; 12], relayed_addr, mapped_addr, 600);
+        let parsed = Message::parse(&response.to_message().serialize()).unwrap();
+        assert!(find_attribute(&parsed, AttributeType::ReservationToken).is_none());
+
+        response.reservation_token = Some([9, 8, 7, 6, 5, 4, 3, 2]);
+        let parsed = Message::parse(&response.to_message().serialize()).unwrap();
+        let attr = find_attribute(&parsed, AttributeType::ReservationToken).unwrap();
+        assert_eq!(attr.value, vec![9, 8, 7, 6, 5, 4, 3, 2]);
+    }
+
+    #[test]
+    fn test_allocate_response_bandwidth() {
+        let relayed_addr: SocketAddr = "192.0.2.1:49152".parse().unwrap();
+        let mapped_addr: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+
+        let mut response = AllocateResponse::success([1; 12], relayed_addr, mapped_addr, 600);
+        let parsed = Message::parse(&response.to_message().serialize()).unwrap();
+        assert!(find_attribute(&parsed, AttributeType::Bandwidth).is_none());
+
+        response.bandwidth = Some(1500);
+        let parsed = Message::parse(&response.to_message().serialize()).unwrap();
+        let attr = find_attribute(&parsed, AttributeType::Bandwidth).unwrap();
+        assert!(matches!(Attribute::decode(&attr, &parsed.transaction_id), Ok(Attribute::Bandwidth(1500))));
+    }
+}
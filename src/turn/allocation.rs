@@ -1,23 +1,195 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use crate::server::socket_options::{bind_reuseport, set_receive_tos};
+use crate::turn::admission::{AdmissionPolicy, AllowAll};
 use crate::turn::error::TurnError;
+use crate::turn::relay_address::{AddressFamily, RelayAddressPool, RelayAddressProvider};
+use crate::turn::reservation::ReservationStore;
+use crate::turn::sharded_map::{ShardedMap, DEFAULT_SHARDS};
 
 pub const DEFAULT_ALLOCATION_LIFETIME: Duration = Duration::from_secs(600); // 10 minutes
 pub const MAX_ALLOCATION_LIFETIME: Duration = Duration::from_secs(3600); // 1 hour
+pub const CHANNEL_BINDING_LIFETIME: Duration = Duration::from_secs(600); // RFC 5766 section 11
+pub const RELAY_ERROR_LOG_CAPACITY: usize = 16;
+// How often an idle peer relay task wakes to check its allocation
+pub const DEFAULT_RELAY_RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+static NEXT_ALLOCATION_ID: AtomicU64 = AtomicU64::new(1);
+
+// Why an allocation's peer relay task stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayStopReason {
+    Expired,
+    Shutdown,
+    Deleted,
+    Error,
+}
+
+impl fmt::Display for RelayStopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            RelayStopReason::Expired => "expiry",
+            RelayStopReason::Shutdown => "shutdown",
+            RelayStopReason::Deleted => "delete",
+            RelayStopReason::Error => "error",
+        };
+        f.write_str(reason)
+    }
+}
+
+// Signalled when the allocation goes away so its peer relay tasks exit and
+// drop their sockets. The first reason given is the one reported.
+#[derive(Debug, Default)]
+pub struct RelayShutdown {
+    notify: Notify,
+    reason: Mutex<Option<RelayStopReason>>,
+}
+
+impl RelayShutdown {
+    pub fn stop(&self, reason: RelayStopReason) {
+        self.reason.lock().unwrap().get_or_insert(reason);
+        self.notify.notify_waiters();
+    }
+
+    // Resolves straight away if stop was called before this was
+    pub async fn stopped(&self) -> RelayStopReason {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        if let Some(reason) = *self.reason.lock().unwrap() {
+            return reason;
+        }
+        notified.await;
+        self.reason.lock().unwrap().unwrap_or(RelayStopReason::Shutdown)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RelayError {
+    pub kind: std::io::ErrorKind,
+    pub peer_address: SocketAddr,
+    pub at: SystemTime,
+}
+
+// The last few relay send failures for an allocation, kept for diagnostics
+// instead of logging each one. Clones of an allocation share the log.
+#[derive(Debug, Clone)]
+pub struct RelayErrorLog {
+    entries: Arc<Mutex<VecDeque<RelayError>>>,
+    capacity: usize,
+}
+
+impl RelayErrorLog {
+    pub fn new(capacity: usize) -> Self {
+        RelayErrorLog {
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub fn record(&self, kind: std::io::ErrorKind, peer_address: SocketAddr) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(RelayError {
+            kind,
+            peer_address,
+            at: SystemTime::now(),
+        });
+    }
+
+    // Oldest first
+    pub fn recent(&self) -> Vec<RelayError> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+// Which relay port an Allocate asked for. EVEN-PORT and RESERVATION-TOKEN
+// are mutually exclusive (RFC 5766 section 6.2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RelayPortRequest {
+    #[default]
+    Any,
+    Even { reserve_next: bool },
+    Reserved([u8; 8]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+// Identifies an allocation (RFC 5766 section 2.2). The server side of the
+// 5-tuple is the same for every client on a listener, so only the client
+// address and transport protocol vary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FiveTuple {
+    pub client_address: SocketAddr,
+    pub transport: Transport,
+}
+
+impl FiveTuple {
+    pub fn new(client_address: SocketAddr, transport: Transport) -> Self {
+        FiveTuple { client_address, transport }
+    }
+
+    pub fn udp(client_address: SocketAddr) -> Self {
+        Self::new(client_address, Transport::Udp)
+    }
+
+    pub fn tcp(client_address: SocketAddr) -> Self {
+        Self::new(client_address, Transport::Tcp)
+    }
+}
+
+// Family and port of the relayed address an Allocate asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RelayRequest {
+    pub family: AddressFamily,
+    pub port: RelayPortRequest,
+}
 
 #[derive(Debug, Clone)]
 pub struct Allocation {
+    // Unique for the life of the process, for tying log lines together
+    pub id: u64,
     pub username: String,
+    pub realm: String,
     pub relayed_address: SocketAddr,
     pub client_address: SocketAddr,
+    pub transport: Transport,
     pub created_at: Instant,
     pub lifetime: Duration,
     pub relay_socket: Arc<UdpSocket>,
+    // Further sockets bound to the same address with SO_REUSEPORT, each
+    // with its own peer relay task. Sends all go out relay_socket.
+    pub relay_readers: Vec<Arc<UdpSocket>>,
     pub permissions: HashMap<SocketAddr, Instant>,
-    pub channel_bindings: HashMap<u16, SocketAddr>,
+    // Peer and when the binding was last made or refreshed
+    pub channel_bindings: HashMap<u16, (SocketAddr, Instant)>,
+    // Reverse of channel_bindings, for framing inbound peer data
+    pub channel_peers: HashMap<SocketAddr, u16>,
+    pub relay_shutdown: Arc<RelayShutdown>,
+    // Set when the relayed address is being drained; the allocation runs
+    // out its lifetime but cannot be refreshed
+    pub draining: bool,
+    // Token for the port reserved alongside this allocation, if any
+    pub reservation_token: Option<[u8; 8]>,
+    pub relay_errors: RelayErrorLog,
+    // Whether DF is currently set on relay_socket, so it is only toggled
+    // when a Send indication asks for something different. Held from
+    // setting DF until the packet is sent, so a concurrent Send can't
+    // change it under a packet in flight; sends on one allocation queue.
+    pub dont_fragment: Arc<tokio::sync::Mutex<bool>>,
 }
 
 impl Allocation {
@@ -28,29 +200,39 @@ impl Allocation {
         relay_socket: Arc<UdpSocket>,
     ) -> Self {
         Allocation {
+            id: NEXT_ALLOCATION_ID.fetch_add(1, Ordering::Relaxed),
             username,
+            realm: String::new(),
             relayed_address,
             client_address,
+            transport: Transport::Udp,
             created_at: Instant::now(),
             lifetime: DEFAULT_ALLOCATION_LIFETIME,
             relay_socket,
+            relay_readers: Vec::new(),
             permissions: HashMap::new(),
             channel_bindings: HashMap::new(),
+            channel_peers: HashMap::new(),
+            relay_shutdown: Arc::new(RelayShutdown::default()),
+            draining: false,
+            reservation_token: None,
+            relay_errors: RelayErrorLog::new(RELAY_ERROR_LOG_CAPACITY),
+            dont_fragment: Arc::new(tokio::sync::Mutex::new(false)),
         }
     }
 
+    pub fn five_tuple(&self) -> FiveTuple {
+        FiveTuple::new(self.client_address, self.transport)
+    }
+
     pub fn is_expired(&self) -> bool {
         self.created_at.elapsed() >= self.lifetime
     }
 
-    pub fn refresh(&mut self, lifetime: Duration) -> Result<(), TurnError> {
-        if lifetime > MAX_ALLOCATION_LIFETIME {
-            return Err(TurnError::BadRequest);
-        }
-        
-        self.lifetime = lifetime;
+    // Longer lifetimes are clamped, as for Allocate (RFC 5766 section 7.2)
+    pub fn refresh(&mut self, lifetime: Duration) {
+        self.lifetime = lifetime.min(MAX_ALLOCATION_LIFETIME);
         self.created_at = Instant::now();
-        Ok(())
     }
 
     pub fn add_permission(&mut self, peer_address: SocketAddr) {
@@ -72,13 +254,60 @@ impl Allocation {
             return Err(TurnError::BadRequest);
         }
         
-        self.channel_bindings.insert(channel_number, peer_address);
+        // A channel bound to another peer, or a peer bound to another
+        // channel, is a 400 (RFC 5766 section 11.2). Binding the same pair
+        // again just refreshes the timer.
+        if let Some((bound_peer, _)) = self.channel_bindings.get(&channel_number)
+            && *bound_peer != peer_address
+        {
+            return Err(TurnError::BadRequest);
+        }
+        if let Some(&bound_channel) = self.channel_peers.get(&peer_address)
+            && bound_channel != channel_number
+        {
+            return Err(TurnError::BadRequest);
+        }
+        
+        self.channel_bindings.insert(channel_number, (peer_address, Instant::now()));
+        self.channel_peers.insert(peer_address, channel_number);
         self.add_permission(peer_address);
         Ok(())
     }
 
+    // Expired bindings are ignored until cleanup_expired_channels drops them
     pub fn get_peer_by_channel(&self, channel_number: u16) -> Option<&SocketAddr> {
-        self.channel_bindings.get(&channel_number)
+        match self.channel_bindings.get(&channel_number) {
+            Some((peer_address, bound_at)) if bound_at.elapsed() < CHANNEL_BINDING_LIFETIME => Some(peer_address),
+            _ => None,
+        }
+    }
+
+    pub fn get_channel_by_peer(&self, peer_address: &SocketAddr) -> Option<u16> {
+        let channel_number = *self.channel_peers.get(peer_address)?;
+        self.get_peer_by_channel(channel_number).map(|_| channel_number)
+    }
+
+    pub fn remove_permission(&mut self, peer_address: &SocketAddr) -> bool {
+        self.permissions.remove(peer_address).is_some()
+    }
+
+    pub fn remove_channel_binding(&mut self, channel_number: u16) -> Option<SocketAddr> {
+        let (peer_address, _) = self.channel_bindings.remove(&channel_number)?;
+        self.channel_peers.remove(&peer_address);
+        Some(peer_address)
+    }
+
+    // Live permissions and channel bindings; expired ones still in the
+    // maps until the next cleanup are not counted
+    pub fn permission_count(&self) -> usize {
+        self.permissions.keys().filter(|peer_address| self.has_permission(peer_address)).count()
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channel_bindings
+            .values()
+            .filter(|(_, bound_at)| bound_at.elapsed() < CHANNEL_BINDING_LIFETIME)
+            .count()
     }
 
     pub fn cleanup_expired_permissions(&mut self) {
@@ -87,98 +316,474 @@ impl Allocation {
             now.duration_since(*granted_at) < Duration::from_secs(300)
         });
     }
+
+    pub fn cleanup_expired_channels(&mut self) {
+        let now = Instant::now();
+        let channel_peers = &mut self.channel_peers;
+        self.channel_bindings.retain(|_, (peer_address, bound_at)| {
+            let live = now.duration_since(*bound_at) < CHANNEL_BINDING_LIFETIME;
+            if !live {
+                channel_peers.remove(peer_address);
+            }
+            live
+        });
+    }
+}
+
+// An Allocate past its quota checks but not yet inserted. It counts as an
+// allocation, so concurrent requests can't all pass the same check.
+#[derive(Debug)]
+struct PendingAllocation {
+    five_tuple: FiveTuple,
+    username: String,
+    realm: String,
+}
+
+// Releases the pending entry if creation fails before insert()
+struct PendingSlot<'a> {
+    manager: &'a AllocationManager,
+    five_tuple: FiveTuple,
+}
+
+impl PendingSlot<'_> {
+    // Swapped for the real allocation under the same lock, so the two are
+    // never both counted or both missing
+    fn insert(self, allocation: Allocation) {
+        let mut pending = self.manager.pending.lock().unwrap();
+        self.manager.allocations.insert(self.five_tuple, allocation);
+        pending.retain(|entry| entry.five_tuple != self.five_tuple);
+    }
+}
+
+impl Drop for PendingSlot<'_> {
+    fn drop(&mut self) {
+        self.manager.pending.lock().unwrap().retain(|entry| entry.five_tuple != self.five_tuple);
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct AllocationManager {
-    allocations: Arc<Mutex<HashMap<SocketAddr, Allocation>>>,
-    relay_address_pool: Arc<Mutex<Vec<SocketAddr>>>,
+    allocations: Arc<ShardedMap<FiveTuple, Allocation>>,
+    pending: Arc<Mutex<Vec<PendingAllocation>>>,
+    relay_address_provider: Arc<dyn RelayAddressProvider>,
+    realm_quotas: HashMap<String, usize>,
+    max_allocations_per_user: Option<usize>,
+    admission_policy: Arc<dyn AdmissionPolicy>,
+    drained_addresses: Arc<Mutex<HashSet<SocketAddr>>>,
+    reservations: Arc<ReservationStore>,
+    relay_recv_timeout: Duration,
+    // How long an Allocate waits for a relay address to be released when
+    // none is free; None fails straight away with 508
+    address_wait_timeout: Option<Duration>,
+    address_released: Arc<Notify>,
+    // Sockets bound per relayed address; above one they share it through
+    // SO_REUSEPORT
+    relay_reader_sockets: usize,
+    // Relay sockets report each datagram's TOS byte, so relay tasks can
+    // copy the DSCP marking onto what they send the client
+    preserve_dscp: bool,
 }
 
 impl AllocationManager {
     pub fn new(relay_addresses: Vec<SocketAddr>) -> Self {
+        Self::with_provider(Arc::new(RelayAddressPool::new(relay_addresses)))
+    }
+
+    pub fn with_provider(relay_address_provider: Arc<dyn RelayAddressProvider>) -> Self {
         AllocationManager {
-            allocations: Arc::new(Mutex::new(HashMap::new())),
-            relay_address_pool: Arc::new(Mutex::new(relay_addresses)),
+            allocations: Arc::new(ShardedMap::new(DEFAULT_SHARDS)),
+            pending: Arc::new(Mutex::new(Vec::new())),
+            relay_address_provider,
+            realm_quotas: HashMap::new(),
+            max_allocations_per_user: None,
+            admission_policy: Arc::new(AllowAll),
+            drained_addresses: Arc::new(Mutex::new(HashSet::new())),
+            reservations: Arc::new(ReservationStore::default()),
+            relay_recv_timeout: DEFAULT_RELAY_RECV_TIMEOUT,
+            address_wait_timeout: None,
+            relay_reader_sockets: 1,
+            preserve_dscp: false,
+            address_released: Arc::new(Notify::new()),
         }
     }
 
+    pub fn with_realm_quota(mut self, realm: String, max_allocations: usize) -> Self {
+        self.realm_quotas.insert(realm, max_allocations);
+        self
+    }
+
+    pub fn with_max_allocations_per_user(mut self, max_allocations: usize) -> Self {
+        self.max_allocations_per_user = Some(max_allocations);
+        self
+    }
+
+    pub fn with_admission_policy(mut self, admission_policy: Arc<dyn AdmissionPolicy>) -> Self {
+        self.admission_policy = admission_policy;
+        self
+    }
+
+    pub fn with_relay_recv_timeout(mut self, relay_recv_timeout: Duration) -> Self {
+        self.relay_recv_timeout = relay_recv_timeout;
+        self
+    }
+
+    pub fn relay_recv_timeout(&self) -> Duration {
+        self.relay_recv_timeout
+    }
+
+    // Relay tasks each allocation runs, one per reader socket
+    pub fn relay_reader_sockets(&self) -> usize {
+        self.relay_reader_sockets
+    }
+
+    pub fn with_relay_reader_sockets(mut self, relay_reader_sockets: usize) -> Self {
+        self.relay_reader_sockets = relay_reader_sockets.max(1);
+        self
+    }
+
+    pub fn with_preserve_dscp(mut self, preserve_dscp: bool) -> Self {
+        self.preserve_dscp = preserve_dscp;
+        self
+    }
+
+    pub fn preserve_dscp(&self) -> bool {
+        self.preserve_dscp
+    }
+
+    // How long a port reserved through EVEN-PORT waits for its
+    // RESERVATION-TOKEN before going back to the pool
+    pub fn with_reservation_lifetime(mut self, reservation_lifetime: Duration) -> Self {
+        self.reservations = Arc::new(ReservationStore::new(reservation_lifetime));
+        self
+    }
+
+    pub fn with_address_wait_timeout(mut self, address_wait_timeout: Duration) -> Self {
+        self.address_wait_timeout = Some(address_wait_timeout);
+        self
+    }
+
     pub async fn create_allocation(
         &self,
         username: String,
-        client_address: SocketAddr,
+        realm: String,
+        five_tuple: FiveTuple,
+        lifetime: Duration,
     ) -> Result<Allocation, TurnError> {
-        let relayed_address = {
-            let mut pool = self.relay_address_pool.lock().unwrap();
-            
-            if pool.is_empty() {
-                return Err(TurnError::InsufficientCapacity);
-            }
-            
-            pool.pop().unwrap()
-        };
+        self.create_allocation_with(username, realm, five_tuple, lifetime, RelayRequest::default()).await
+    }
+
+    pub async fn create_allocation_with(
+        &self,
+        username: String,
+        realm: String,
+        five_tuple: FiveTuple,
+        lifetime: Duration,
+        relay: RelayRequest,
+    ) -> Result<Allocation, TurnError> {
+        self.admission_policy.allow(&username, five_tuple.client_address, &realm).await?;
+        
+        let slot = self.reserve_slot(&username, &realm, five_tuple)?;
+        
+        let (relayed_address, reservation_token) = self.acquire_relay_address(relay).await?;
         
-        // Create UDP socket for relay
-        let relay_socket = match UdpSocket::bind(relayed_address).await {
-            Ok(socket) => Arc::new(socket),
+        // Create UDP sockets for relay
+        let mut relay_sockets = match self.bind_relay_sockets(relayed_address).await {
+            Ok(sockets) => sockets,
             Err(_) => {
-                // Return address to pool on failure
-                self.relay_address_pool.lock().unwrap().push(relayed_address);
+                // Return addresses to pool on failure
+                self.release_address(relayed_address);
+                if let Some(reserved) = reservation_token.and_then(|token| self.reservations.claim(&token)) {
+                    self.release_address(reserved);
+                }
                 return Err(TurnError::InsufficientCapacity);
             }
         };
         
-        let allocation = Allocation::new(
+        let relay_socket = relay_sockets.remove(0);
+        let mut allocation = Allocation::new(
             username,
             relayed_address,
-            client_address,
+            five_tuple.client_address,
             relay_socket,
         );
         
-        let mut allocations = self.allocations.lock().unwrap();
-        allocations.insert(client_address, allocation.clone());
+        allocation.relay_readers = relay_sockets;
+        allocation.transport = five_tuple.transport;
+        allocation.realm = realm;
+        allocation.reservation_token = reservation_token;
+        
+        // Requested lifetimes above the maximum are clamped, not rejected
+        allocation.lifetime = lifetime.min(MAX_ALLOCATION_LIFETIME);
+        
+        slot.insert(allocation.clone());
         
         Ok(allocation)
     }
 
-    pub fn get_allocation(&self, client_address: &SocketAddr) -> Option<Allocation> {
-        let allocations = self.allocations.lock().unwrap();
-        allocations.get(client_address).cloned()
+    fn reserve_slot(&self, username: &str, realm: &str, five_tuple: FiveTuple) -> Result<PendingSlot<'_>, TurnError> {
+        let mut pending = self.pending.lock().unwrap();
+        
+        // Expired is gone as far as the client is concerned, even before
+        // cleanup_expired sweeps it, so it is evicted rather than answered
+        // with a 437
+        if self.allocations.with(&five_tuple, Allocation::is_expired) == Some(true)
+            && let Some(expired) = self.allocations.remove(&five_tuple)
+        {
+            self.release_address(expired.relayed_address);
+            expired.relay_shutdown.stop(RelayStopReason::Expired);
+        }
+        
+        // A client gets one allocation per 5-tuple; a second Allocate,
+        // retransmitted or not, is a mismatch (RFC 5766 section 6.2)
+        if self.allocations.with(&five_tuple, |_| ()).is_some()
+            || pending.iter().any(|entry| entry.five_tuple == five_tuple)
+        {
+            return Err(TurnError::AllocationMismatch);
+        }
+        
+        if let Some(&max_allocations) = self.realm_quotas.get(realm) {
+            let in_realm = self.allocations.count_where(|a| a.realm == realm && !a.is_expired())
+                + pending.iter().filter(|entry| entry.realm == realm).count();
+            
+            if in_realm >= max_allocations {
+                return Err(TurnError::AllocationQuotaReached);
+            }
+        }
+        
+        // Counted across all of the user's client addresses. Removed and
+        // expired allocations free their slot, swept or not.
+        if let Some(max_allocations) = self.max_allocations_per_user {
+            let for_user = self.allocations.count_where(|a| a.username == username && !a.is_expired())
+                + pending.iter().filter(|entry| entry.username == username).count();
+            
+            if for_user >= max_allocations {
+                return Err(TurnError::AllocationQuotaReached);
+            }
+        }
+        
+        pending.push(PendingAllocation {
+            five_tuple,
+            username: username.to_string(),
+            realm: realm.to_string(),
+        });
+        
+        Ok(PendingSlot { manager: self, five_tuple })
     }
 
-    pub fn refresh_allocation(
+    // The first socket picks the port when relayed_address has port 0, and
+    // any others join it there
+    async fn bind_relay_sockets(&self, relayed_address: SocketAddr) -> std::io::Result<Vec<Arc<UdpSocket>>> {
+        let sockets = if self.relay_reader_sockets == 1 {
+            vec![Arc::new(UdpSocket::bind(relayed_address).await?)]
+        } else {
+            let first = bind_reuseport(relayed_address)?;
+            let bound_address = first.local_addr()?;
+            let mut sockets = vec![Arc::new(first)];
+            for _ in 1..self.relay_reader_sockets {
+                sockets.push(Arc::new(bind_reuseport(bound_address)?));
+            }
+            sockets
+        };
+        
+        if self.preserve_dscp {
+            for socket in &sockets {
+                set_receive_tos(socket)?;
+            }
+        }
+        Ok(sockets)
+    }
+
+    async fn acquire_relay_address(
         &self,
-        client_address: &SocketAddr,
-        lifetime: Duration,
-    ) -> Result<(), TurnError> {
-        let mut allocations = self.allocations.lock().unwrap();
+        relay: RelayRequest,
+    ) -> Result<(SocketAddr, Option<[u8; 8]>), TurnError> {
+        // A family with no relay addresses at all is 440 rather than 508
+        if !matches!(relay.port, RelayPortRequest::Reserved(_))
+            && !self.relay_address_provider.supports(relay.family)
+        {
+            return Err(TurnError::AddressFamilyNotSupported);
+        }
         
-        match allocations.get_mut(client_address) {
-            Some(allocation) => allocation.refresh(lifetime),
-            None => Err(TurnError::AllocationMismatch),
+        match relay.port {
+            RelayPortRequest::Any => self.acquire_or_wait(relay.family)
+                .await
+                .map(|address| (address, None))
+                .ok_or(TurnError::InsufficientCapacity),
+            RelayPortRequest::Even { reserve_next } => {
+                let (address, next) = self.relay_address_provider
+                    .acquire_even(relay.family, reserve_next)
+                    .await
+                    .ok_or(TurnError::InsufficientCapacity)?;
+                Ok((address, next.map(|next| self.reservations.reserve(next))))
+            }
+            // Unknown or expired tokens get 508, as for an exhausted pool
+            RelayPortRequest::Reserved(token) => self.reservations
+                .claim(&token)
+                .map(|address| (address, None))
+                .ok_or(TurnError::InsufficientCapacity),
         }
     }
 
-    pub fn remove_allocation(&self, client_address: &SocketAddr) -> Option<Allocation> {
-        let mut allocations = self.allocations.lock().unwrap();
+    async fn acquire_or_wait(&self, family: AddressFamily) -> Option<SocketAddr> {
+        let Some(address_wait_timeout) = self.address_wait_timeout else {
+            return self.relay_address_provider.acquire(family).await;
+        };
         
-        if let Some(allocation) = allocations.remove(client_address) {
+        let deadline = tokio::time::Instant::now() + address_wait_timeout;
+        loop {
+            // Registered before trying, so a release in between isn't missed
+            let released = self.address_released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            
+            if let Some(address) = self.relay_address_provider.acquire(family).await {
+                return Some(address);
+            }
+            
+            tokio::time::timeout_at(deadline, released).await.ok()?;
+        }
+    }
+
+    pub fn get_allocation(&self, five_tuple: &FiveTuple) -> Option<Allocation> {
+        self.allocations.with(five_tuple, Allocation::clone)
+    }
+
+    pub fn refresh_allocation(
+        &self,
+        five_tuple: &FiveTuple,
+        lifetime: Duration,
+    ) -> Result<Duration, TurnError> {
+        self.allocations
+            .with_mut(five_tuple, |allocation| {
+                if allocation.draining {
+                    return Err(TurnError::InsufficientCapacity);
+                }
+                allocation.refresh(lifetime);
+                Ok(allocation.lifetime)
+            })
+            .unwrap_or(Err(TurnError::AllocationMismatch))
+    }
+
+    pub fn remove_allocation(&self, five_tuple: &FiveTuple) -> Option<Allocation> {
+        if let Some(allocation) = self.allocations.remove(five_tuple) {
             // Return the relay address to the pool
-            let mut pool = self.relay_address_pool.lock().unwrap();
-            pool.push(allocation.relayed_address);
+            self.release_address(allocation.relayed_address);
+            allocation.relay_shutdown.stop(RelayStopReason::Deleted);
             Some(allocation)
         } else {
             None
         }
     }
 
+    // Runs f on the stored allocation under its shard's lock. get_allocation
+    // hands out a copy, so changes made to that are lost.
+    pub fn with_allocation_mut<R>(
+        &self,
+        five_tuple: &FiveTuple,
+        f: impl FnOnce(&mut Allocation) -> R,
+    ) -> Option<R> {
+        self.allocations.with_mut(five_tuple, f)
+    }
+
+    pub fn add_permission(&self, five_tuple: &FiveTuple, peer_address: SocketAddr) -> bool {
+        self.with_allocation_mut(five_tuple, |allocation| allocation.add_permission(peer_address))
+            .is_some()
+    }
+
+    pub fn add_channel_binding(
+        &self,
+        five_tuple: &FiveTuple,
+        channel_number: u16,
+        peer_address: SocketAddr,
+    ) -> Result<(), TurnError> {
+        self.with_allocation_mut(five_tuple, |allocation| {
+            allocation.add_channel_binding(channel_number, peer_address)
+        })
+        .unwrap_or(Err(TurnError::AllocationMismatch))
+    }
+
+    // Takes a relay address out of service: it is withdrawn from the free
+    // pool, allocations using it are flagged so they can't be refreshed, and
+    // it is not returned to the pool when they go away.
+    pub fn drain_address(&self, address: SocketAddr) {
+        self.drained_addresses.lock().unwrap().insert(address);
+        self.relay_address_provider.withdraw(address);
+
+        self.allocations.for_each_mut(|allocation| {
+            if allocation.relayed_address == address {
+                allocation.draining = true;
+            }
+        });
+    }
+
+    fn release_address(&self, address: SocketAddr) {
+        if !self.drained_addresses.lock().unwrap().contains(&address) {
+            self.relay_address_provider.release(address);
+            self.address_released.notify_waiters();
+        }
+    }
+
+    pub fn allocation_count(&self) -> usize {
+        self.allocations.len()
+    }
+
+    pub fn relay_errors(&self, five_tuple: &FiveTuple) -> Option<Vec<RelayError>> {
+        self.allocations.with(five_tuple, |allocation| allocation.relay_errors.recent())
+    }
+
+    pub fn revoke_permission(&self, five_tuple: &FiveTuple, peer_address: &SocketAddr) -> bool {
+        self.with_allocation_mut(five_tuple, |allocation| allocation.remove_permission(peer_address))
+            .unwrap_or(false)
+    }
+
+    pub fn revoke_channel(&self, five_tuple: &FiveTuple, channel_number: u16) -> Option<SocketAddr> {
+        // The peer's permission is left in place and expires on its own
+        self.with_allocation_mut(five_tuple, |allocation| allocation.remove_channel_binding(channel_number))
+            .flatten()
+    }
+
+    pub fn cleanup_expired_channels(&self) {
+        self.allocations.for_each_mut(Allocation::cleanup_expired_channels);
+    }
+
+    pub fn cleanup_expired_permissions(&self) {
+        self.allocations.for_each_mut(Allocation::cleanup_expired_permissions);
+    }
+
+    // Summed across allocations, for the server-wide gauges
+    pub fn permission_count(&self) -> usize {
+        self.allocations.sum_by(Allocation::permission_count)
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.allocations.sum_by(Allocation::channel_count)
+    }
+
+    // Removes every allocation and reservation for shutdown, returning
+    // their relay addresses to the pool. Returns how many allocations went.
+    pub fn remove_all(&self) -> usize {
+        for address in self.reservations.take_all() {
+            self.release_address(address);
+        }
+        
+        let removed = self.allocations.drain();
+        for allocation in &removed {
+            self.release_address(allocation.relayed_address);
+            allocation.relay_shutdown.stop(RelayStopReason::Shutdown);
+        }
+        removed.len()
+    }
+
     pub fn cleanup_expired(&self) {
-        let mut allocations = self.allocations.lock().unwrap();
-        let mut pool = self.relay_address_pool.lock().unwrap();
+        // Reserved ports nobody claimed go back to the pool
+        for address in self.reservations.take_expired() {
+            self.release_address(address);
+        }
         
-        allocations.retain(|_, allocation| {
+        self.allocations.retain(|_, allocation| {
             if allocation.is_expired() {
-                pool.push(allocation.relayed_address);
+                self.release_address(allocation.relayed_address);
+                allocation.relay_shutdown.stop(RelayStopReason::Expired);
                 false
             } else {
                 true
@@ -237,6 +842,15 @@ mod tests {
         assert!(allocation.has_permission(&peer_addr));
     }
 
+    #[test]
+    async fn test_relay_shutdown_keeps_first_reason() {
+        let shutdown = RelayShutdown::default();
+        shutdown.stop(RelayStopReason::Expired);
+        shutdown.stop(RelayStopReason::Deleted);
+
+        assert_eq!(shutdown.stopped().await, RelayStopReason::Expired);
+    }
+
     #[test]
     async fn test_channel_binding() {
         let client_addr: SocketAddr = "10.0.0.1:54321".parse().unwrap();
@@ -264,6 +878,79 @@ mod tests {
         assert!(allocation.add_channel_binding(0x3FFF, peer_addr).is_err());
     }
 
+    #[test]
+    async fn test_channel_binding_expires() {
+        let peer_addr: SocketAddr = "203.0.113.1:80".parse().unwrap();
+        let socket = create_test_socket("127.0.0.1:0".parse().unwrap()).await;
+        let mut allocation = Allocation::new(
+            "testuser".to_string(),
+            socket.local_addr().unwrap(),
+            "10.0.0.1:54321".parse().unwrap(),
+            socket,
+        );
+        let expired = Instant::now().checked_sub(CHANNEL_BINDING_LIFETIME).unwrap();
+
+        allocation.add_channel_binding(0x4000, peer_addr).unwrap();
+        allocation.channel_bindings.get_mut(&0x4000).unwrap().1 = expired;
+        assert!(allocation.get_peer_by_channel(0x4000).is_none());
+        assert!(allocation.get_channel_by_peer(&peer_addr).is_none());
+
+        // Binding again refreshes the timer
+        allocation.add_channel_binding(0x4000, peer_addr).unwrap();
+        assert_eq!(allocation.get_peer_by_channel(0x4000), Some(&peer_addr));
+
+        allocation.channel_bindings.get_mut(&0x4000).unwrap().1 = expired;
+        allocation.cleanup_expired_channels();
+        assert!(allocation.channel_bindings.is_empty());
+        assert!(allocation.channel_peers.is_empty());
+    }
+
+    #[test]
+    async fn test_channel_reverse_index() {
+        let peer_a: SocketAddr = "203.0.113.1:80".parse().unwrap();
+        let peer_b: SocketAddr = "203.0.113.2:80".parse().unwrap();
+        let socket = create_test_socket("127.0.0.1:0".parse().unwrap()).await;
+        let mut allocation = Allocation::new(
+            "testuser".to_string(),
+            socket.local_addr().unwrap(),
+            "10.0.0.1:54321".parse().unwrap(),
+            socket,
+        );
+
+        allocation.add_channel_binding(0x4001, peer_a).unwrap();
+        assert_eq!(allocation.get_channel_by_peer(&peer_a), Some(0x4001));
+
+        // The same pair again is a refresh
+        allocation.add_channel_binding(0x4001, peer_a).unwrap();
+
+        // Neither the channel nor the peer can move to a new partner
+        assert!(matches!(allocation.add_channel_binding(0x4001, peer_b), Err(TurnError::BadRequest)));
+        assert!(matches!(allocation.add_channel_binding(0x4002, peer_a), Err(TurnError::BadRequest)));
+        assert_eq!(allocation.get_channel_by_peer(&peer_a), Some(0x4001));
+        assert_eq!(allocation.get_channel_by_peer(&peer_b), None);
+        assert_eq!(allocation.get_peer_by_channel(0x4002), None);
+
+        assert_eq!(allocation.remove_channel_binding(0x4001), Some(peer_a));
+        assert_eq!(allocation.get_channel_by_peer(&peer_a), None);
+        allocation.add_channel_binding(0x4001, peer_b).unwrap();
+    }
+
+    #[test]
+    async fn test_relay_error_log_bounded() {
+        let log = RelayErrorLog::new(3);
+        for port in 1..=5 {
+            log.record(std::io::ErrorKind::ConnectionRefused, SocketAddr::from(([192, 0, 2, 1], port)));
+        }
+
+        let ports: Vec<u16> = log.recent().iter().map(|e| e.peer_address.port()).collect();
+        assert_eq!(ports, [3, 4, 5]);
+
+        // Shared by clones
+        log.clone().record(std::io::ErrorKind::InvalidInput, SocketAddr::from(([192, 0, 2, 1], 6)));
+        assert_eq!(log.recent().last().unwrap().peer_address.port(), 6);
+        assert_eq!(log.recent().len(), 3);
+    }
+
     #[test]
     async fn test_allocation_manager() {
         let relay_addresses = vec![
@@ -277,18 +964,663 @@ mod tests {
         // Create allocation
         let allocation = manager.create_allocation(
             "testuser".to_string(),
-            client_addr,
+            "example.com".to_string(),
+            FiveTuple::udp(client_addr),
+            DEFAULT_ALLOCATION_LIFETIME,
         ).await.unwrap();
         
         // Get allocation
-        let retrieved = manager.get_allocation(&client_addr).unwrap();
+        let retrieved = manager.get_allocation(&FiveTuple::udp(client_addr)).unwrap();
         assert_eq!(retrieved.username, allocation.username);
         
         // Remove allocation
-        let removed = manager.remove_allocation(&client_addr).unwrap();
+        let removed = manager.remove_allocation(&FiveTuple::udp(client_addr)).unwrap();
         assert_eq!(removed.username, allocation.username);
         
         // Should be gone
-        assert!(manager.get_allocation(&client_addr).is_none());
+        assert!(manager.get_allocation(&FiveTuple::udp(client_addr)).is_none());
+    }
+
+    #[test]
+    async fn test_manager_mutations_persist() {
+        let manager = AllocationManager::new(vec!["127.0.0.1:0".parse().unwrap()]);
+        let client_addr: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+        let peer_addr: SocketAddr = "203.0.113.1:80".parse().unwrap();
+        let channel_peer: SocketAddr = "203.0.113.2:80".parse().unwrap();
+
+        manager.create_allocation("testuser".to_string(), "example.com".to_string(), FiveTuple::udp(client_addr), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+
+        assert!(manager.add_permission(&FiveTuple::udp(client_addr), peer_addr));
+        manager.add_channel_binding(&FiveTuple::udp(client_addr), 0x4000, channel_peer).unwrap();
+
+        // A fresh copy reflects both changes
+        let allocation = manager.get_allocation(&FiveTuple::udp(client_addr)).unwrap();
+        assert!(allocation.has_permission(&peer_addr));
+        assert_eq!(allocation.get_peer_by_channel(0x4000), Some(&channel_peer));
+
+        // Invalid channel numbers are still rejected by the allocation
+        assert!(matches!(
+            manager.add_channel_binding(&FiveTuple::udp(client_addr), 0x3FFF, channel_peer),
+            Err(TurnError::BadRequest)
+        ));
+
+        // No allocation for this client
+        let other_client: SocketAddr = "10.0.0.2:54321".parse().unwrap();
+        assert!(!manager.add_permission(&FiveTuple::udp(other_client), peer_addr));
+        assert!(matches!(
+            manager.add_channel_binding(&FiveTuple::udp(other_client), 0x4000, channel_peer),
+            Err(TurnError::AllocationMismatch)
+        ));
+        assert!(manager.with_allocation_mut(&FiveTuple::udp(other_client), |_| ()).is_none());
+    }
+
+    #[test]
+    async fn test_revoke_permission() {
+        let relay_addresses = vec!["127.0.0.1:49210".parse().unwrap()];
+        let manager = AllocationManager::new(relay_addresses);
+        let client_addr: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+        let peer_addr: SocketAddr = "203.0.113.1:80".parse().unwrap();
+
+        manager.create_allocation("testuser".to_string(), "example.com".to_string(), FiveTuple::udp(client_addr), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        assert!(manager.add_permission(&FiveTuple::udp(client_addr), peer_addr));
+        assert!(manager.get_allocation(&FiveTuple::udp(client_addr)).unwrap().has_permission(&peer_addr));
+
+        assert!(manager.revoke_permission(&FiveTuple::udp(client_addr), &peer_addr));
+
+        // Relaying checks the stored allocation, so the peer is now denied
+        assert!(!manager.get_allocation(&FiveTuple::udp(client_addr)).unwrap().has_permission(&peer_addr));
+
+        // Revoking again is a no-op
+        assert!(!manager.revoke_permission(&FiveTuple::udp(client_addr), &peer_addr));
+    }
+
+    #[test]
+    async fn test_revoke_channel() {
+        let relay_addresses = vec!["127.0.0.1:49211".parse().unwrap()];
+        let manager = AllocationManager::new(relay_addresses);
+        let client_addr: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+        let peer_addr: SocketAddr = "203.0.113.1:80".parse().unwrap();
+
+        manager.create_allocation("testuser".to_string(), "example.com".to_string(), FiveTuple::udp(client_addr), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        manager.add_channel_binding(&FiveTuple::udp(client_addr), 0x4000, peer_addr).unwrap();
+
+        assert_eq!(manager.revoke_channel(&FiveTuple::udp(client_addr), 0x4000), Some(peer_addr));
+
+        let allocation = manager.get_allocation(&FiveTuple::udp(client_addr)).unwrap();
+        assert!(allocation.get_peer_by_channel(0x4000).is_none());
+        assert!(allocation.has_permission(&peer_addr));
+
+        // Unknown allocation
+        let other_client: SocketAddr = "10.0.0.2:54321".parse().unwrap();
+        assert!(manager.revoke_channel(&FiveTuple::udp(other_client), 0x4000).is_none());
+    }
+
+    #[test]
+    async fn test_create_allocation_clamps_lifetime() {
+        let relay_addresses = vec!["127.0.0.1:49212".parse().unwrap()];
+        let manager = AllocationManager::new(relay_addresses);
+        let client_addr: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+
+        let allocation = manager.create_allocation(
+            "testuser".to_string(),
+            "example.com".to_string(),
+            FiveTuple::udp(client_addr),
+            Duration::from_secs(7200),
+        ).await.unwrap();
+
+        assert_eq!(allocation.lifetime, MAX_ALLOCATION_LIFETIME);
+        assert_eq!(manager.get_allocation(&FiveTuple::udp(client_addr)).unwrap().lifetime, MAX_ALLOCATION_LIFETIME);
+    }
+
+    #[derive(Debug, Default)]
+    struct SequentialProvider {
+        next_port: Mutex<u16>,
+        released: Mutex<Vec<SocketAddr>>,
+    }
+
+    impl RelayAddressProvider for SequentialProvider {
+        fn acquire(&self, _family: AddressFamily) -> crate::turn::relay_address::AcquireFuture<'_> {
+            Box::pin(async move {
+                let mut next_port = self.next_port.lock().unwrap();
+                let addr = SocketAddr::from(([127, 0, 0, 1], 49220 + *next_port));
+                *next_port += 1;
+                Some(addr)
+            })
+        }
+
+        fn release(&self, address: SocketAddr) {
+            self.released.lock().unwrap().push(address);
+        }
+    }
+
+    #[test]
+    async fn test_custom_relay_address_provider() {
+        let provider = Arc::new(SequentialProvider::default());
+        let manager = AllocationManager::with_provider(provider.clone());
+        let client1: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+        let client2: SocketAddr = "10.0.0.2:54321".parse().unwrap();
+
+        let first = manager.create_allocation("alice".to_string(), "example.com".to_string(), FiveTuple::udp(client1), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        let second = manager.create_allocation("bob".to_string(), "example.com".to_string(), FiveTuple::udp(client2), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+
+        assert_eq!(first.relayed_address, "127.0.0.1:49220".parse().unwrap());
+        assert_eq!(second.relayed_address, "127.0.0.1:49221".parse().unwrap());
+
+        manager.remove_allocation(&FiveTuple::udp(client1));
+        assert_eq!(*provider.released.lock().unwrap(), vec![first.relayed_address]);
+    }
+
+    #[test]
+    async fn test_permission_and_channel_counts() {
+        let manager = AllocationManager::new(vec!["127.0.0.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()]);
+        let first = FiveTuple::udp("10.0.0.1:54321".parse().unwrap());
+        let second = FiveTuple::udp("10.0.0.2:54321".parse().unwrap());
+        let peer_a: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let peer_b: SocketAddr = "192.0.2.2:5000".parse().unwrap();
+
+        manager.create_allocation("alice".to_string(), "example.com".to_string(), first, DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        manager.create_allocation("bob".to_string(), "example.com".to_string(), second, DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        assert_eq!((manager.permission_count(), manager.channel_count()), (0, 0));
+
+        manager.add_permission(&first, peer_a);
+        manager.add_permission(&first, peer_b);
+        manager.add_permission(&second, peer_a);
+        manager.add_channel_binding(&first, 0x4000, peer_a).unwrap();
+        manager.add_channel_binding(&second, 0x4000, peer_a).unwrap();
+        assert_eq!((manager.permission_count(), manager.channel_count()), (3, 2));
+
+        // Expired entries stop counting straight away, before they are reaped
+        let expired = Instant::now().checked_sub(Duration::from_secs(900)).unwrap();
+        manager.with_allocation_mut(&first, |allocation| {
+            allocation.permissions.insert(peer_b, expired);
+            allocation.channel_bindings.get_mut(&0x4000).unwrap().1 = expired;
+        });
+        assert_eq!((manager.permission_count(), manager.channel_count()), (2, 1));
+
+        manager.cleanup_expired_permissions();
+        manager.cleanup_expired_channels();
+        assert_eq!((manager.permission_count(), manager.channel_count()), (2, 1));
+        let allocation = manager.get_allocation(&first).unwrap();
+        assert_eq!((allocation.permissions.len(), allocation.channel_bindings.len()), (1, 0));
+
+        // Removing an allocation takes its share with it
+        manager.remove_allocation(&second);
+        assert_eq!((manager.permission_count(), manager.channel_count()), (1, 0));
+    }
+
+    #[test]
+    async fn test_allocate_waits_for_released_address() {
+        let manager = Arc::new(
+            AllocationManager::new(vec!["127.0.0.1:0".parse().unwrap()])
+                .with_address_wait_timeout(Duration::from_secs(1)),
+        );
+        let first = FiveTuple::udp("10.0.0.1:54321".parse().unwrap());
+        let second = FiveTuple::udp("10.0.0.2:54321".parse().unwrap());
+
+        manager.create_allocation("alice".to_string(), "example.com".to_string(), first, DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+
+        let waiter = tokio::spawn({
+            let manager = manager.clone();
+            async move {
+                manager.create_allocation("bob".to_string(), "example.com".to_string(), second, DEFAULT_ALLOCATION_LIFETIME).await
+            }
+        });
+
+        // Still waiting while the pool is empty
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        manager.remove_allocation(&first);
+        let allocation = tokio::time::timeout(Duration::from_millis(500), waiter).await.unwrap().unwrap().unwrap();
+        assert_eq!(allocation.username, "bob");
+    }
+
+    #[test]
+    async fn test_allocate_wait_times_out() {
+        let manager = AllocationManager::new(vec!["127.0.0.1:0".parse().unwrap()])
+            .with_address_wait_timeout(Duration::from_millis(50));
+        let first = FiveTuple::udp("10.0.0.1:54321".parse().unwrap());
+        let second = FiveTuple::udp("10.0.0.2:54321".parse().unwrap());
+        manager.create_allocation("alice".to_string(), "example.com".to_string(), first, DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+
+        let started = Instant::now();
+        let result = manager.create_allocation("bob".to_string(), "example.com".to_string(), second, DEFAULT_ALLOCATION_LIFETIME).await;
+        assert!(matches!(result, Err(TurnError::InsufficientCapacity)));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    async fn test_remove_allocation_unbinds_relay_port() {
+        let relayed_addr: SocketAddr = "127.0.0.1:49213".parse().unwrap();
+        let manager = AllocationManager::new(vec![relayed_addr]);
+        let client_addr: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+
+        manager.create_allocation("testuser".to_string(), "example.com".to_string(), FiveTuple::udp(client_addr), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        assert!(UdpSocket::bind(relayed_addr).await.is_err());
+
+        drop(manager.remove_allocation(&FiveTuple::udp(client_addr)));
+
+        // No other handle keeps the relay socket alive
+        assert!(UdpSocket::bind(relayed_addr).await.is_ok());
+    }
+
+    #[test]
+    async fn test_udp_and_tcp_allocations_from_same_address_coexist() {
+        let manager = AllocationManager::new(vec![
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.1:0".parse().unwrap(),
+        ]);
+        let client_addr: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+        let udp = FiveTuple::udp(client_addr);
+        let tcp = FiveTuple::tcp(client_addr);
+
+        manager.create_allocation("alice".to_string(), "example.com".to_string(), udp, DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        let allocation = manager.create_allocation("alice".to_string(), "example.com".to_string(), tcp, DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        assert_eq!(allocation.five_tuple(), tcp);
+
+        assert_eq!(manager.get_allocation(&udp).unwrap().transport, Transport::Udp);
+        assert_eq!(manager.get_allocation(&tcp).unwrap().transport, Transport::Tcp);
+
+        // Removing one leaves the other
+        manager.remove_allocation(&tcp);
+        assert!(manager.get_allocation(&tcp).is_none());
+        assert!(manager.get_allocation(&udp).is_some());
+    }
+
+    #[test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_allocations_distinct_keys() {
+        const TASKS: usize = 64;
+        let manager = AllocationManager::new(vec!["127.0.0.1:0".parse().unwrap(); TASKS]);
+
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|i| {
+                let manager = manager.clone();
+                tokio::spawn(async move {
+                    let five_tuple = FiveTuple::udp(SocketAddr::from(([10, 0, 0, 1], 10000 + i as u16)));
+                    manager.create_allocation(format!("user{}", i), "example.com".to_string(), five_tuple, DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+
+                    for _ in 0..100 {
+                        assert!(manager.get_allocation(&five_tuple).is_some());
+                        manager.refresh_allocation(&five_tuple, DEFAULT_ALLOCATION_LIFETIME).unwrap();
+                        tokio::task::yield_now().await;
+                    }
+
+                    // Every other task leaves its allocation in place
+                    if i % 2 == 0 {
+                        assert!(manager.remove_allocation(&five_tuple).is_some());
+                    }
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(manager.allocation_count(), TASKS / 2);
+        assert_eq!(manager.remove_all(), TASKS / 2);
+        assert_eq!(manager.allocation_count(), 0);
+    }
+
+    #[test]
+    async fn test_cleanup_expired_unbinds_relay_port() {
+        let relayed_addr: SocketAddr = "127.0.0.1:49214".parse().unwrap();
+        let manager = AllocationManager::new(vec![relayed_addr]);
+        let client_addr: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+
+        manager.create_allocation("testuser".to_string(), "example.com".to_string(), FiveTuple::udp(client_addr), Duration::ZERO).await.unwrap();
+        manager.cleanup_expired();
+
+        assert!(manager.get_allocation(&FiveTuple::udp(client_addr)).is_none());
+        assert!(UdpSocket::bind(relayed_addr).await.is_ok());
+    }
+
+    #[test]
+    async fn test_realm_quota() {
+        let relay_addresses = vec![
+            "127.0.0.1:49215".parse().unwrap(),
+            "127.0.0.1:49216".parse().unwrap(),
+            "127.0.0.1:49217".parse().unwrap(),
+        ];
+        let manager = AllocationManager::new(relay_addresses)
+            .with_realm_quota("tenant-a.com".to_string(), 1)
+            .with_realm_quota("tenant-b.com".to_string(), 2);
+
+        let client1: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+        let client2: SocketAddr = "10.0.0.2:54321".parse().unwrap();
+        let client3: SocketAddr = "10.0.0.3:54321".parse().unwrap();
+
+        let allocation = manager.create_allocation("alice".to_string(), "tenant-a.com".to_string(), FiveTuple::udp(client1), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        assert_eq!(allocation.realm, "tenant-a.com");
+
+        // tenant-a is at its cap, even for a different user
+        let result = manager.create_allocation("bob".to_string(), "tenant-a.com".to_string(), FiveTuple::udp(client2), DEFAULT_ALLOCATION_LIFETIME).await;
+        assert!(matches!(result, Err(TurnError::AllocationQuotaReached)));
+
+        // tenant-b is unaffected
+        manager.create_allocation("bob".to_string(), "tenant-b.com".to_string(), FiveTuple::udp(client2), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        manager.create_allocation("carol".to_string(), "tenant-b.com".to_string(), FiveTuple::udp(client3), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+
+        // Freeing the tenant-a allocation makes room again
+        drop(allocation);
+        manager.remove_allocation(&FiveTuple::udp(client1));
+        let client4: SocketAddr = "10.0.0.4:54321".parse().unwrap();
+        assert!(manager.create_allocation("bob".to_string(), "tenant-a.com".to_string(), FiveTuple::udp(client4), DEFAULT_ALLOCATION_LIFETIME).await.is_ok());
+    }
+
+    #[test]
+    async fn test_per_user_quota() {
+        let manager = AllocationManager::new(vec![
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.1:0".parse().unwrap(),
+        ])
+        .with_max_allocations_per_user(2);
+
+        let client1: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+        let client2: SocketAddr = "10.0.0.2:54321".parse().unwrap();
+        let client3: SocketAddr = "10.0.0.3:54321".parse().unwrap();
+
+        manager.create_allocation("alice".to_string(), "example.com".to_string(), FiveTuple::udp(client1), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        manager.create_allocation("alice".to_string(), "example.com".to_string(), FiveTuple::udp(client2), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+
+        // Third allocation for alice, from yet another address
+        let result = manager.create_allocation("alice".to_string(), "example.com".to_string(), FiveTuple::udp(client3), DEFAULT_ALLOCATION_LIFETIME).await;
+        assert!(matches!(result, Err(TurnError::AllocationQuotaReached)));
+        assert_eq!(result.unwrap_err().error_code(), 486);
+
+        // Other users are unaffected
+        manager.create_allocation("bob".to_string(), "example.com".to_string(), FiveTuple::udp(client3), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+
+        // Removal frees a slot
+        manager.remove_allocation(&FiveTuple::udp(client1));
+        manager.create_allocation("alice".to_string(), "example.com".to_string(), FiveTuple::udp(client1), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+
+        // So does expiry
+        manager.with_allocation_mut(&FiveTuple::udp(client2), |allocation| allocation.lifetime = Duration::ZERO);
+        manager.cleanup_expired();
+        manager.create_allocation("alice".to_string(), "example.com".to_string(), FiveTuple::udp(client2), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+    }
+
+    #[test]
+    async fn test_expired_allocation_frees_slot_before_sweep() {
+        let manager = AllocationManager::new(vec![
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.1:0".parse().unwrap(),
+        ])
+        .with_max_allocations_per_user(1)
+        .with_realm_quota("example.com".to_string(), 1);
+        let client1 = FiveTuple::udp("10.0.0.1:54321".parse().unwrap());
+        let client2 = FiveTuple::udp("10.0.0.2:54321".parse().unwrap());
+
+        let expired = manager.create_allocation("alice".to_string(), "example.com".to_string(), client1, DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        manager.with_allocation_mut(&client1, |allocation| allocation.lifetime = Duration::ZERO);
+
+        // Neither quota counts it, though cleanup_expired hasn't run
+        manager.create_allocation("alice".to_string(), "example.com".to_string(), client2, DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        manager.remove_allocation(&client2);
+
+        // Allocating again on its 5-tuple replaces it instead of a 437
+        let replacement = manager.create_allocation("alice".to_string(), "example.com".to_string(), client1, DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        assert_ne!(replacement.id, expired.id);
+        assert_eq!(manager.get_allocation(&client1).unwrap().id, replacement.id);
+        assert_eq!(manager.allocation_count(), 1);
+        assert_eq!(expired.relay_shutdown.stopped().await, RelayStopReason::Expired);
+    }
+
+    // Yields before handing out an address, so concurrent Allocates all get
+    // past their quota checks before any of them inserts
+    #[derive(Debug)]
+    struct YieldingProvider(RelayAddressPool);
+
+    impl RelayAddressProvider for YieldingProvider {
+        fn acquire(&self, family: AddressFamily) -> crate::turn::relay_address::AcquireFuture<'_> {
+            Box::pin(async move {
+                tokio::task::yield_now().await;
+                self.0.acquire(family).await
+            })
+        }
+
+        fn release(&self, address: SocketAddr) {
+            self.0.release(address);
+        }
+    }
+
+    #[test]
+    async fn test_per_user_quota_concurrent() {
+        let pool = RelayAddressPool::new(vec!["127.0.0.1:0".parse().unwrap(); 8]);
+        let manager = Arc::new(AllocationManager::with_provider(Arc::new(YieldingProvider(pool))).with_max_allocations_per_user(2));
+
+        let tasks: Vec<_> = (0..8u16)
+            .map(|i| {
+                let manager = manager.clone();
+                let five_tuple = FiveTuple::udp(SocketAddr::from(([10, 0, 0, 1], 50000 + i)));
+                tokio::spawn(async move {
+                    manager.create_allocation("alice".to_string(), "example.com".to_string(), five_tuple, DEFAULT_ALLOCATION_LIFETIME).await
+                })
+            })
+            .collect();
+
+        let mut created = 0;
+        for task in tasks {
+            match task.await.unwrap() {
+                Ok(_) => created += 1,
+                Err(e) => assert!(matches!(e, TurnError::AllocationQuotaReached)),
+            }
+        }
+        assert_eq!(created, 2);
+        assert_eq!(manager.allocation_count(), 2);
+    }
+
+    #[test]
+    async fn test_realm_quota_concurrent() {
+        let pool = RelayAddressPool::new(vec!["127.0.0.1:0".parse().unwrap(); 8]);
+        let manager = Arc::new(AllocationManager::with_provider(Arc::new(YieldingProvider(pool))).with_realm_quota("tenant-a.com".to_string(), 3));
+
+        // A different user per request, so only the realm quota applies
+        let tasks: Vec<_> = (0..8u16)
+            .map(|i| {
+                let manager = manager.clone();
+                let five_tuple = FiveTuple::udp(SocketAddr::from(([10, 0, 0, 1], 50000 + i)));
+                tokio::spawn(async move {
+                    manager.create_allocation(format!("user{i}"), "tenant-a.com".to_string(), five_tuple, DEFAULT_ALLOCATION_LIFETIME).await
+                })
+            })
+            .collect();
+
+        let mut created = 0;
+        for task in tasks {
+            match task.await.unwrap() {
+                Ok(_) => created += 1,
+                Err(e) => assert!(matches!(e, TurnError::AllocationQuotaReached)),
+            }
+        }
+        assert_eq!(created, 3);
+        assert_eq!(manager.allocation_count(), 3);
+    }
+
+    #[test]
+    async fn test_drain_address() {
+        let spare: SocketAddr = "127.0.0.1:49240".parse().unwrap();
+        let drained: SocketAddr = "127.0.0.1:49241".parse().unwrap();
+        let manager = AllocationManager::new(vec![spare, drained]);
+        let client1: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+        let client2: SocketAddr = "10.0.0.2:54321".parse().unwrap();
+        let client3: SocketAddr = "10.0.0.3:54321".parse().unwrap();
+
+        let allocation = manager.create_allocation("alice".to_string(), "example.com".to_string(), FiveTuple::udp(client1), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        assert_eq!(allocation.relayed_address, drained);
+        drop(allocation);
+
+        manager.drain_address(drained);
+
+        // The existing allocation keeps working but can't be refreshed
+        assert!(manager.get_allocation(&FiveTuple::udp(client1)).unwrap().draining);
+        assert!(matches!(
+            manager.refresh_allocation(&FiveTuple::udp(client1), DEFAULT_ALLOCATION_LIFETIME),
+            Err(TurnError::InsufficientCapacity)
+        ));
+
+        // New allocations avoid it
+        let allocation = manager.create_allocation("bob".to_string(), "example.com".to_string(), FiveTuple::udp(client2), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        assert_eq!(allocation.relayed_address, spare);
+        assert!(!allocation.draining);
+
+        // Nor does it come back once its allocation is gone
+        manager.remove_allocation(&FiveTuple::udp(client1));
+        let result = manager.create_allocation("carol".to_string(), "example.com".to_string(), FiveTuple::udp(client3), DEFAULT_ALLOCATION_LIFETIME).await;
+        assert!(matches!(result, Err(TurnError::InsufficientCapacity)));
+    }
+
+    #[test]
+    async fn test_even_port_allocation() {
+        let manager = AllocationManager::new(vec![
+            "127.0.0.1:49250".parse().unwrap(),
+            "127.0.0.1:49251".parse().unwrap(),
+            "127.0.0.1:49253".parse().unwrap(),
+        ]);
+        let client_addr: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+
+        let allocation = manager.create_allocation_with(
+            "alice".to_string(),
+            "example.com".to_string(),
+            FiveTuple::udp(client_addr),
+            DEFAULT_ALLOCATION_LIFETIME,
+            RelayRequest { port: RelayPortRequest::Even { reserve_next: false }, ..Default::default() },
+        ).await.unwrap();
+        assert_eq!(allocation.relayed_address.port() % 2, 0);
+        assert!(allocation.reservation_token.is_none());
+
+        // Only odd ports remain
+        let other_client: SocketAddr = "10.0.0.2:54321".parse().unwrap();
+        let result = manager.create_allocation_with(
+            "bob".to_string(),
+            "example.com".to_string(),
+            FiveTuple::udp(other_client),
+            DEFAULT_ALLOCATION_LIFETIME,
+            RelayRequest { port: RelayPortRequest::Even { reserve_next: false }, ..Default::default() },
+        ).await;
+        assert!(matches!(result, Err(TurnError::InsufficientCapacity)));
     }
-}
\ No newline at end of file
+
+    #[test]
+    async fn test_claim_reserved_port() {
+        let manager = AllocationManager::new(vec![
+            "127.0.0.1:49260".parse().unwrap(),
+            "127.0.0.1:49261".parse().unwrap(),
+            "127.0.0.1:49262".parse().unwrap(),
+        ]);
+        let client1: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+        let client2: SocketAddr = "10.0.0.2:54321".parse().unwrap();
+        let client3: SocketAddr = "10.0.0.3:54321".parse().unwrap();
+
+        let allocation = manager.create_allocation_with(
+            "alice".to_string(),
+            "example.com".to_string(),
+            FiveTuple::udp(client1),
+            DEFAULT_ALLOCATION_LIFETIME,
+            RelayRequest { port: RelayPortRequest::Even { reserve_next: true }, ..Default::default() },
+        ).await.unwrap();
+        assert_eq!(allocation.relayed_address, "127.0.0.1:49260".parse().unwrap());
+        let token = allocation.reservation_token.unwrap();
+
+        // The reserved port is not handed out to ordinary requests
+        let other = manager.create_allocation("bob".to_string(), "example.com".to_string(), FiveTuple::udp(client2), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        assert_eq!(other.relayed_address, "127.0.0.1:49262".parse().unwrap());
+
+        let claimed = manager.create_allocation_with(
+            "alice".to_string(),
+            "example.com".to_string(),
+            FiveTuple::udp(client3),
+            DEFAULT_ALLOCATION_LIFETIME,
+            RelayRequest { port: RelayPortRequest::Reserved(token), ..Default::default() },
+        ).await.unwrap();
+        assert_eq!(claimed.relayed_address, "127.0.0.1:49261".parse().unwrap());
+
+        // A token can only be used once
+        let client4: SocketAddr = "10.0.0.4:54321".parse().unwrap();
+        let result = manager.create_allocation_with(
+            "alice".to_string(),
+            "example.com".to_string(),
+            FiveTuple::udp(client4),
+            DEFAULT_ALLOCATION_LIFETIME,
+            RelayRequest { port: RelayPortRequest::Reserved(token), ..Default::default() },
+        ).await;
+        assert!(matches!(result, Err(TurnError::InsufficientCapacity)));
+    }
+
+    #[test]
+    async fn test_reservation_lifetime() {
+        let manager = AllocationManager::new(vec![
+            "127.0.0.1:49270".parse().unwrap(),
+            "127.0.0.1:49271".parse().unwrap(),
+            "127.0.0.1:49272".parse().unwrap(),
+            "127.0.0.1:49273".parse().unwrap(),
+        ])
+        .with_reservation_lifetime(Duration::from_millis(50));
+        let reserve = |client: &str| {
+            manager.create_allocation_with(
+                "alice".to_string(),
+                "example.com".to_string(),
+                FiveTuple::udp(client.parse().unwrap()),
+                DEFAULT_ALLOCATION_LIFETIME,
+                RelayRequest { port: RelayPortRequest::Even { reserve_next: true }, ..Default::default() },
+            )
+        };
+        let redeem = |client: &str, token| {
+            manager.create_allocation_with(
+                "alice".to_string(),
+                "example.com".to_string(),
+                FiveTuple::udp(client.parse().unwrap()),
+                DEFAULT_ALLOCATION_LIFETIME,
+                RelayRequest { port: RelayPortRequest::Reserved(token), ..Default::default() },
+            )
+        };
+
+        let next_port = |allocation: &Allocation| {
+            let mut next = allocation.relayed_address;
+            next.set_port(next.port() + 1);
+            next
+        };
+
+        // Redeemed within the window
+        let allocation = reserve("10.0.0.1:54321").await.unwrap();
+        let claimed = redeem("10.0.0.2:54321", allocation.reservation_token.unwrap()).await.unwrap();
+        assert_eq!(claimed.relayed_address, next_port(&allocation));
+
+        // Redeemed too late
+        let allocation = reserve("10.0.0.3:54321").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let result = redeem("10.0.0.4:54321", allocation.reservation_token.unwrap()).await;
+        assert!(matches!(result, Err(TurnError::InsufficientCapacity)));
+
+        // Cleanup hands the reserved port, the last free one, out again
+        manager.cleanup_expired();
+        let other = manager.create_allocation("bob".to_string(), "example.com".to_string(), FiveTuple::udp("10.0.0.5:54321".parse().unwrap()), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        assert_eq!(other.relayed_address, next_port(&allocation));
+    }
+
+    #[test]
+    async fn test_refresh_keeps_relay_socket() {
+        let relay_addresses = vec!["127.0.0.1:49218".parse().unwrap()];
+        let manager = AllocationManager::new(relay_addresses);
+        let client_addr: SocketAddr = "10.0.0.1:54321".parse().unwrap();
+
+        let allocation = manager.create_allocation("testuser".to_string(), "example.com".to_string(), FiveTuple::udp(client_addr), DEFAULT_ALLOCATION_LIFETIME).await.unwrap();
+        manager.refresh_allocation(&FiveTuple::udp(client_addr), Duration::from_secs(300)).unwrap();
+
+        let refreshed = manager.get_allocation(&FiveTuple::udp(client_addr)).unwrap();
+        assert_eq!(refreshed.relayed_address, allocation.relayed_address);
+        assert!(Arc::ptr_eq(&refreshed.relay_socket, &allocation.relay_socket));
+        assert_eq!(refreshed.relay_socket.local_addr().unwrap(), allocation.relayed_address);
+
+        // The relay socket still carries traffic after the refresh
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"ping", allocation.relayed_address).await.unwrap();
+        let mut buf = [0u8; 16];
+        let (len, from) = refreshed.relay_socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from, peer.local_addr().unwrap());
+    }
+}
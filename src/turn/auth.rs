@@ -1,27 +1,95 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use rand::{thread_rng, Rng};
+use crate::stun::auth::Credentials;
 use crate::turn::error::TurnError;
 
+// Server-wide cap on new nonces, counted over one-second windows
+#[derive(Debug, Clone)]
+struct NonceRateLimit {
+    max_per_second: u32,
+    window_start: Instant,
+    issued: u32,
+}
+
+// Produces nonce values; swappable so tests get a deterministic sequence
+// and FIPS deployments can use an approved RNG
+#[derive(Clone)]
+struct NonceGenerator(Arc<dyn Fn() -> String + Send + Sync>);
+
+impl fmt::Debug for NonceGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("NonceGenerator")
+    }
+}
+
+fn random_nonce() -> String {
+    let mut rng = thread_rng();
+    (0..16)
+        .map(|_| format!("{:02x}", rng.r#gen::<u8>()))
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct NonceManager {
     nonces: HashMap<String, Instant>,
     lifetime: Duration,
+    rate_limit: Option<NonceRateLimit>,
+    generator: NonceGenerator,
 }
 
 impl NonceManager {
     pub fn new(lifetime: Duration) -> Self {
+        Self::with_generator(lifetime, random_nonce)
+    }
+
+    pub fn with_generator(
+        lifetime: Duration,
+        generator: impl Fn() -> String + Send + Sync + 'static,
+    ) -> Self {
         NonceManager {
             nonces: HashMap::new(),
             lifetime,
+            rate_limit: None,
+            generator: NonceGenerator(Arc::new(generator)),
         }
     }
 
+    // With a limit the map holds at most max_per_second times the lifetime
+    // in seconds, however many sources the challenges are spread across
+    pub fn with_rate_limit(mut self, max_per_second: u32) -> Self {
+        self.rate_limit = Some(NonceRateLimit {
+            max_per_second,
+            window_start: Instant::now(),
+            issued: 0,
+        });
+        self
+    }
+
+    // Like generate_nonce, but None once this second's allowance is used up
+    pub fn try_generate_nonce(&mut self) -> Option<String> {
+        if let Some(rate_limit) = &mut self.rate_limit {
+            if rate_limit.window_start.elapsed() >= Duration::from_secs(1) {
+                rate_limit.window_start = Instant::now();
+                rate_limit.issued = 0;
+            }
+            if rate_limit.issued >= rate_limit.max_per_second {
+                return None;
+            }
+            rate_limit.issued += 1;
+        }
+        
+        Some(self.generate_nonce())
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.nonces.len()
+    }
+
     pub fn generate_nonce(&mut self) -> String {
-        let mut rng = thread_rng();
-        let nonce: String = (0..16)
-            .map(|_| format!("{:02x}", rng.r#gen::<u8>()))
-            .collect();
+        let nonce = (self.generator.0)();
         
         self.nonces.insert(nonce.clone(), Instant::now());
         nonce
@@ -82,6 +150,26 @@ impl Default for UserDatabase {
     }
 }
 
+// The key the server checks long-term credential MESSAGE-INTEGRITY with, so
+// provisioning tools can store keys instead of passwords. The password is
+// expected to already be SASLprep'd.
+pub fn compute_long_term_key(username: &str, realm: &str, password: &str) -> [u8; 16] {
+    let credentials = Credentials::new(username.to_string(), password.to_string(), realm.to_string());
+    credentials.compute_key().try_into().expect("MD5 digests are 16 bytes")
+}
+
+// USERNAME must be under 513 bytes (RFC 5389 section 15.3)
+pub const MAX_USERNAME_LEN: usize = 512;
+
+// Over-long and NUL-containing usernames are a 400 before any key lookup or
+// hashing. Invalid UTF-8 is left to fail authentication as before.
+pub fn decode_username(value: Vec<u8>) -> Result<Option<String>, TurnError> {
+    if value.len() > MAX_USERNAME_LEN || value.contains(&0) {
+        return Err(TurnError::BadRequest);
+    }
+    Ok(String::from_utf8(value).ok())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -97,6 +185,19 @@ mod tests {
         assert_eq!(nonce1.len(), 32); // 16 bytes * 2 hex chars
     }
 
+    #[test]
+    fn test_injected_nonce_generator() {
+        let counter = std::sync::atomic::AtomicU32::new(0);
+        let mut nonce_mgr = NonceManager::with_generator(Duration::from_secs(300), move || {
+            format!("nonce-{}", counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed))
+        });
+        
+        assert_eq!(nonce_mgr.generate_nonce(), "nonce-0");
+        assert_eq!(nonce_mgr.generate_nonce(), "nonce-1");
+        assert_eq!(nonce_mgr.try_generate_nonce().as_deref(), Some("nonce-2"));
+        assert!(nonce_mgr.validate_nonce("nonce-1").is_ok());
+    }
+
     #[test]
     fn test_nonce_validation() {
         let mut nonce_mgr = NonceManager::new(Duration::from_secs(300));
@@ -110,6 +211,23 @@ mod tests {
         assert!(nonce_mgr.validate_nonce("unknown").is_err());
     }
 
+    #[test]
+    fn test_nonce_rate_limit() {
+        let mut nonce_mgr = NonceManager::new(Duration::from_secs(300)).with_rate_limit(3);
+        
+        for _ in 0..3 {
+            assert!(nonce_mgr.try_generate_nonce().is_some());
+        }
+        assert!(nonce_mgr.try_generate_nonce().is_none());
+        assert_eq!(nonce_mgr.outstanding(), 3);
+        
+        // Unlimited without a rate limit
+        let mut nonce_mgr = NonceManager::new(Duration::from_secs(300));
+        for _ in 0..10 {
+            assert!(nonce_mgr.try_generate_nonce().is_some());
+        }
+    }
+
     #[test]
     fn test_nonce_expiration() {
         let mut nonce_mgr = NonceManager::new(Duration::from_millis(100));
@@ -143,4 +261,24 @@ mod tests {
         assert!(!db.authenticate("alice", "wrongpassword"));
         assert!(!db.authenticate("charlie", "anypassword"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_compute_long_term_key_matches_credentials() {
+        let credentials = Credentials::new("alice".to_string(), "password123".to_string(), "example.org".to_string());
+        let key = compute_long_term_key("alice", "example.org", "password123");
+        assert_eq!(key.to_vec(), credentials.compute_key());
+
+        // RFC 5769 section 2.4 sample credentials
+        let key = compute_long_term_key("\u{30DE}\u{30C8}\u{30EA}\u{30C3}\u{30AF}\u{30B9}", "example.org", "TheMatrIX");
+        assert_eq!(hex::encode(key), "e8ca7ad59d5eb0518e312911d2dab2a9");
+    }
+
+    #[test]
+    fn test_decode_username() {
+        assert_eq!(decode_username(b"alice".to_vec()).unwrap(), Some("alice".to_string()));
+        assert_eq!(decode_username(vec![b'a'; MAX_USERNAME_LEN]).unwrap().map(|name| name.len()), Some(MAX_USERNAME_LEN));
+
+        assert!(matches!(decode_username(vec![b'a'; MAX_USERNAME_LEN + 1]), Err(TurnError::BadRequest)));
+        assert!(matches!(decode_username(b"ali\0ce".to_vec()), Err(TurnError::BadRequest)));
+    }
+}
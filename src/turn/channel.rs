@@ -1,8 +1,10 @@
 use std::net::SocketAddr;
 use crate::stun::{
-    message::{Message, MessageClass, MessageMethod},
-    attributes::{RawAttribute, AttributeType},
+    message::{IntoStunMessage, Message, MessageClass, MessageMethod, MessageType},
+    attributes::{encode_error_code, RawAttribute, AttributeType},
+    xor_addr::{decode_xor_address, unsupported_family},
 };
+use crate::turn::auth::decode_username;
 use crate::turn::error::TurnError;
 
 #[derive(Debug, Clone)]
@@ -36,26 +38,25 @@ impl ChannelBindRequest {
         let mut found_peer = false;
 
         // Parse attributes
-        let mut offset = 0;
-        while offset < message.attributes.len() {
-            let (attr, consumed) = RawAttribute::parse(&message.attributes[offset..])?;
-            offset += consumed;
+        for attr in message.attributes_iter() {
+            let attr = attr?;
 
             match AttributeType::from_u16(attr.attribute_type) {
-                Some(AttributeType::ChannelNumber) => {
-                    if attr.value.len() >= 4 {
-                        request.channel_number = u16::from_be_bytes([attr.value[0], attr.value[1]]);
-                        found_channel = true;
-                    }
+                Some(AttributeType::ChannelNumber) if attr.value.len() >= 4 => {
+                    request.channel_number = u16::from_be_bytes([attr.value[0], attr.value[1]]);
+                    found_channel = true;
                 }
                 Some(AttributeType::XorPeerAddress) => {
-                    if let Some(addr) = parse_xor_peer_address(&attr.value, &message.transaction_id) {
+                    if let Some(family) = unsupported_family(&attr.value) {
+                        return Err(TurnError::UnsupportedAddressFamily(family));
+                    }
+                    if let Some(addr) = decode_xor_address(&attr.value, &message.transaction_id) {
                         request.peer_address = addr;
                         found_peer = true;
                     }
                 }
                 Some(AttributeType::Username) => {
-                    request.username = String::from_utf8(attr.value).ok();
+                    request.username = decode_username(attr.value)?;
                 }
                 Some(AttributeType::Realm) => {
                     request.realm = String::from_utf8(attr.value).ok();
@@ -157,6 +158,20 @@ impl ChannelData {
         })
     }
 
+    // A UDP datagram carries exactly one frame. Padding is optional over UDP
+    // (RFC 5766 section 11.5), but anything beyond it is rejected.
+    pub fn parse_datagram(data: &[u8]) -> Result<Self, TurnError> {
+        let channel_data = Self::parse(data)?;
+
+        let unpadded = 4 + channel_data.data.len();
+        let padded = unpadded.next_multiple_of(4);
+        if data.len() != unpadded && data.len() != padded {
+            return Err(TurnError::BadRequest);
+        }
+
+        Ok(channel_data)
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         let mut result = Vec::new();
         
@@ -177,56 +192,38 @@ impl ChannelData {
     }
 }
 
-fn parse_xor_peer_address(data: &[u8], transaction_id: &[u8; 12]) -> Option<SocketAddr> {
-    if data.len() < 8 {
-        return None;
-    }
+impl IntoStunMessage for ChannelBindResponse {
+    fn to_message(&self) -> Message {
+        let class = if self.error_code.is_some() {
+            MessageClass::ErrorResponse
+        } else {
+            MessageClass::SuccessResponse
+        };
 
-    let family = data[1];
-    let xor_port = u16::from_be_bytes([data[2], data[3]]);
-    
-    // XOR with magic cookie for port
-    let port = xor_port ^ (crate::stun::message::MAGIC_COOKIE >> 16) as u16;
+        let mut message = Message::new(MessageType::new(MessageMethod::ChannelBind, class));
+        message.transaction_id = self.transaction_id;
 
-    match family {
-        0x01 => { // IPv4
-            if data.len() < 8 {
-                return None;
-            }
-            
-            let xor_ip = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
-            let ip = xor_ip ^ crate::stun::message::MAGIC_COOKIE;
-            
-            let ip_addr = std::net::Ipv4Addr::from(ip);
-            Some(SocketAddr::from((ip_addr, port)))
+        if let Some((code, reason)) = &self.error_code {
+            message.push_attribute(encode_error_code(*code, reason));
         }
-        0x02 => { // IPv6
-            if data.len() < 20 {
-                return None;
-            }
-            
-            let mut ip_bytes = [0u8; 16];
-            ip_bytes.copy_from_slice(&data[4..20]);
-            
-            // XOR with magic cookie and transaction ID
-            for (i, byte) in ip_bytes.iter_mut().enumerate().take(4) {
-                *byte ^= (crate::stun::message::MAGIC_COOKIE >> (24 - i * 8)) as u8;
-            }
-            for (i, byte) in ip_bytes.iter_mut().enumerate().skip(4).take(12) {
-                *byte ^= transaction_id[i - 4];
-            }
-            
-            let ip_addr = std::net::Ipv6Addr::from(ip_bytes);
-            Some(SocketAddr::from((ip_addr, port)))
+
+        if let Some(realm) = &self.realm {
+            message.push_attribute(RawAttribute::new(AttributeType::Realm as u16, realm.as_bytes().to_vec()));
+        }
+
+        if let Some(nonce) = &self.nonce {
+            message.push_attribute(RawAttribute::new(AttributeType::Nonce as u16, nonce.clone()));
         }
-        _ => None,
+
+
+        message
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::stun::message::MessageType;
+    use crate::stun::xor_addr::encode_xor_address;
 
     fn create_channel_bind_request_message(channel: u16, peer: SocketAddr, transaction_id: [u8; 12]) -> Message {
         let mut message = Message::new(MessageType::new(
@@ -245,7 +242,7 @@ mod tests {
         attrs.extend(channel_attr.serialize());
         
         // Add XOR-PEER-ADDRESS
-        let peer_attr = create_xor_peer_address_attr(peer, &transaction_id);
+        let peer_attr = RawAttribute::new(AttributeType::XorPeerAddress as u16, encode_xor_address(peer, &transaction_id));
         attrs.extend(peer_attr.serialize());
         
         message.attributes = attrs;
@@ -253,54 +250,6 @@ mod tests {
         message
     }
 
-    fn create_xor_peer_address_attr(addr: SocketAddr, transaction_id: &[u8; 12]) -> RawAttribute {
-        let mut data = Vec::new();
-        
-        // Padding
-        data.push(0);
-        
-        match addr {
-            SocketAddr::V4(v4) => {
-                // Family
-                data.push(0x01);
-                
-                // XOR Port
-                let xor_port = addr.port() ^ (crate::stun::message::MAGIC_COOKIE >> 16) as u16;
-                data.extend_from_slice(&xor_port.to_be_bytes());
-                
-                // XOR IP
-                let ip_bytes = v4.ip().octets();
-                let ip = u32::from_be_bytes(ip_bytes);
-                let xor_ip = ip ^ crate::stun::message::MAGIC_COOKIE;
-                data.extend_from_slice(&xor_ip.to_be_bytes());
-            }
-            SocketAddr::V6(v6) => {
-                // Family
-                data.push(0x02);
-                
-                // XOR Port
-                let xor_port = addr.port() ^ (crate::stun::message::MAGIC_COOKIE >> 16) as u16;
-                data.extend_from_slice(&xor_port.to_be_bytes());
-                
-                // XOR IPv6
-                let mut ip_bytes = v6.ip().octets();
-                
-                // XOR with magic cookie
-                for (i, byte) in ip_bytes.iter_mut().enumerate().take(4) {
-                    *byte ^= (crate::stun::message::MAGIC_COOKIE >> (24 - i * 8)) as u8;
-                }
-                // XOR with transaction ID
-                for (i, byte) in ip_bytes.iter_mut().enumerate().skip(4).take(12) {
-                    *byte ^= transaction_id[i - 4];
-                }
-                
-                data.extend_from_slice(&ip_bytes);
-            }
-        }
-        
-        RawAttribute::new(AttributeType::XorPeerAddress as u16, data)
-    }
-
     #[test]
     fn test_parse_channel_bind_request() {
         let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
@@ -371,6 +320,26 @@ mod tests {
         assert_eq!(parsed.data, data);
     }
 
+    #[test]
+    fn test_channel_data_datagram_trailing_bytes() {
+        let serialized = ChannelData::new(0x4002, b"Test data".to_vec()).unwrap().serialize();
+
+        // Clean frame, with and without padding
+        assert_eq!(ChannelData::parse_datagram(&serialized).unwrap().data, b"Test data");
+        assert_eq!(ChannelData::parse_datagram(&serialized[..4 + 9]).unwrap().data, b"Test data");
+
+        // Trailing garbage after the padding
+        let mut trailing = serialized.clone();
+        trailing.extend_from_slice(&[0xDE, 0xAD]);
+        assert!(matches!(ChannelData::parse_datagram(&trailing), Err(TurnError::BadRequest)));
+
+        // Partial padding is neither form
+        assert!(ChannelData::parse_datagram(&serialized[..4 + 10]).is_err());
+
+        // The lenient parser still accepts it
+        assert!(ChannelData::parse(&trailing).is_ok());
+    }
+
     #[test]
     fn test_channel_data_invalid_number() {
         let result = ChannelData::new(0x8000, vec![1, 2, 3]); // Too high
@@ -379,4 +348,17 @@ mod tests {
         let result = ChannelData::new(0x3FFF, vec![1, 2, 3]); // Too low
         assert!(result.is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_channel_bind_response_to_message() {
+        let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let response = ChannelBindResponse::success(transaction_id);
+
+        let serialized = response.to_message().serialize();
+        let parsed = Message::parse(&serialized).unwrap();
+
+        assert_eq!(parsed.message_type.method(), MessageMethod::ChannelBind);
+        assert_eq!(parsed.message_type.class(), MessageClass::SuccessResponse);
+        assert_eq!(parsed.transaction_id, transaction_id);
+    }
+}
@@ -2,6 +2,7 @@ use std::net::SocketAddr;
 use crate::stun::{
     message::{Message, MessageType, MessageClass, MessageMethod},
     attributes::{RawAttribute, AttributeType},
+    xor_addr::{decode_xor_address, encode_xor_address, unsupported_family},
 };
 use crate::turn::error::TurnError;
 
@@ -32,14 +33,15 @@ impl SendIndication {
         let mut found_data = false;
 
         // Parse attributes
-        let mut offset = 0;
-        while offset < message.attributes.len() {
-            let (attr, consumed) = RawAttribute::parse(&message.attributes[offset..])?;
-            offset += consumed;
+        for attr in message.attributes_iter() {
+            let attr = attr?;
 
             match AttributeType::from_u16(attr.attribute_type) {
                 Some(AttributeType::XorPeerAddress) => {
-                    if let Some(addr) = parse_xor_peer_address(&attr.value, &message.transaction_id) {
+                    if let Some(family) = unsupported_family(&attr.value) {
+                        return Err(TurnError::UnsupportedAddressFamily(family));
+                    }
+                    if let Some(addr) = decode_xor_address(&attr.value, &message.transaction_id) {
                         indication.peer_address = addr;
                         found_peer = true;
                     }
@@ -48,7 +50,10 @@ impl SendIndication {
                     indication.data = attr.value;
                     found_data = true;
                 }
-                _ => {} // Ignore unknown attributes and DONT-FRAGMENT for now
+                Some(AttributeType::DontFragment) => {
+                    indication.dont_fragment = true;
+                }
+                _ => {} // Unknown comprehension-required types are turned away by the handler
             }
         }
 
@@ -66,18 +71,21 @@ impl SendIndication {
         ));
         message.transaction_id = self.transaction_id;
 
-        let mut attrs = Vec::new();
-
         // Add XOR-PEER-ADDRESS
-        let peer_attr = create_xor_peer_address_attr(self.peer_address, &self.transaction_id);
-        attrs.extend(peer_attr.serialize());
+        let peer_attr = RawAttribute::new(
+            AttributeType::XorPeerAddress as u16,
+            encode_xor_address(self.peer_address, &self.transaction_id),
+        );
+        message.push_attribute(peer_attr);
 
         // Add DATA
         let data_attr = RawAttribute::new(AttributeType::Data as u16, self.data.clone());
-        attrs.extend(data_attr.serialize());
+        message.push_attribute(data_attr);
+
+        if self.dont_fragment {
+            message.push_attribute(RawAttribute::new(AttributeType::DontFragment as u16, Vec::new()));
+        }
 
-        message.attributes = attrs;
-        message.length = message.attributes.len() as u16;
 
         message
     }
@@ -120,14 +128,15 @@ impl DataIndication {
         let mut found_data = false;
 
         // Parse attributes
-        let mut offset = 0;
-        while offset < message.attributes.len() {
-            let (attr, consumed) = RawAttribute::parse(&message.attributes[offset..])?;
-            offset += consumed;
+        for attr in message.attributes_iter() {
+            let attr = attr?;
 
             match AttributeType::from_u16(attr.attribute_type) {
                 Some(AttributeType::XorPeerAddress) => {
-                    if let Some(addr) = parse_xor_peer_address(&attr.value, &message.transaction_id) {
+                    if let Some(family) = unsupported_family(&attr.value) {
+                        return Err(TurnError::UnsupportedAddressFamily(family));
+                    }
+                    if let Some(addr) = decode_xor_address(&attr.value, &message.transaction_id) {
                         indication.peer_address = addr;
                         found_peer = true;
                     }
@@ -154,117 +163,22 @@ impl DataIndication {
         ));
         message.transaction_id = self.transaction_id;
 
-        let mut attrs = Vec::new();
-
         // Add XOR-PEER-ADDRESS
-        let peer_attr = create_xor_peer_address_attr(self.peer_address, &self.transaction_id);
-        attrs.extend(peer_attr.serialize());
+        let peer_attr = RawAttribute::new(
+            AttributeType::XorPeerAddress as u16,
+            encode_xor_address(self.peer_address, &self.transaction_id),
+        );
+        message.push_attribute(peer_attr);
 
         // Add DATA
         let data_attr = RawAttribute::new(AttributeType::Data as u16, self.data.clone());
-        attrs.extend(data_attr.serialize());
+        message.push_attribute(data_attr);
 
-        message.attributes = attrs;
-        message.length = message.attributes.len() as u16;
 
         message
     }
 }
 
-fn parse_xor_peer_address(data: &[u8], transaction_id: &[u8; 12]) -> Option<SocketAddr> {
-    if data.len() < 8 {
-        return None;
-    }
-
-    let family = data[1];
-    let xor_port = u16::from_be_bytes([data[2], data[3]]);
-    
-    // XOR with magic cookie for port
-    let port = xor_port ^ (crate::stun::message::MAGIC_COOKIE >> 16) as u16;
-
-    match family {
-        0x01 => { // IPv4
-            if data.len() < 8 {
-                return None;
-            }
-            
-            let xor_ip = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
-            let ip = xor_ip ^ crate::stun::message::MAGIC_COOKIE;
-            
-            let ip_addr = std::net::Ipv4Addr::from(ip);
-            Some(SocketAddr::from((ip_addr, port)))
-        }
-        0x02 => { // IPv6
-            if data.len() < 20 {
-                return None;
-            }
-            
-            let mut ip_bytes = [0u8; 16];
-            ip_bytes.copy_from_slice(&data[4..20]);
-            
-            // XOR with magic cookie and transaction ID
-            for (i, byte) in ip_bytes.iter_mut().enumerate().take(4) {
-                *byte ^= (crate::stun::message::MAGIC_COOKIE >> (24 - i * 8)) as u8;
-            }
-            for (i, byte) in ip_bytes.iter_mut().enumerate().skip(4).take(12) {
-                *byte ^= transaction_id[i - 4];
-            }
-            
-            let ip_addr = std::net::Ipv6Addr::from(ip_bytes);
-            Some(SocketAddr::from((ip_addr, port)))
-        }
-        _ => None,
-    }
-}
-
-fn create_xor_peer_address_attr(addr: SocketAddr, transaction_id: &[u8; 12]) -> RawAttribute {
-    let mut data = Vec::new();
-    
-    // Padding
-    data.push(0);
-    
-    match addr {
-        SocketAddr::V4(v4) => {
-            // Family
-            data.push(0x01);
-            
-            // XOR Port
-            let xor_port = addr.port() ^ (crate::stun::message::MAGIC_COOKIE >> 16) as u16;
-            data.extend_from_slice(&xor_port.to_be_bytes());
-            
-            // XOR IP
-            let ip_bytes = v4.ip().octets();
-            let ip = u32::from_be_bytes(ip_bytes);
-            let xor_ip = ip ^ crate::stun::message::MAGIC_COOKIE;
-            data.extend_from_slice(&xor_ip.to_be_bytes());
-        }
-        SocketAddr::V6(v6) => {
-            // Family
-            data.push(0x02);
-            
-            // XOR Port
-            let xor_port = addr.port() ^ (crate::stun::message::MAGIC_COOKIE >> 16) as u16;
-            data.extend_from_slice(&xor_port.to_be_bytes());
-            
-            // XOR IPv6
-            let mut ip_bytes = v6.ip().octets();
-            
-            // XOR with magic cookie
-            for (i, byte) in ip_bytes.iter_mut().enumerate().take(4) {
-                *byte ^= (crate::stun::message::MAGIC_COOKIE >> (24 - i * 8)) as u8;
-            }
-            // XOR with transaction ID
-            for (i, byte) in ip_bytes.iter_mut().enumerate().skip(4).take(12) {
-                *byte ^= transaction_id[i - 4];
-            }
-            
-            data.extend_from_slice(&ip_bytes);
-        }
-    }
-    
-    RawAttribute::new(AttributeType::XorPeerAddress as u16, data)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -289,6 +203,20 @@ mod tests {
         assert_eq!(parsed.peer_address, peer_addr);
         assert_eq!(parsed.data, data);
         assert_eq!(parsed.transaction_id, send_ind.transaction_id);
+        assert!(!parsed.dont_fragment);
+    }
+
+    #[test]
+    fn test_send_indication_dont_fragment() {
+        let send_ind = SendIndication {
+            transaction_id: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+            peer_address: "192.0.2.1:80".parse().unwrap(),
+            data: b"Hello, World!".to_vec(),
+            dont_fragment: true,
+        };
+
+        let parsed = SendIndication::from_message(&send_ind.to_message()).unwrap();
+        assert!(parsed.dont_fragment);
     }
 
     #[test]
@@ -315,9 +243,9 @@ mod tests {
         ));
 
         // Add only peer address, no data
-        let peer_attr = create_xor_peer_address_attr(
-            "192.0.2.1:80".parse().unwrap(),
-            &message.transaction_id,
+        let peer_attr = RawAttribute::new(
+            AttributeType::XorPeerAddress as u16,
+            encode_xor_address("192.0.2.1:80".parse().unwrap(), &message.transaction_id),
         );
         message.attributes = peer_attr.serialize();
         message.length = message.attributes.len() as u16;
@@ -338,15 +266,4 @@ mod tests {
         assert!(result.is_err());
         assert!(matches!(result.unwrap_err(), TurnError::BadRequest));
     }
-
-    #[test]
-    fn test_ipv6_xor_address() {
-        let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
-        let peer_addr: SocketAddr = "[2001:db8::1]:8080".parse().unwrap();
-        
-        let attr = create_xor_peer_address_attr(peer_addr, &transaction_id);
-        let parsed = parse_xor_peer_address(&attr.value, &transaction_id).unwrap();
-        
-        assert_eq!(parsed, peer_addr);
-    }
 }
\ No newline at end of file
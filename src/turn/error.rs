@@ -2,12 +2,22 @@ use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum TurnError {
+    #[error("Try Alternate")]
+    TryAlternate,
+    
     #[error("Bad Request")]
     BadRequest,
     
+    // An address attribute with a family byte other than IPv4 or IPv6
+    #[error("Unsupported address family {0:#04x}")]
+    UnsupportedAddressFamily(u8),
+    
     #[error("Unauthorized")]
     Unauthorized,
     
+    #[error("Forbidden")]
+    Forbidden,
+    
     #[error("Unknown Attribute")]
     UnknownAttribute,
     
@@ -20,6 +30,9 @@ pub enum TurnError {
     #[error("Wrong Credentials")]
     WrongCredentials,
     
+    #[error("Address Family not Supported")]
+    AddressFamilyNotSupported,
+    
     #[error("Unsupported Transport Protocol")]
     UnsupportedTransportProtocol,
     
@@ -36,12 +49,16 @@ pub enum TurnError {
 impl TurnError {
     pub fn error_code(&self) -> u16 {
         match self {
+            TurnError::TryAlternate => 300,
             TurnError::BadRequest => 400,
+            TurnError::UnsupportedAddressFamily(_) => 400,
             TurnError::Unauthorized => 401,
+            TurnError::Forbidden => 403,
             TurnError::UnknownAttribute => 420,
             TurnError::AllocationMismatch => 437,
             TurnError::StaleNonce => 438,
             TurnError::WrongCredentials => 441,
+            TurnError::AddressFamilyNotSupported => 440,
             TurnError::UnsupportedTransportProtocol => 442,
             TurnError::AllocationQuotaReached => 486,
             TurnError::InsufficientCapacity => 508,
@@ -5,4 +5,11 @@ pub mod allocation;
 pub mod refresh;
 pub mod permission;
 pub mod data;
-pub mod channel;
\ No newline at end of file
+pub mod channel;
+pub mod relay_address;
+pub mod admission;
+pub mod reservation;
+pub mod sharded_map;
+
+pub use auth::compute_long_term_key;
+
@@ -1,8 +1,10 @@
 use std::net::SocketAddr;
 use crate::stun::{
-    message::{Message, MessageClass, MessageMethod},
-    attributes::{RawAttribute, AttributeType},
+    message::{IntoStunMessage, Message, MessageClass, MessageMethod, MessageType},
+    attributes::{encode_error_code, RawAttribute, AttributeType},
+    xor_addr::{decode_xor_address, unsupported_family},
 };
+use crate::turn::auth::decode_username;
 use crate::turn::error::TurnError;
 
 #[derive(Debug, Clone)]
@@ -31,19 +33,20 @@ impl CreatePermissionRequest {
         };
 
         // Parse attributes
-        let mut offset = 0;
-        while offset < message.attributes.len() {
-            let (attr, consumed) = RawAttribute::parse(&message.attributes[offset..])?;
-            offset += consumed;
+        for attr in message.attributes_iter() {
+            let attr = attr?;
 
             match AttributeType::from_u16(attr.attribute_type) {
                 Some(AttributeType::XorPeerAddress) => {
-                    if let Some(addr) = parse_xor_peer_address(&attr.value, &message.transaction_id) {
+                    if let Some(family) = unsupported_family(&attr.value) {
+                        return Err(TurnError::UnsupportedAddressFamily(family));
+                    }
+                    if let Some(addr) = decode_xor_address(&attr.value, &message.transaction_id) {
                         request.peer_addresses.push(addr);
                     }
                 }
                 Some(AttributeType::Username) => {
-                    request.username = String::from_utf8(attr.value).ok();
+                    request.username = decode_username(attr.value)?;
                 }
                 Some(AttributeType::Realm) => {
                     request.realm = String::from_utf8(attr.value).ok();
@@ -51,7 +54,7 @@ impl CreatePermissionRequest {
                 Some(AttributeType::Nonce) => {
                     request.nonce = Some(attr.value);
                 }
-                _ => {} // Ignore unknown attributes for now
+                _ => {} // Unknown comprehension-required types are turned away by the handler
             }
         }
 
@@ -97,56 +100,38 @@ impl CreatePermissionResponse {
     }
 }
 
-fn parse_xor_peer_address(data: &[u8], transaction_id: &[u8; 12]) -> Option<SocketAddr> {
-    if data.len() < 8 {
-        return None;
-    }
+impl IntoStunMessage for CreatePermissionResponse {
+    fn to_message(&self) -> Message {
+        let class = if self.error_code.is_some() {
+            MessageClass::ErrorResponse
+        } else {
+            MessageClass::SuccessResponse
+        };
 
-    let family = data[1];
-    let xor_port = u16::from_be_bytes([data[2], data[3]]);
-    
-    // XOR with magic cookie for port
-    let port = xor_port ^ (crate::stun::message::MAGIC_COOKIE >> 16) as u16;
+        let mut message = Message::new(MessageType::new(MessageMethod::CreatePermission, class));
+        message.transaction_id = self.transaction_id;
 
-    match family {
-        0x01 => { // IPv4
-            if data.len() < 8 {
-                return None;
-            }
-            
-            let xor_ip = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
-            let ip = xor_ip ^ crate::stun::message::MAGIC_COOKIE;
-            
-            let ip_addr = std::net::Ipv4Addr::from(ip);
-            Some(SocketAddr::from((ip_addr, port)))
+        if let Some((code, reason)) = &self.error_code {
+            message.push_attribute(encode_error_code(*code, reason));
         }
-        0x02 => { // IPv6
-            if data.len() < 20 {
-                return None;
-            }
-            
-            let mut ip_bytes = [0u8; 16];
-            ip_bytes.copy_from_slice(&data[4..20]);
-            
-            // XOR with magic cookie and transaction ID
-            for (i, byte) in ip_bytes.iter_mut().enumerate().take(4) {
-                *byte ^= (crate::stun::message::MAGIC_COOKIE >> (24 - i * 8)) as u8;
-            }
-            for (i, byte) in ip_bytes.iter_mut().enumerate().skip(4).take(12) {
-                *byte ^= transaction_id[i - 4];
-            }
-            
-            let ip_addr = std::net::Ipv6Addr::from(ip_bytes);
-            Some(SocketAddr::from((ip_addr, port)))
+
+        if let Some(realm) = &self.realm {
+            message.push_attribute(RawAttribute::new(AttributeType::Realm as u16, realm.as_bytes().to_vec()));
         }
-        _ => None,
+
+        if let Some(nonce) = &self.nonce {
+            message.push_attribute(RawAttribute::new(AttributeType::Nonce as u16, nonce.clone()));
+        }
+
+
+        message
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::stun::message::MessageType;
+    use crate::stun::xor_addr::encode_xor_address;
 
     fn create_permission_request_message(attributes: Vec<RawAttribute>) -> Message {
         let mut message = Message::new(MessageType::new(
@@ -165,42 +150,12 @@ mod tests {
         message
     }
 
-    fn create_xor_peer_address_attr(addr: SocketAddr, _transaction_id: &[u8; 12]) -> RawAttribute {
-        let mut data = Vec::new();
-        
-        // Padding
-        data.push(0);
-        
-        match addr {
-            SocketAddr::V4(v4) => {
-                // Family
-                data.push(0x01);
-                
-                // XOR Port
-                let xor_port = addr.port() ^ (crate::stun::message::MAGIC_COOKIE >> 16) as u16;
-                data.extend_from_slice(&xor_port.to_be_bytes());
-                
-                // XOR IP
-                let ip_bytes = v4.ip().octets();
-                let ip = u32::from_be_bytes(ip_bytes);
-                let xor_ip = ip ^ crate::stun::message::MAGIC_COOKIE;
-                data.extend_from_slice(&xor_ip.to_be_bytes());
-            }
-            SocketAddr::V6(_) => {
-                // Not implemented for tests
-                unimplemented!("IPv6 test not implemented");
-            }
-        }
-        
-        RawAttribute::new(AttributeType::XorPeerAddress as u16, data)
-    }
-
     #[test]
     fn test_parse_create_permission_request() {
         let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
         let peer_addr: SocketAddr = "192.0.2.1:80".parse().unwrap();
         
-        let peer_attr = create_xor_peer_address_attr(peer_addr, &transaction_id);
+        let peer_attr = RawAttribute::new(AttributeType::XorPeerAddress as u16, encode_xor_address(peer_addr, &transaction_id));
         let username_attr = RawAttribute::new(
             AttributeType::Username as u16,
             b"testuser".to_vec(),
@@ -253,4 +208,17 @@ mod tests {
         assert_eq!(response.transaction_id, transaction_id);
         assert_eq!(response.error_code, Some((403, "Forbidden".to_string())));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_create_permission_response_to_message() {
+        let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let response = CreatePermissionResponse::success(transaction_id);
+
+        let serialized = response.to_message().serialize();
+        let parsed = Message::parse(&serialized).unwrap();
+
+        assert_eq!(parsed.message_type.method(), MessageMethod::CreatePermission);
+        assert_eq!(parsed.message_type.class(), MessageClass::SuccessResponse);
+        assert_eq!(parsed.transaction_id, transaction_id);
+    }
+}
@@ -1,7 +1,8 @@
 use crate::stun::{
-    message::{Message, MessageClass, MessageMethod},
-    attributes::{RawAttribute, AttributeType},
+    message::{IntoStunMessage, Message, MessageClass, MessageMethod, MessageType},
+    attributes::{encode_error_code, RawAttribute, AttributeType},
 };
+use crate::turn::auth::decode_username;
 use crate::turn::error::TurnError;
 
 #[derive(Debug, Clone)]
@@ -30,25 +31,21 @@ impl RefreshRequest {
         };
 
         // Parse attributes
-        let mut offset = 0;
-        while offset < message.attributes.len() {
-            let (attr, consumed) = RawAttribute::parse(&message.attributes[offset..])?;
-            offset += consumed;
+        for attr in message.attributes_iter() {
+            let attr = attr?;
 
             match AttributeType::from_u16(attr.attribute_type) {
-                Some(AttributeType::Lifetime) => {
-                    if attr.value.len() >= 4 {
-                        let lifetime = u32::from_be_bytes([
-                            attr.value[0],
-                            attr.value[1],
-                            attr.value[2],
-                            attr.value[3],
-                        ]);
-                        request.lifetime = Some(lifetime);
-                    }
+                Some(AttributeType::Lifetime) if attr.value.len() >= 4 => {
+                    let lifetime = u32::from_be_bytes([
+                        attr.value[0],
+                        attr.value[1],
+                        attr.value[2],
+                        attr.value[3],
+                    ]);
+                    request.lifetime = Some(lifetime);
                 }
                 Some(AttributeType::Username) => {
-                    request.username = String::from_utf8(attr.value).ok();
+                    request.username = decode_username(attr.value)?;
                 }
                 Some(AttributeType::Realm) => {
                     request.realm = String::from_utf8(attr.value).ok();
@@ -56,7 +53,7 @@ impl RefreshRequest {
                 Some(AttributeType::Nonce) => {
                     request.nonce = Some(attr.value);
                 }
-                _ => {} // Ignore unknown attributes for now
+                _ => {} // Unknown comprehension-required types are turned away by the handler
             }
         }
 
@@ -105,10 +102,42 @@ impl RefreshResponse {
     }
 }
 
+impl IntoStunMessage for RefreshResponse {
+    fn to_message(&self) -> Message {
+        let class = if self.error_code.is_some() {
+            MessageClass::ErrorResponse
+        } else {
+            MessageClass::SuccessResponse
+        };
+
+        let mut message = Message::new(MessageType::new(MessageMethod::Refresh, class));
+        message.transaction_id = self.transaction_id;
+
+        if let Some((code, reason)) = &self.error_code {
+            message.push_attribute(encode_error_code(*code, reason));
+        }
+
+        if let Some(realm) = &self.realm {
+            message.push_attribute(RawAttribute::new(AttributeType::Realm as u16, realm.as_bytes().to_vec()));
+        }
+
+        if let Some(nonce) = &self.nonce {
+            message.push_attribute(RawAttribute::new(AttributeType::Nonce as u16, nonce.clone()));
+        }
+
+        if let Some(lifetime) = self.lifetime {
+            message.push_attribute(RawAttribute::new(AttributeType::Lifetime as u16, lifetime.to_be_bytes().to_vec()));
+        }
+
+
+        message
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::stun::message::MessageType;
+    use crate::stun::attributes::decode_error_code;
 
     fn create_refresh_request_message(attributes: Vec<RawAttribute>) -> Message {
         let mut message = Message::new(MessageType::new(
@@ -198,4 +227,48 @@ mod tests {
         assert!(response.lifetime.is_none());
         assert_eq!(response.error_code, Some((437, "Allocation Mismatch".to_string())));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_refresh_response_to_message() {
+        let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let response = RefreshResponse::success(transaction_id, 600);
+
+        let serialized = response.to_message().serialize();
+        let parsed = Message::parse(&serialized).unwrap();
+
+        assert_eq!(parsed.message_type.method(), MessageMethod::Refresh);
+        assert_eq!(parsed.message_type.class(), MessageClass::SuccessResponse);
+        assert_eq!(parsed.transaction_id, transaction_id);
+    }
+
+    #[test]
+    fn test_refresh_response_lifetime_attribute() {
+        let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let response = RefreshResponse::success(transaction_id, 300);
+
+        let parsed = Message::parse(&response.to_message().serialize()).unwrap();
+        let (attr, _) = RawAttribute::parse(&parsed.attributes).unwrap();
+
+        assert_eq!(attr.attribute_type, AttributeType::Lifetime as u16);
+        assert_eq!(attr.value, 300u32.to_be_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_refresh_response_error_to_message() {
+        let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let response = RefreshResponse::error(
+            transaction_id,
+            437,
+            "Allocation Mismatch".to_string(),
+            None,
+            None,
+        );
+
+        let parsed = Message::parse(&response.to_message().serialize()).unwrap();
+        assert_eq!(parsed.message_type.class(), MessageClass::ErrorResponse);
+
+        let (attr, _) = RawAttribute::parse(&parsed.attributes).unwrap();
+        assert_eq!(attr.attribute_type, AttributeType::ErrorCode as u16);
+        assert_eq!(decode_error_code(&attr.value).unwrap(), (437, "Allocation Mismatch".to_string()));
+    }
+}
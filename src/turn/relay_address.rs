@@ -0,0 +1,209 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Mutex;
+
+// Relayed addresses are IPv4 unless the client asks otherwise (RFC 6156)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum AddressFamily {
+    #[default]
+    IPv4,
+    IPv6,
+}
+
+impl AddressFamily {
+    pub fn of(address: &SocketAddr) -> Self {
+        match address {
+            SocketAddr::V4(_) => AddressFamily::IPv4,
+            SocketAddr::V6(_) => AddressFamily::IPv6,
+        }
+    }
+}
+
+pub type AcquireFuture<'a> = Pin<Box<dyn Future<Output = Option<SocketAddr>> + Send + 'a>>;
+
+// An even-port address, plus the next port up when that was asked for
+pub type AcquireEvenFuture<'a> =
+    Pin<Box<dyn Future<Output = Option<(SocketAddr, Option<SocketAddr>)>> + Send + 'a>>;
+
+// Source of relay transport addresses. Deployments backed by an external
+// IPAM service can implement this instead of using the fixed pool.
+pub trait RelayAddressProvider: Debug + Send + Sync {
+    fn acquire(&self, family: AddressFamily) -> AcquireFuture<'_>;
+
+    fn release(&self, address: SocketAddr);
+
+    // Whether the provider serves this family at all, as opposed to having
+    // run out of addresses in it
+    fn supports(&self, _family: AddressFamily) -> bool {
+        true
+    }
+
+    // EVEN-PORT support (RFC 5766 section 14.6). With reserve_next the
+    // address one port up is taken as well, for the caller to hold for a
+    // later Allocate. Providers that can't do this return None, which the
+    // client sees as 508.
+    fn acquire_even(&self, _family: AddressFamily, _reserve_next: bool) -> AcquireEvenFuture<'_> {
+        Box::pin(async { None })
+    }
+
+    // Takes a free address out of circulation for maintenance. Providers
+    // that cannot do this ignore it; drained addresses are still never
+    // released back to them.
+    fn withdraw(&self, _address: SocketAddr) {}
+}
+
+// Free addresses of both families; each acquire only looks at the family
+// asked for, so this acts as separate IPv4 and IPv6 pools
+#[derive(Debug)]
+pub struct RelayAddressPool {
+    addresses: Mutex<Vec<SocketAddr>>,
+    families: Vec<AddressFamily>,
+}
+
+impl RelayAddressPool {
+    pub fn new(addresses: Vec<SocketAddr>) -> Self {
+        let mut families = Vec::new();
+        for family in addresses.iter().map(AddressFamily::of) {
+            if !families.contains(&family) {
+                families.push(family);
+            }
+        }
+
+        RelayAddressPool {
+            addresses: Mutex::new(addresses),
+            families,
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        self.addresses.lock().unwrap().len()
+    }
+}
+
+impl RelayAddressProvider for RelayAddressPool {
+    fn acquire(&self, family: AddressFamily) -> AcquireFuture<'_> {
+        Box::pin(async move {
+            let mut addresses = self.addresses.lock().unwrap();
+
+            let index = addresses
+                .iter()
+                .rposition(|addr| AddressFamily::of(addr) == family)?;
+
+            Some(addresses.remove(index))
+        })
+    }
+
+    fn release(&self, address: SocketAddr) {
+        self.addresses.lock().unwrap().push(address);
+    }
+
+    fn supports(&self, family: AddressFamily) -> bool {
+        self.families.contains(&family)
+    }
+
+    fn acquire_even(&self, family: AddressFamily, reserve_next: bool) -> AcquireEvenFuture<'_> {
+        Box::pin(async move {
+            let mut addresses = self.addresses.lock().unwrap();
+
+            let next_of = |addr: &SocketAddr| {
+                let mut next = *addr;
+                next.set_port(addr.port().checked_add(1)?);
+                Some(next)
+            };
+
+            let index = addresses.iter().rposition(|addr| {
+                AddressFamily::of(addr) == family
+                    && addr.port().is_multiple_of(2)
+                    && (!reserve_next || next_of(addr).is_some_and(|next| addresses.contains(&next)))
+            })?;
+            let address = addresses.remove(index);
+
+            let next = if reserve_next {
+                let next = next_of(&address)?;
+                addresses.retain(|addr| *addr != next);
+                Some(next)
+            } else {
+                None
+            };
+
+            Some((address, next))
+        })
+    }
+
+    fn withdraw(&self, address: SocketAddr) {
+        self.addresses.lock().unwrap().retain(|addr| *addr != address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pool_acquire_and_release() {
+        let pool = RelayAddressPool::new(vec![
+            "127.0.0.1:49152".parse().unwrap(),
+            "127.0.0.1:49153".parse().unwrap(),
+        ]);
+
+        let first = pool.acquire(AddressFamily::IPv4).await.unwrap();
+        let second = pool.acquire(AddressFamily::IPv4).await.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(pool.available(), 0);
+
+        // Exhausted
+        assert!(pool.acquire(AddressFamily::IPv4).await.is_none());
+
+        pool.release(first);
+        assert_eq!(pool.acquire(AddressFamily::IPv4).await, Some(first));
+    }
+
+    #[tokio::test]
+    async fn test_pool_acquire_by_family() {
+        let v4: SocketAddr = "127.0.0.1:49152".parse().unwrap();
+        let v6: SocketAddr = "[::1]:49152".parse().unwrap();
+        let pool = RelayAddressPool::new(vec![v4, v6]);
+
+        assert_eq!(pool.acquire(AddressFamily::IPv4).await, Some(v4));
+        assert!(pool.acquire(AddressFamily::IPv4).await.is_none());
+        assert_eq!(pool.acquire(AddressFamily::IPv6).await, Some(v6));
+    }
+
+    #[tokio::test]
+    async fn test_pool_acquire_even() {
+        let pool = RelayAddressPool::new(vec![
+            "127.0.0.1:49152".parse().unwrap(),
+            "127.0.0.1:49153".parse().unwrap(),
+            "127.0.0.1:49154".parse().unwrap(),
+            "127.0.0.1:49157".parse().unwrap(),
+        ]);
+
+        // 49154 is even but 49155 isn't free, so reserving picks 49152
+        let (address, next) = pool.acquire_even(AddressFamily::IPv4, true).await.unwrap();
+        assert_eq!(address, "127.0.0.1:49152".parse().unwrap());
+        assert_eq!(next, Some("127.0.0.1:49153".parse().unwrap()));
+        assert_eq!(pool.available(), 2);
+
+        let (address, next) = pool.acquire_even(AddressFamily::IPv4, false).await.unwrap();
+        assert_eq!(address, "127.0.0.1:49154".parse().unwrap());
+        assert_eq!(next, None);
+
+        // Only an odd port left
+        assert!(pool.acquire_even(AddressFamily::IPv4, false).await.is_none());
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pool_withdraw() {
+        let kept: SocketAddr = "127.0.0.1:49152".parse().unwrap();
+        let withdrawn: SocketAddr = "127.0.0.1:49153".parse().unwrap();
+        let pool = RelayAddressPool::new(vec![kept, withdrawn]);
+
+        pool.withdraw(withdrawn);
+        assert_eq!(pool.available(), 1);
+        assert_eq!(pool.acquire(AddressFamily::IPv4).await, Some(kept));
+        assert!(pool.acquire(AddressFamily::IPv4).await.is_none());
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use rand::{thread_rng, Rng};
+
+// How long a port reserved by EVEN-PORT's R bit is held (RFC 5766 section 6.2)
+pub const RESERVATION_LIFETIME: Duration = Duration::from_secs(30);
+
+// Relay addresses held for a later Allocate carrying RESERVATION-TOKEN
+#[derive(Debug)]
+pub struct ReservationStore {
+    reservations: Mutex<HashMap<[u8; 8], (SocketAddr, Instant)>>,
+    lifetime: Duration,
+}
+
+impl ReservationStore {
+    pub fn new(lifetime: Duration) -> Self {
+        ReservationStore {
+            reservations: Mutex::new(HashMap::new()),
+            lifetime,
+        }
+    }
+
+    pub fn reserve(&self, address: SocketAddr) -> [u8; 8] {
+        let mut reservations = self.reservations.lock().unwrap();
+
+        let mut token = [0u8; 8];
+        loop {
+            thread_rng().fill(&mut token);
+            if !reservations.contains_key(&token) {
+                break;
+            }
+        }
+
+        reservations.insert(token, (address, Instant::now()));
+        token
+    }
+
+    // Expired reservations are left for take_expired so their address goes
+    // back to the pool
+    pub fn claim(&self, token: &[u8; 8]) -> Option<SocketAddr> {
+        let mut reservations = self.reservations.lock().unwrap();
+
+        match reservations.get(token) {
+            Some((_, reserved_at)) if reserved_at.elapsed() < self.lifetime => {
+                reservations.remove(token).map(|(address, _)| address)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn take_all(&self) -> Vec<SocketAddr> {
+        let mut reservations = self.reservations.lock().unwrap();
+        reservations.drain().map(|(_, (address, _))| address).collect()
+    }
+
+    pub fn take_expired(&self) -> Vec<SocketAddr> {
+        let mut reservations = self.reservations.lock().unwrap();
+
+        let mut expired = Vec::new();
+        reservations.retain(|_, (address, reserved_at)| {
+            if reserved_at.elapsed() >= self.lifetime {
+                expired.push(*address);
+                false
+            } else {
+                true
+            }
+        });
+        expired
+    }
+}
+
+impl Default for ReservationStore {
+    fn default() -> Self {
+        Self::new(RESERVATION_LIFETIME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve_and_claim() {
+        let store = ReservationStore::default();
+        let address: SocketAddr = "127.0.0.1:49153".parse().unwrap();
+
+        let token = store.reserve(address);
+        assert_eq!(store.claim(&token), Some(address));
+
+        // Tokens are single use
+        assert_eq!(store.claim(&token), None);
+        assert_eq!(store.claim(&[0; 8]), None);
+    }
+
+    #[test]
+    fn test_expired_reservation() {
+        let store = ReservationStore::new(Duration::ZERO);
+        let address: SocketAddr = "127.0.0.1:49153".parse().unwrap();
+
+        let token = store.reserve(address);
+        assert_eq!(store.claim(&token), None);
+        assert_eq!(store.take_expired(), vec![address]);
+        assert!(store.take_expired().is_empty());
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::RwLock;
+
+// Default shard count for AllocationManager; enough that unrelated
+// allocations rarely share a lock
+pub const DEFAULT_SHARDS: usize = 32;
+
+// HashMap split across independently locked shards by key hash, so lookups
+// for one key don't wait on writes to keys in other shards
+#[derive(Debug)]
+pub struct ShardedMap<K, V> {
+    shards: Vec<RwLock<HashMap<K, V>>>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V> ShardedMap<K, V> {
+    pub fn new(shards: usize) -> Self {
+        ShardedMap {
+            shards: (0..shards.max(1)).map(|_| RwLock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &RwLock<HashMap<K, V>> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).write().unwrap().insert(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).write().unwrap().remove(key)
+    }
+
+    pub fn with<R>(&self, key: &K, f: impl FnOnce(&V) -> R) -> Option<R> {
+        self.shard(key).read().unwrap().get(key).map(f)
+    }
+
+    pub fn with_mut<R>(&self, key: &K, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+        self.shard(key).write().unwrap().get_mut(key).map(f)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Whole-map operations lock one shard at a time, so they see a
+    // consistent view of each shard but not of the map as a whole
+    pub fn count_where(&self, mut predicate: impl FnMut(&V) -> bool) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().values().filter(|value| predicate(value)).count())
+            .sum()
+    }
+
+    pub fn sum_by(&self, mut f: impl FnMut(&V) -> usize) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().values().map(&mut f).sum::<usize>())
+            .sum()
+    }
+
+    pub fn for_each_mut(&self, mut f: impl FnMut(&mut V)) {
+        for shard in &self.shards {
+            shard.write().unwrap().values_mut().for_each(&mut f);
+        }
+    }
+
+    pub fn retain(&self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        for shard in &self.shards {
+            shard.write().unwrap().retain(&mut f);
+        }
+    }
+
+    pub fn drain(&self) -> Vec<V> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.write().unwrap().drain().map(|(_, value)| value).collect::<Vec<_>>())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sharded_map_operations() {
+        let map = ShardedMap::new(4);
+        for key in 0..100 {
+            assert_eq!(map.insert(key, key * 2), None);
+        }
+        assert_eq!(map.len(), 100);
+        assert_eq!(map.with(&21, |value| *value), Some(42));
+        assert_eq!(map.with(&100, |value| *value), None);
+
+        map.with_mut(&21, |value| *value = 0);
+        assert_eq!(map.remove(&21), Some(0));
+        assert_eq!(map.count_where(|value| value % 4 == 0), 50);
+
+        map.retain(|key, _| key % 2 == 0);
+        assert_eq!(map.len(), 50);
+
+        assert_eq!(map.drain().len(), 50);
+        assert!(map.is_empty());
+    }
+}